@@ -2,4 +2,5 @@ extern crate prost_build;
 
 fn main() {
     prost_build::compile_protos(&["src/protos/example.proto"], &["src/"]).unwrap();
+    tonic_build::compile_protos("src/protos/control.proto").unwrap();
 }
@@ -0,0 +1,167 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::{error, info};
+use tmq::{publish, Context};
+
+use message_recorder::replay::{replay, ReplayOptions};
+use message_recorder::shutdown::ShutdownCoordinator;
+
+const USAGE: &str = "Usage: replay [OPTIONS] <file>...
+
+Publishes recorded messages on a ZMQ PUB socket with their original topics.
+Several files are replayed as one recording, in the order given.
+
+Options:
+  --bind <endpoint>     Bind the PUB socket (default tcp://*:5556)
+  --connect <endpoint>  Connect the PUB socket instead of binding it
+  --speed <factor>      Replay faster or slower than recorded (default 1)
+  --max-rate            Publish as fast as possible, ignoring the timing
+  --loop                Start over after the last file
+  --start <duration>    Skip the first part of the recording, e.g. 30s or 1m30s
+  --stop <duration>     Stop this far into the recording
+  --wait <duration>     Time for subscribers to connect before publishing (default 1s)";
+
+#[derive(Debug)]
+struct ReplayArgs {
+    endpoint: String,
+    bind: bool,
+    wait: Duration,
+    files: Vec<PathBuf>,
+    options: ReplayOptions,
+}
+
+fn parse_duration(flag: &str, value: &str) -> Result<Duration, String> {
+    humantime::parse_duration(value).map_err(|e| format!("Bad {} '{}': {}", flag, value, e))
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<ReplayArgs, String> {
+    let mut parsed = ReplayArgs {
+        endpoint: "tcp://*:5556".to_string(),
+        bind: true,
+        wait: Duration::from_secs(1),
+        files: vec![],
+        options: ReplayOptions::default(),
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--bind" | "--connect" => {
+                parsed.bind = arg == "--bind";
+                parsed.endpoint = value()?;
+            }
+            "--speed" => {
+                let speed = value()?;
+                parsed.options.speed = speed
+                    .parse()
+                    .map_err(|_| format!("Bad --speed '{}'", speed))?;
+            }
+            "--max-rate" => parsed.options.max_rate = true,
+            "--loop" => parsed.options.looping = true,
+            "--start" => parsed.options.start = Some(parse_duration("--start", &value()?)?),
+            "--stop" => parsed.options.stop = Some(parse_duration("--stop", &value()?)?),
+            "--wait" => parsed.wait = parse_duration("--wait", &value()?)?,
+            flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+            file => parsed.files.push(PathBuf::from(file)),
+        }
+    }
+    if parsed.files.is_empty() {
+        return Err("No recording given".to_string());
+    }
+    Ok(parsed)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let builder = publish(&Context::new());
+    let socket = if args.bind {
+        builder.bind(&args.endpoint)
+    } else {
+        builder.connect(&args.endpoint)
+    };
+    let mut socket = match socket {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Failed to open a PUB socket on {}: {}", args.endpoint, e);
+            std::process::exit(1);
+        }
+    };
+    info!("Publishing on {}", args.endpoint);
+    // PUB drops everything sent before a subscriber has connected
+    tokio::time::sleep(args.wait).await;
+
+    tokio::select! {
+        result = replay(&args.files, &mut socket, &args.options) => match result {
+            Ok(stats) => info!(
+                "Published {} messages, skipped {}, {} damaged records",
+                stats.published, stats.skipped, stats.damaged
+            ),
+            Err(e) => {
+                error!("Replay failed: {}", e);
+                std::process::exit(1);
+            }
+        },
+        signal = ShutdownCoordinator::wait_for_signal() => {
+            if let Err(e) = signal {
+                error!("Failed to listen for shutdown signals: {}", e);
+            }
+            info!("Replay interrupted");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<ReplayArgs, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let parsed = args(&[
+            "--connect",
+            "tcp://localhost:5559",
+            "--speed",
+            "2.5",
+            "--loop",
+            "--start",
+            "1m30s",
+            "a.rec",
+            "b.rec",
+        ])
+        .unwrap();
+        assert!(!parsed.bind);
+        assert_eq!(parsed.endpoint, "tcp://localhost:5559");
+        assert_eq!(parsed.options.speed, 2.5);
+        assert!(parsed.options.looping);
+        assert_eq!(parsed.options.start, Some(Duration::from_secs(90)));
+        assert_eq!(parsed.options.stop, None);
+        assert_eq!(
+            parsed.files,
+            vec![PathBuf::from("a.rec"), PathBuf::from("b.rec")]
+        );
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(args(&[]).is_err());
+        assert!(args(&["--speed"]).is_err());
+        assert!(args(&["--speed", "fast", "a.rec"]).is_err());
+        assert!(args(&["--start", "soon", "a.rec"]).is_err());
+        assert!(args(&["--rewind", "a.rec"]).is_err());
+    }
+}
@@ -0,0 +1,565 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tmq::pull::Pull;
+use tmq::{Context, FromZmqSocket};
+
+use crate::endpoint::Endpoint;
+use crate::security::Security;
+use crate::topic_filter::TopicFilter;
+use crate::zmq_connection::MessageRecorderError;
+
+/// Kind of ZMQ socket a connection receives on.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SocketType {
+    /// Subscribes to PUB or XPUB sockets
+    #[default]
+    Sub,
+    /// Like SUB, subscribing by sending subscription messages
+    XSub,
+    /// Receives from PUSH sockets
+    Pull,
+    /// Receives from DEALER or REQ sockets, the sender's identity is the first frame
+    Router,
+}
+
+impl SocketType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SocketType::Sub => "sub",
+            SocketType::XSub => "xsub",
+            SocketType::Pull => "pull",
+            SocketType::Router => "router",
+        }
+    }
+
+    /// Whether messages start with a topic frame to filter on.
+    pub fn has_topics(&self) -> bool {
+        matches!(self, SocketType::Sub | SocketType::XSub)
+    }
+
+    fn zmq_type(&self) -> zmq::SocketType {
+        match self {
+            SocketType::Sub => zmq::SUB,
+            SocketType::XSub => zmq::XSUB,
+            SocketType::Pull => zmq::PULL,
+            SocketType::Router => zmq::ROUTER,
+        }
+    }
+}
+
+impl TryFrom<&str> for SocketType {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "sub" => Ok(SocketType::Sub),
+            "xsub" => Ok(SocketType::XSub),
+            "pull" => Ok(SocketType::Pull),
+            "router" => Ok(SocketType::Router),
+            _ => Err(format!(
+                "Unknown socket type '{}', expected sub, xsub, pull or router",
+                value
+            )),
+        }
+    }
+}
+
+/// Whether the socket connects to its peers or waits for them to connect.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SocketMode {
+    #[default]
+    Connect,
+    Bind,
+}
+
+impl SocketMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SocketMode::Connect => "connect",
+            SocketMode::Bind => "bind",
+        }
+    }
+}
+
+impl TryFrom<&str> for SocketMode {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "connect" => Ok(SocketMode::Connect),
+            "bind" => Ok(SocketMode::Bind),
+            _ => Err(format!(
+                "Unknown socket mode '{}', expected connect or bind",
+                value
+            )),
+        }
+    }
+}
+
+/// TCP keepalive probes of a socket. Unset fields keep the OS defaults.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TcpKeepalive {
+    pub enabled: bool,
+    /// Idle time before the first probe, in whole seconds
+    pub idle: Option<Duration>,
+    /// Time between probes, in whole seconds
+    pub interval: Option<Duration>,
+    /// Unanswered probes before the connection is dropped
+    pub count: Option<i32>,
+}
+
+/// Tuning of a connection's socket. Unset options keep the ZMQ defaults.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SocketOptions {
+    /// Messages queued before a SUB socket drops them, or before other
+    /// sockets stop reading from their peers. 0 is unlimited, ZMQ's default is 1000
+    pub receive_hwm: Option<i32>,
+    /// Kernel receive buffer in bytes
+    pub receive_buffer: Option<i32>,
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    /// Sets ZMQ_RCVTIMEO. The recorder never blocks on a receive, so it also
+    /// warns when nothing arrived for this long
+    pub receive_timeout: Option<Duration>,
+}
+
+impl SocketOptions {
+    /// Sets the options on the socket. Must be called before connecting or binding.
+    pub fn apply(&self, socket: &zmq::Socket) -> Result<(), zmq::Error> {
+        if let Some(hwm) = self.receive_hwm {
+            socket.set_rcvhwm(hwm)?;
+        }
+        if let Some(size) = self.receive_buffer {
+            socket.set_rcvbuf(size)?;
+        }
+        if let Some(keepalive) = self.tcp_keepalive {
+            socket.set_tcp_keepalive(keepalive.enabled as i32)?;
+            if let Some(idle) = keepalive.idle {
+                socket.set_tcp_keepalive_idle(seconds(idle))?;
+            }
+            if let Some(interval) = keepalive.interval {
+                socket.set_tcp_keepalive_intvl(seconds(interval))?;
+            }
+            if let Some(count) = keepalive.count {
+                socket.set_tcp_keepalive_cnt(count)?;
+            }
+        }
+        if let Some(timeout) = self.receive_timeout {
+            socket.set_rcvtimeo(millis(timeout))?;
+        }
+        Ok(())
+    }
+
+    /// The options in effect on the socket. Options ZMQ leaves to the OS, or
+    /// an infinite receive timeout, read back as None.
+    pub fn read(socket: &zmq::Socket) -> Result<SocketOptions, zmq::Error> {
+        let set = |value: i32| (value >= 0).then_some(value);
+        let duration = |secs: i32| set(secs).map(|secs| Duration::from_secs(secs as u64));
+        let tcp_keepalive = match socket.get_tcp_keepalive()? {
+            -1 => None,
+            enabled => Some(TcpKeepalive {
+                enabled: enabled == 1,
+                idle: duration(socket.get_tcp_keepalive_idle()?),
+                interval: duration(socket.get_tcp_keepalive_intvl()?),
+                count: set(socket.get_tcp_keepalive_cnt()?),
+            }),
+        };
+        Ok(SocketOptions {
+            receive_hwm: Some(socket.get_rcvhwm()?),
+            receive_buffer: set(socket.get_rcvbuf()?),
+            tcp_keepalive,
+            receive_timeout: set(socket.get_rcvtimeo()?)
+                .map(|millis| Duration::from_millis(millis as u64)),
+        })
+    }
+}
+
+/// Logged as e.g. `receive_hwm=1000 receive_buffer=os tcp_keepalive=os receive_timeout=none`.
+impl std::fmt::Display for SocketOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let or_default =
+            |value: Option<String>, default: &str| value.unwrap_or_else(|| default.to_string());
+        let keepalive = self.tcp_keepalive.map(|keepalive| match keepalive {
+            TcpKeepalive { enabled: false, .. } => "off".to_string(),
+            TcpKeepalive {
+                idle,
+                interval,
+                count,
+                ..
+            } => format!(
+                "on(idle={},interval={},count={})",
+                or_default(idle.map(|idle| format!("{}s", idle.as_secs())), "os"),
+                or_default(
+                    interval.map(|interval| format!("{}s", interval.as_secs())),
+                    "os"
+                ),
+                or_default(count.map(|count| count.to_string()), "os")
+            ),
+        });
+        write!(
+            f,
+            "receive_hwm={} receive_buffer={} tcp_keepalive={} receive_timeout={}",
+            or_default(self.receive_hwm.map(|hwm| hwm.to_string()), "zmq"),
+            or_default(self.receive_buffer.map(|size| size.to_string()), "os"),
+            or_default(keepalive, "os"),
+            or_default(
+                self.receive_timeout
+                    .map(|timeout| humantime::format_duration(timeout).to_string()),
+                "none"
+            )
+        )
+    }
+}
+
+/// Creates the context shared by all connections' sockets, with the given
+/// number of I/O threads or ZMQ's default of 1.
+pub fn new_context(io_threads: Option<i32>) -> Result<Context, zmq::Error> {
+    let context = Context::new();
+    if let Some(io_threads) = io_threads {
+        context.set_io_threads(io_threads)?;
+    }
+    Ok(context)
+}
+
+/// How a connection's socket is set up.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SocketConfig {
+    pub socket_type: SocketType,
+    pub mode: SocketMode,
+    pub security: Option<Security>,
+    /// Wait before the first reconnect attempt, ZMQ's default is 100ms
+    pub reconnect_interval: Option<Duration>,
+    /// Doubles the wait after every failed attempt up to this, ZMQ's default
+    /// is to keep the first interval
+    pub reconnect_interval_max: Option<Duration>,
+    pub options: SocketOptions,
+}
+
+/// A connection's socket and the monitor reporting what happens to it. Keep
+/// both for as long as the socket receives, see `open`.
+pub struct CaptureSocket {
+    pub receiver: Pull,
+    /// Receives two frame monitor messages, see `MonitorEvent::parse`
+    pub monitor: Pull,
+    /// Options in effect once the socket was set up
+    pub options: SocketOptions,
+}
+
+/// Tells the monitors of all sockets apart, they may share a context.
+static NEXT_MONITOR: AtomicU64 = AtomicU64::new(0);
+
+fn millis(duration: Duration) -> i32 {
+    duration.as_millis().try_into().unwrap_or(i32::MAX)
+}
+
+fn seconds(duration: Duration) -> i32 {
+    duration.as_secs().try_into().unwrap_or(i32::MAX)
+}
+
+/// Opens the socket with a monitor, sets up its options, security and reconnects,
+/// subscribes it to the topics of the filter and connects or binds it.
+///
+/// tmq has no XSUB socket, so every socket is set up with zmq and then wrapped
+/// in tmq's receive-only stream, which works for any socket type.
+///
+/// The monitor is the peer of an inproc pipe libzmq writes the socket's
+/// events to. Dropping it while keeping `receiver` closes that peer under
+/// a live socket, which makes the socket unreliable, so hold on to the whole
+/// `CaptureSocket` until receiving ends.
+pub fn open(
+    context: &Context,
+    endpoint: &Endpoint,
+    config: &SocketConfig,
+    topic_filter: &TopicFilter,
+) -> Result<CaptureSocket, MessageRecorderError> {
+    let socket = context
+        .socket(config.socket_type.zmq_type())
+        .map_err(tmq::TmqError::from)?;
+    // Attached before connecting so not even the first event is missed
+    let monitor_endpoint = format!(
+        "inproc://recorder-monitor-{}",
+        NEXT_MONITOR.fetch_add(1, Ordering::Relaxed)
+    );
+    socket
+        .monitor(&monitor_endpoint, zmq::SocketEvent::ALL as i32)
+        .map_err(tmq::TmqError::from)?;
+    let monitor = context.socket(zmq::PAIR).map_err(tmq::TmqError::from)?;
+    monitor
+        .connect(&monitor_endpoint)
+        .map_err(tmq::TmqError::from)?;
+    if let Some(interval) = config.reconnect_interval {
+        socket
+            .set_reconnect_ivl(millis(interval))
+            .map_err(tmq::TmqError::from)?;
+    }
+    if let Some(interval) = config.reconnect_interval_max {
+        socket
+            .set_reconnect_ivl_max(millis(interval))
+            .map_err(tmq::TmqError::from)?;
+    }
+    config.options.apply(&socket).map_err(tmq::TmqError::from)?;
+    if let Some(security) = &config.security {
+        security
+            .apply(&socket, config.mode)
+            .map_err(tmq::TmqError::from)?;
+    }
+    // ZMQ only filters on prefixes so the rest is checked on receipt
+    match config.socket_type {
+        SocketType::Sub => {
+            for topic in topic_filter.subscriptions() {
+                socket.set_subscribe(topic).map_err(tmq::TmqError::from)?;
+            }
+        }
+        SocketType::XSub => {
+            // XSUB keeps its subscriptions and sends them to every publisher it meets
+            for topic in topic_filter.subscriptions() {
+                socket
+                    .send([&[1u8], topic].concat(), 0)
+                    .map_err(tmq::TmqError::from)?;
+            }
+        }
+        SocketType::Pull | SocketType::Router => {}
+    }
+    let address = endpoint.to_string();
+    match config.mode {
+        SocketMode::Connect => socket.connect(&address),
+        SocketMode::Bind => socket.bind(&address),
+    }
+    .map_err(tmq::TmqError::from)?;
+    let options = SocketOptions::read(&socket).map_err(tmq::TmqError::from)?;
+    Ok(CaptureSocket {
+        receiver: Pull::from_zmq_socket(socket)?,
+        monitor: Pull::from_zmq_socket(monitor)?,
+        options,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_health::{MonitorEvent, MonitorEventKind};
+    use crate::security::tests::{key, CLIENT_PUBLIC, CLIENT_SECRET, SERVER_PUBLIC, SERVER_SECRET};
+    use crate::topic_filter::TopicMatchMode;
+    use futures::{SinkExt, TryStreamExt};
+    use tmq::Multipart;
+
+    fn ipc(dir: &tempfile::TempDir, name: &str) -> Endpoint {
+        Endpoint::try_from(format!("ipc://{}", dir.path().join(name).display()).as_str()).unwrap()
+    }
+
+    async fn receive(socket: &mut Pull) -> Multipart {
+        tokio::time::timeout(Duration::from_secs(5), socket.try_next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn next_event(monitor: &mut Pull) -> MonitorEventKind {
+        let message = receive(monitor).await;
+        let frames: Vec<&[u8]> = message.iter().map(|frame| &frame[..]).collect();
+        MonitorEvent::parse(&frames, 0).unwrap().kind
+    }
+
+    fn frames(message: &Multipart) -> Vec<Vec<u8>> {
+        message.iter().map(|frame| frame.to_vec()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_bound_pull_receives_from_push() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = ipc(&dir, "pull.sock");
+        let config = SocketConfig {
+            socket_type: SocketType::Pull,
+            mode: SocketMode::Bind,
+            ..Default::default()
+        };
+        let mut capture = open(&Context::new(), &endpoint, &config, &TopicFilter::all()).unwrap();
+        let socket = &mut capture.receiver;
+
+        let mut push = tmq::push(&Context::new())
+            .connect(&endpoint.to_string())
+            .unwrap();
+        push.send(vec![b"a".to_vec(), b"b".to_vec()]).await.unwrap();
+
+        let message = receive(socket).await;
+        assert_eq!(frames(&message), vec![b"a".to_vec(), b"b".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_router_records_the_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = ipc(&dir, "router.sock");
+        let config = SocketConfig {
+            socket_type: SocketType::Router,
+            mode: SocketMode::Bind,
+            ..Default::default()
+        };
+        let mut capture = open(&Context::new(), &endpoint, &config, &TopicFilter::all()).unwrap();
+        let socket = &mut capture.receiver;
+
+        let mut dealer = tmq::dealer(&Context::new())
+            .set_identity(b"worker-1")
+            .connect(&endpoint.to_string())
+            .unwrap();
+        dealer.send(vec![b"job".to_vec()]).await.unwrap();
+
+        let message = receive(socket).await;
+        assert_eq!(
+            frames(&message),
+            vec![b"worker-1".to_vec(), b"job".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_xsub_subscribes_to_topics() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = ipc(&dir, "xsub.sock");
+        let mut publisher = tmq::publish(&Context::new())
+            .bind(&endpoint.to_string())
+            .unwrap();
+        let config = SocketConfig {
+            socket_type: SocketType::XSub,
+            mode: SocketMode::Connect,
+            ..Default::default()
+        };
+        let filter = TopicFilter::new(vec!["a".to_string()], TopicMatchMode::Prefix).unwrap();
+        let mut capture = open(&Context::new(), &endpoint, &config, &filter).unwrap();
+        let socket = &mut capture.receiver;
+
+        // Publish until the subscription has reached the publisher
+        let message = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                publisher
+                    .send(vec![b"b".to_vec(), b"skipped".to_vec()])
+                    .await
+                    .unwrap();
+                publisher
+                    .send(vec![b"a".to_vec(), b"kept".to_vec()])
+                    .await
+                    .unwrap();
+                let received =
+                    tokio::time::timeout(Duration::from_millis(50), socket.try_next()).await;
+                if let Ok(received) = received {
+                    break received.unwrap().unwrap();
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(frames(&message), vec![b"a".to_vec(), b"kept".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_curve_client_receives_from_curve_server() {
+        if crate::security::check_curve_support().is_err() {
+            // Nothing to test against a libzmq without CURVE
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = ipc(&dir, "curve.sock");
+        let mut publisher = tmq::publish(&Context::new())
+            .set_curve_server(true)
+            .set_curve_secretkey(&key(SERVER_SECRET))
+            .bind(&endpoint.to_string())
+            .unwrap();
+        let config = SocketConfig {
+            security: Some(Security::Curve {
+                server_key: Some(key(SERVER_PUBLIC)),
+                public_key: key(CLIENT_PUBLIC),
+                secret_key: key(CLIENT_SECRET),
+            }),
+            ..Default::default()
+        };
+        let mut capture = open(&Context::new(), &endpoint, &config, &TopicFilter::all()).unwrap();
+        let socket = &mut capture.receiver;
+
+        let message = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                publisher.send(vec![b"secret".to_vec()]).await.unwrap();
+                let received =
+                    tokio::time::timeout(Duration::from_millis(50), socket.try_next()).await;
+                if let Ok(received) = received {
+                    break received.unwrap().unwrap();
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(frames(&message), vec![b"secret".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_monitor_reports_connect_and_disconnect() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = ipc(&dir, "monitor.sock");
+        let config = SocketConfig {
+            socket_type: SocketType::Pull,
+            reconnect_interval: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let mut socket = open(&Context::new(), &endpoint, &config, &TopicFilter::all()).unwrap();
+        let push = tmq::push(&Context::new())
+            .bind(&endpoint.to_string())
+            .unwrap();
+
+        while next_event(&mut socket.monitor).await != MonitorEventKind::Connected {}
+        drop(push);
+        while next_event(&mut socket.monitor).await != MonitorEventKind::Disconnected {}
+    }
+
+    #[tokio::test]
+    async fn test_options_are_applied_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = SocketOptions {
+            receive_hwm: Some(50_000),
+            receive_buffer: Some(1 << 20),
+            tcp_keepalive: Some(TcpKeepalive {
+                enabled: true,
+                idle: Some(Duration::from_secs(60)),
+                interval: Some(Duration::from_secs(10)),
+                count: Some(5),
+            }),
+            receive_timeout: Some(Duration::from_millis(1500)),
+        };
+        let config = SocketConfig {
+            options: options.clone(),
+            ..Default::default()
+        };
+        let context = new_context(Some(2)).unwrap();
+        assert_eq!(context.get_io_threads().unwrap(), 2);
+        let socket = open(
+            &context,
+            &ipc(&dir, "options.sock"),
+            &config,
+            &TopicFilter::all(),
+        )
+        .unwrap();
+        assert_eq!(socket.options, options);
+        assert_eq!(
+            socket.options.to_string(),
+            "receive_hwm=50000 receive_buffer=1048576 \
+             tcp_keepalive=on(idle=60s,interval=10s,count=5) receive_timeout=1s 500ms"
+        );
+
+        let defaults = open(
+            &context,
+            &ipc(&dir, "defaults.sock"),
+            &SocketConfig::default(),
+            &TopicFilter::all(),
+        )
+        .unwrap();
+        assert_eq!(
+            defaults.options.to_string(),
+            "receive_hwm=1000 receive_buffer=os tcp_keepalive=os receive_timeout=none"
+        );
+    }
+
+    #[test]
+    fn test_parse_socket_type_and_mode() {
+        assert_eq!(SocketType::try_from("xsub"), Ok(SocketType::XSub));
+        assert_eq!(SocketMode::try_from("bind"), Ok(SocketMode::Bind));
+        assert!(SocketType::try_from("pub").is_err());
+        assert!(SocketMode::try_from("listen").is_err());
+    }
+}
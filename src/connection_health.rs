@@ -0,0 +1,409 @@
+use std::collections::VecDeque;
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use zmq::SocketEvent;
+
+use crate::capture_socket::SocketMode;
+
+/// Events kept for `HealthSnapshot::recent`.
+pub const RECENT_EVENTS: usize = 32;
+
+/// Topic of the monitor events handed to sinks that record them.
+pub const MONITOR_TOPIC: &str = "$zmq-monitor";
+
+pub(crate) fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// What a ZMQ socket monitor reported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MonitorEventKind {
+    Connected,
+    ConnectDelayed,
+    /// The value is the reconnect interval in milliseconds
+    ConnectRetried,
+    Listening,
+    BindFailed,
+    Accepted,
+    AcceptFailed,
+    Closed,
+    CloseFailed,
+    Disconnected,
+    MonitorStopped,
+    HandshakeSucceeded,
+    HandshakeFailedNoDetail,
+    HandshakeFailedProtocol,
+    HandshakeFailedAuth,
+    /// An event this libzmq knows and the recorder does not
+    Other(u16),
+}
+
+impl MonitorEventKind {
+    pub fn from_raw(raw: u16) -> Self {
+        // SocketEvent::from_raw panics on unknown events, so compare instead
+        let known = [
+            (SocketEvent::CONNECTED, MonitorEventKind::Connected),
+            (
+                SocketEvent::CONNECT_DELAYED,
+                MonitorEventKind::ConnectDelayed,
+            ),
+            (
+                SocketEvent::CONNECT_RETRIED,
+                MonitorEventKind::ConnectRetried,
+            ),
+            (SocketEvent::LISTENING, MonitorEventKind::Listening),
+            (SocketEvent::BIND_FAILED, MonitorEventKind::BindFailed),
+            (SocketEvent::ACCEPTED, MonitorEventKind::Accepted),
+            (SocketEvent::ACCEPT_FAILED, MonitorEventKind::AcceptFailed),
+            (SocketEvent::CLOSED, MonitorEventKind::Closed),
+            (SocketEvent::CLOSE_FAILED, MonitorEventKind::CloseFailed),
+            (SocketEvent::DISCONNECTED, MonitorEventKind::Disconnected),
+            (
+                SocketEvent::MONITOR_STOPPED,
+                MonitorEventKind::MonitorStopped,
+            ),
+            (
+                SocketEvent::HANDSHAKE_SUCCEEDED,
+                MonitorEventKind::HandshakeSucceeded,
+            ),
+            (
+                SocketEvent::HANDSHAKE_FAILED_NO_DETAIL,
+                MonitorEventKind::HandshakeFailedNoDetail,
+            ),
+            (
+                SocketEvent::HANDSHAKE_FAILED_PROTOCOL,
+                MonitorEventKind::HandshakeFailedProtocol,
+            ),
+            (
+                SocketEvent::HANDSHAKE_FAILED_AUTH,
+                MonitorEventKind::HandshakeFailedAuth,
+            ),
+        ];
+        known
+            .iter()
+            .find(|(event, _)| event.to_raw() == raw)
+            .map(|(_, kind)| *kind)
+            .unwrap_or(MonitorEventKind::Other(raw))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MonitorEventKind::Connected => "connected",
+            MonitorEventKind::ConnectDelayed => "connect_delayed",
+            MonitorEventKind::ConnectRetried => "connect_retried",
+            MonitorEventKind::Listening => "listening",
+            MonitorEventKind::BindFailed => "bind_failed",
+            MonitorEventKind::Accepted => "accepted",
+            MonitorEventKind::AcceptFailed => "accept_failed",
+            MonitorEventKind::Closed => "closed",
+            MonitorEventKind::CloseFailed => "close_failed",
+            MonitorEventKind::Disconnected => "disconnected",
+            MonitorEventKind::MonitorStopped => "monitor_stopped",
+            MonitorEventKind::HandshakeSucceeded => "handshake_succeeded",
+            MonitorEventKind::HandshakeFailedNoDetail => "handshake_failed",
+            MonitorEventKind::HandshakeFailedProtocol => "handshake_failed_protocol",
+            MonitorEventKind::HandshakeFailedAuth => "handshake_failed_auth",
+            MonitorEventKind::Other(_) => "other",
+        }
+    }
+
+    /// Whether the event means something is wrong, rather than routine.
+    pub fn is_failure(&self) -> bool {
+        matches!(
+            self,
+            MonitorEventKind::BindFailed
+                | MonitorEventKind::AcceptFailed
+                | MonitorEventKind::CloseFailed
+                | MonitorEventKind::Disconnected
+                | MonitorEventKind::HandshakeFailedNoDetail
+                | MonitorEventKind::HandshakeFailedProtocol
+                | MonitorEventKind::HandshakeFailedAuth
+        )
+    }
+}
+
+/// One event of a socket monitor with the time it was received.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonitorEvent {
+    pub wall_time_ns: u64,
+    pub kind: MonitorEventKind,
+    /// File descriptor, error number or retry interval, depending on the kind
+    pub value: u32,
+    /// Endpoint of the peer or of the bound socket
+    pub address: String,
+}
+
+impl MonitorEvent {
+    /// Parses the two frames of a monitor message: event number and value,
+    /// then the address.
+    pub fn parse(frames: &[&[u8]], wall_time_ns: u64) -> Option<MonitorEvent> {
+        let (header, address) = match frames {
+            [header, address] if header.len() == 6 => (header, address),
+            _ => return None,
+        };
+        Some(MonitorEvent {
+            wall_time_ns,
+            kind: MonitorEventKind::from_raw(u16::from_le_bytes([header[0], header[1]])),
+            value: u32::from_le_bytes([header[2], header[3], header[4], header[5]]),
+            address: String::from_utf8_lossy(address).into_owned(),
+        })
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "event": self.kind.name(),
+            "value": self.value,
+            "address": self.address,
+            "wall_time_ns": self.wall_time_ns,
+        })
+    }
+}
+
+impl std::fmt::Display for MonitorEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.kind {
+            MonitorEventKind::ConnectRetried => {
+                write!(f, "connect_retried {} in {}ms", self.address, self.value)
+            }
+            MonitorEventKind::Other(raw) => write!(f, "event {} {}", raw, self.address),
+            kind => write!(f, "{} {}", kind.name(), self.address),
+        }
+    }
+}
+
+/// Whether a connection currently has a peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    /// No monitor event yet
+    Starting,
+    /// Waiting for a peer to accept the first connection
+    Connecting,
+    /// Bound and waiting for peers
+    Listening,
+    /// At least one peer is connected
+    Connected,
+    /// Lost every peer, ZMQ retries by itself
+    Disconnected,
+    /// Binding failed, nothing will connect
+    Failed,
+    Closed,
+}
+
+impl ConnectionState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConnectionState::Starting => "starting",
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Listening => "listening",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Disconnected => "disconnected",
+            ConnectionState::Failed => "failed",
+            ConnectionState::Closed => "closed",
+        }
+    }
+}
+
+/// State of a connection and the events that led there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthSnapshot {
+    pub state: ConnectionState,
+    /// When the state last changed
+    pub since_ns: u64,
+    pub peers: u32,
+    pub connects: u64,
+    pub disconnects: u64,
+    pub retries: u64,
+    pub handshake_failures: u64,
+    /// The last `RECENT_EVENTS` events, oldest first
+    pub recent: VecDeque<MonitorEvent>,
+}
+
+/// Tracks the state of a connection from its socket monitor events.
+#[derive(Debug)]
+pub struct ConnectionHealth {
+    snapshot: Mutex<HealthSnapshot>,
+}
+
+impl Default for ConnectionHealth {
+    fn default() -> Self {
+        ConnectionHealth::new()
+    }
+}
+
+impl ConnectionHealth {
+    pub fn new() -> Self {
+        ConnectionHealth {
+            snapshot: Mutex::new(HealthSnapshot {
+                state: ConnectionState::Starting,
+                since_ns: now_ns(),
+                peers: 0,
+                connects: 0,
+                disconnects: 0,
+                retries: 0,
+                handshake_failures: 0,
+                recent: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn snapshot(&self) -> HealthSnapshot {
+        self.snapshot
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.snapshot
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .state
+    }
+
+    /// Applies an event, returns the new state if it changed.
+    pub fn record(&self, event: &MonitorEvent, mode: SocketMode) -> Option<ConnectionState> {
+        let mut snapshot = self.snapshot.lock().unwrap_or_else(PoisonError::into_inner);
+        let idle = match mode {
+            SocketMode::Connect => ConnectionState::Connecting,
+            SocketMode::Bind => ConnectionState::Listening,
+        };
+        let state = match event.kind {
+            MonitorEventKind::Connected | MonitorEventKind::Accepted => {
+                snapshot.peers += 1;
+                snapshot.connects += 1;
+                ConnectionState::Connected
+            }
+            MonitorEventKind::Disconnected => {
+                snapshot.peers = snapshot.peers.saturating_sub(1);
+                snapshot.disconnects += 1;
+                match (snapshot.peers, mode) {
+                    (0, SocketMode::Connect) => ConnectionState::Disconnected,
+                    (0, SocketMode::Bind) => ConnectionState::Listening,
+                    _ => ConnectionState::Connected,
+                }
+            }
+            MonitorEventKind::ConnectRetried => {
+                snapshot.retries += 1;
+                snapshot.state
+            }
+            MonitorEventKind::ConnectDelayed | MonitorEventKind::Listening
+                if snapshot.peers == 0 && snapshot.state == ConnectionState::Starting =>
+            {
+                idle
+            }
+            MonitorEventKind::HandshakeFailedNoDetail
+            | MonitorEventKind::HandshakeFailedProtocol
+            | MonitorEventKind::HandshakeFailedAuth => {
+                snapshot.handshake_failures += 1;
+                snapshot.state
+            }
+            MonitorEventKind::BindFailed => ConnectionState::Failed,
+            MonitorEventKind::MonitorStopped => ConnectionState::Closed,
+            _ => snapshot.state,
+        };
+        if snapshot.recent.len() == RECENT_EVENTS {
+            snapshot.recent.pop_front();
+        }
+        snapshot.recent.push_back(event.clone());
+        if state == snapshot.state {
+            return None;
+        }
+        snapshot.state = state;
+        snapshot.since_ns = event.wall_time_ns;
+        Some(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: MonitorEventKind, wall_time_ns: u64) -> MonitorEvent {
+        MonitorEvent {
+            wall_time_ns,
+            kind,
+            value: 0,
+            address: "tcp://127.0.0.1:5555".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_monitor_message() {
+        let mut header = SocketEvent::CONNECT_RETRIED.to_raw().to_le_bytes().to_vec();
+        header.extend_from_slice(&200u32.to_le_bytes());
+        let parsed = MonitorEvent::parse(&[&header, b"tcp://127.0.0.1:5555"], 7).unwrap();
+        assert_eq!(parsed.kind, MonitorEventKind::ConnectRetried);
+        assert_eq!(parsed.value, 200);
+        assert_eq!(
+            parsed.to_string(),
+            "connect_retried tcp://127.0.0.1:5555 in 200ms"
+        );
+        assert_eq!(parsed.to_json()["event"], "connect_retried");
+        assert!(MonitorEvent::parse(&[b"short"], 7).is_none());
+        assert_eq!(
+            MonitorEventKind::from_raw(0x8000),
+            MonitorEventKind::Other(0x8000)
+        );
+    }
+
+    #[test]
+    fn test_connect_mode_states() {
+        let health = ConnectionHealth::new();
+        let mode = SocketMode::Connect;
+        assert_eq!(
+            health.record(&event(MonitorEventKind::ConnectDelayed, 1), mode),
+            Some(ConnectionState::Connecting)
+        );
+        assert_eq!(
+            health.record(&event(MonitorEventKind::ConnectRetried, 2), mode),
+            None
+        );
+        assert_eq!(
+            health.record(&event(MonitorEventKind::Connected, 3), mode),
+            Some(ConnectionState::Connected)
+        );
+        assert_eq!(
+            health.record(&event(MonitorEventKind::Disconnected, 4), mode),
+            Some(ConnectionState::Disconnected)
+        );
+        // Retries after a disconnect keep the connection disconnected
+        health.record(&event(MonitorEventKind::ConnectDelayed, 5), mode);
+        let snapshot = health.snapshot();
+        assert_eq!(snapshot.state, ConnectionState::Disconnected);
+        assert_eq!(snapshot.since_ns, 4);
+        assert_eq!(
+            (snapshot.connects, snapshot.disconnects, snapshot.retries),
+            (1, 1, 1)
+        );
+        assert_eq!(snapshot.recent.len(), 5);
+    }
+
+    #[test]
+    fn test_bind_mode_counts_peers() {
+        let health = ConnectionHealth::new();
+        let mode = SocketMode::Bind;
+        health.record(&event(MonitorEventKind::Listening, 1), mode);
+        assert_eq!(health.state(), ConnectionState::Listening);
+        health.record(&event(MonitorEventKind::Accepted, 2), mode);
+        health.record(&event(MonitorEventKind::Accepted, 3), mode);
+        assert_eq!(
+            health.record(&event(MonitorEventKind::Disconnected, 4), mode),
+            None
+        );
+        assert_eq!(
+            health.record(&event(MonitorEventKind::Disconnected, 5), mode),
+            Some(ConnectionState::Listening)
+        );
+        for time in 0..RECENT_EVENTS as u64 {
+            health.record(&event(MonitorEventKind::HandshakeFailedAuth, time), mode);
+        }
+        let snapshot = health.snapshot();
+        assert_eq!(snapshot.recent.len(), RECENT_EVENTS);
+        assert_eq!(snapshot.handshake_failures, RECENT_EVENTS as u64);
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use log::{error, info};
+use tmq::Context;
+use tokio::task::JoinHandle;
+
+use crate::metrics;
+use crate::process_zmq_connection::process_zmq_connection;
+use crate::shutdown::ShutdownCoordinator;
+use crate::zmq_connection::{MessageRecorderError, ZmqConnection};
+
+/// A connection whose receive loop is running.
+#[derive(Debug)]
+struct RunningConnection {
+    connection: Arc<ZmqConnection>,
+    stop: ShutdownCoordinator,
+    handle: JoinHandle<()>,
+}
+
+/// Files written by the sinks of running connections. Two sinks writing one
+/// file would interleave their records, so every file has one owner.
+#[derive(Debug, Default)]
+pub struct FileClaims {
+    files: Mutex<HashSet<String>>,
+}
+
+impl FileClaims {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashSet<String>> {
+        self.files.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Claims every file, or none of them and returns the first one taken.
+    pub fn claim(&self, files: &[String]) -> Result<(), String> {
+        let mut claimed = self.lock();
+        let mut wanted = HashSet::new();
+        if let Some(taken) = files
+            .iter()
+            .find(|file| claimed.contains(*file) || !wanted.insert(*file))
+        {
+            return Err(taken.clone());
+        }
+        claimed.extend(files.iter().cloned());
+        Ok(())
+    }
+
+    pub fn release(&self, files: &[String]) {
+        let mut claimed = self.lock();
+        for file in files {
+            claimed.remove(file);
+        }
+    }
+
+    pub fn is_claimed(&self, file: &str) -> bool {
+        self.lock().contains(file)
+    }
+}
+
+/// Running connections by id. Connections can be started and stopped while
+/// the recorder runs, stopping one drains and flushes its sinks first.
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    connections: Mutex<BTreeMap<u64, RunningConnection>>,
+    next_id: AtomicU64,
+    files: FileClaims,
+    /// Shared by the sockets of all connections
+    context: Context,
+}
+
+impl std::fmt::Debug for ConnectionRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ConnectionRegistry")
+            .field("connections", &self.connections)
+            .field("next_id", &self.next_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        ConnectionRegistry::default()
+    }
+
+    /// A registry whose connections open their sockets in `context`.
+    pub fn with_context(context: Context) -> Self {
+        ConnectionRegistry {
+            context,
+            ..Default::default()
+        }
+    }
+
+    /// Files claimed by the sinks of running connections.
+    pub fn files(&self) -> &FileClaims {
+        &self.files
+    }
+
+    /// Spawns the receive loop of `connection` and returns its id. Refuses
+    /// a connection whose sinks write a file another running sink writes.
+    /// Must be called from within a tokio runtime.
+    pub fn start(&self, connection: ZmqConnection) -> Result<u64, MessageRecorderError> {
+        self.files
+            .claim(&connection.sink_files())
+            .map_err(MessageRecorderError::FileInUse)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        info!("Subscribing to connection {}: {:?}", id, connection);
+        let connection = Arc::new(connection);
+        let stop = ShutdownCoordinator::new();
+        let shutdown = stop.subscribe();
+        let task_connection = Arc::clone(&connection);
+        let context = self.context.clone();
+        let handle = tokio::spawn(async move {
+            match process_zmq_connection(&task_connection, &context, shutdown).await {
+                Ok(_) => info!("Stopped connection {}", task_connection),
+                Err(e) => error!(
+                    "Error received from subscribe function for connection {:?}: {}",
+                    task_connection, e
+                ),
+            };
+        });
+        self.lock().insert(
+            id,
+            RunningConnection {
+                connection,
+                stop,
+                handle,
+            },
+        );
+        Ok(id)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, RunningConnection>> {
+        // Nothing panics while holding the lock, so the map is always consistent
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Every running connection with its id, by id.
+    pub fn list(&self) -> Vec<(u64, Arc<ZmqConnection>)> {
+        self.lock()
+            .iter()
+            .map(|(id, running)| (*id, Arc::clone(&running.connection)))
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<ZmqConnection>> {
+        self.lock()
+            .get(&id)
+            .map(|running| Arc::clone(&running.connection))
+    }
+
+    /// Stops receiving on the connection, then drains, flushes and closes its
+    /// sinks, so nothing already received is lost. Returns false for an unknown id.
+    pub async fn stop(&self, id: u64) -> Result<bool, MessageRecorderError> {
+        let running = match self.lock().remove(&id) {
+            Some(running) => running,
+            None => return Ok(false),
+        };
+        running.stop.trigger();
+        if let Err(e) = running.handle.await {
+            error!("Receive loop of connection {} failed: {:?}", id, e);
+        }
+        let files = running.connection.sink_files();
+        let closed = running.connection.close_sinks().await;
+        self.files.release(&files);
+        let host = running.connection.get_host();
+        // Connections to the same endpoint share their metrics
+        let shared = self
+            .lock()
+            .values()
+            .any(|other| other.connection.get_host() == host);
+        if !shared {
+            metrics::global().remove_connection(&host);
+        }
+        closed?;
+        Ok(true)
+    }
+
+    /// Closes one sink of `connection` like `ZmqConnection::close_sink` and
+    /// releases its file.
+    pub async fn close_sink(
+        &self,
+        connection: &ZmqConnection,
+        sink_name: &str,
+    ) -> Result<bool, MessageRecorderError> {
+        let file = connection.sink_file(sink_name);
+        let closed = connection.close_sink(sink_name).await;
+        if let Some(file) = file {
+            self.files.release(&[file]);
+        }
+        closed
+    }
+
+    /// Stops every connection. Returns the first failure to close sinks,
+    /// after trying all of them.
+    pub async fn stop_all(&self) -> Result<(), MessageRecorderError> {
+        let ids: Vec<u64> = self.lock().keys().copied().collect();
+        let mut result = Ok(());
+        for id in ids {
+            if let Err(e) = self.stop(id).await {
+                error!("Failed to close sinks for connection {}: {}", id, e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoint::Endpoint;
+    use crate::message_envelope::MessageEnvelope;
+    use crate::sink::SinksEnum;
+    use crate::sink_worker::OverflowPolicy;
+    use crate::sinks::file_sink::FileSink;
+
+    #[tokio::test]
+    async fn test_start_list_and_stop() {
+        let registry = ConnectionRegistry::new();
+        // Nothing publishes there, connecting a SUB socket still succeeds
+        let first = registry
+            .start(ZmqConnection::new(
+                Endpoint::tcp("127.0.0.1", 45871).unwrap(),
+                None,
+                "rec",
+            ))
+            .unwrap();
+        let second = registry
+            .start(ZmqConnection::new(
+                Endpoint::tcp("127.0.0.1", 45872).unwrap(),
+                None,
+                "rec",
+            ))
+            .unwrap();
+        assert_ne!(first, second);
+        let hosts: Vec<String> = registry
+            .list()
+            .iter()
+            .map(|(_, connection)| connection.get_host())
+            .collect();
+        assert_eq!(
+            hosts,
+            vec!["tcp://127.0.0.1:45871", "tcp://127.0.0.1:45872"]
+        );
+
+        assert!(registry.stop(first).await.unwrap());
+        assert!(!registry.stop(first).await.unwrap());
+        assert!(registry.get(first).is_none());
+        assert!(registry.get(second).is_some());
+        registry.stop_all().await.unwrap();
+        assert!(registry.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stop_flushes_queued_messages() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let filename = temp_dir.path().join("stop.rec");
+        let connection =
+            ZmqConnection::new(Endpoint::tcp("127.0.0.1", 45873).unwrap(), None, "rec");
+        let sink = FileSink::new(filename.to_str().unwrap().to_string(), 3600).unwrap();
+        connection
+            .register_new_sink(
+                "file".to_string(),
+                Box::new(SinksEnum::FileSink(sink)),
+                16,
+                OverflowPolicy::Block,
+            )
+            .unwrap();
+        let registry = ConnectionRegistry::new();
+        let id = registry.start(connection).unwrap();
+        let connection = registry.get(id).unwrap();
+        connection
+            .use_sinks(MessageEnvelope::new(
+                0,
+                connection.get_host(),
+                None,
+                vec![b"payload".to_vec()],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::metadata(&filename).unwrap().len(), 0);
+
+        registry.stop(id).await.unwrap();
+        assert!(std::fs::metadata(&filename).unwrap().len() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_sinks_never_share_a_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let filename = temp_dir.path().join("shared.rec");
+        let filename = filename.to_str().unwrap().to_string();
+        let connection = |port: u16| {
+            let connection =
+                ZmqConnection::new(Endpoint::tcp("127.0.0.1", port).unwrap(), None, "rec");
+            let sink = FileSink::new(filename.clone(), 3600).unwrap();
+            connection
+                .register_new_sink(
+                    "file".to_string(),
+                    Box::new(SinksEnum::FileSink(sink)),
+                    16,
+                    OverflowPolicy::Block,
+                )
+                .unwrap();
+            connection.set_sink_file("file", filename.clone());
+            connection
+        };
+        let registry = ConnectionRegistry::new();
+        let first = registry.start(connection(45875)).unwrap();
+
+        let refused = registry.start(connection(45876)).unwrap_err();
+        assert!(matches!(refused, MessageRecorderError::FileInUse(file) if file == filename));
+
+        let running = registry.get(first).unwrap();
+        assert!(registry.close_sink(&running, "file").await.unwrap());
+        assert!(!registry.files().is_claimed(&filename));
+        let second = registry.start(connection(45876)).unwrap();
+        assert!(registry.files().is_claimed(&filename));
+        registry.stop(second).await.unwrap();
+        assert!(!registry.files().is_claimed(&filename));
+        registry.stop_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stop_removes_connection_metrics() {
+        let registry = ConnectionRegistry::new();
+        let id = registry
+            .start(ZmqConnection::new(
+                Endpoint::tcp("127.0.0.1", 45874).unwrap(),
+                None,
+                "rec",
+            ))
+            .unwrap();
+        assert!(metrics::global().encode().contains("tcp://127.0.0.1:45874"));
+
+        registry.stop(id).await.unwrap();
+        assert!(!metrics::global().encode().contains("tcp://127.0.0.1:45874"));
+    }
+}
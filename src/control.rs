@@ -0,0 +1,411 @@
+// Handlers return tonic::Status, which is large but what the generated trait expects
+#![allow(clippy::result_large_err)]
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{error, info};
+use prost_reflect::DescriptorPool;
+use tonic::{Request, Response, Status};
+
+use crate::connection_registry::ConnectionRegistry;
+use crate::shutdown::ShutdownSignal;
+use crate::utils::config::{parse_connection, parse_sink, SecretSources};
+use crate::zmq_connection::ZmqConnection;
+
+pub mod proto {
+    tonic::include_proto!("control");
+}
+
+use proto::recorder_control_server::{RecorderControl, RecorderControlServer};
+use proto::{
+    AddConnectionRequest, AddSinkRequest, ConnectionInfo, ConnectionRequest, FlushRequest,
+    FlushResponse, ListConnectionsRequest, ListConnectionsResponse, RemoveConnectionResponse,
+    RemoveSinkRequest, SinkInfo,
+};
+
+/// gRPC service managing the connections of a running recorder.
+#[derive(Debug)]
+pub struct ControlService {
+    registry: Arc<ConnectionRegistry>,
+    /// Descriptor sets of the config, for message types of added connections
+    pool: DescriptorPool,
+}
+
+impl ControlService {
+    pub fn new(registry: Arc<ConnectionRegistry>, pool: DescriptorPool) -> Self {
+        ControlService { registry, pool }
+    }
+
+    fn connection(&self, id: u64) -> Result<Arc<ZmqConnection>, Status> {
+        self.registry
+            .get(id)
+            .ok_or_else(|| Status::not_found(format!("No connection with id {}", id)))
+    }
+}
+
+fn connection_info(id: u64, connection: &ZmqConnection) -> Result<ConnectionInfo, Status> {
+    let sinks = connection
+        .sink_status()
+        .map_err(|e| Status::internal(e.to_string()))?
+        .into_iter()
+        .map(|(name, queued, dropped)| SinkInfo {
+            name,
+            queued_messages: queued as u64,
+            dropped_messages: dropped,
+        })
+        .collect();
+    let health = connection.get_health().snapshot();
+    Ok(ConnectionInfo {
+        id,
+        host: connection.get_host(),
+        topics: connection.get_topics().clone(),
+        paused: connection.is_paused(),
+        sinks,
+        state: health.state.name().to_string(),
+        state_since_ns: health.since_ns,
+        peers: health.peers,
+        connects: health.connects,
+        disconnects: health.disconnects,
+        retries: health.retries,
+        handshake_failures: health.handshake_failures,
+        recent_events: health
+            .recent
+            .iter()
+            .map(|event| proto::MonitorEvent {
+                event: event.kind.name().to_string(),
+                value: event.value,
+                address: event.address.clone(),
+                wall_time_ns: event.wall_time_ns,
+            })
+            .collect(),
+    })
+}
+
+#[tonic::async_trait]
+impl RecorderControl for ControlService {
+    async fn list_connections(
+        &self,
+        _request: Request<ListConnectionsRequest>,
+    ) -> Result<Response<ListConnectionsResponse>, Status> {
+        let connections = self
+            .registry
+            .list()
+            .iter()
+            .map(|(id, connection)| connection_info(*id, connection))
+            .collect::<Result<_, _>>()?;
+        Ok(Response::new(ListConnectionsResponse { connections }))
+    }
+
+    async fn add_connection(
+        &self,
+        request: Request<AddConnectionRequest>,
+    ) -> Result<Response<ConnectionInfo>, Status> {
+        // Clients must not get to read the recorder's files or environment
+        let connection = parse_connection(
+            &request.get_ref().config_yaml,
+            &self.pool,
+            SecretSources::InlineOnly,
+            self.registry.files(),
+        )
+        .map_err(Status::invalid_argument)?;
+        let id = self
+            .registry
+            .start(connection)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let connection = self.connection(id)?;
+        Ok(Response::new(connection_info(id, &connection)?))
+    }
+
+    async fn remove_connection(
+        &self,
+        request: Request<ConnectionRequest>,
+    ) -> Result<Response<RemoveConnectionResponse>, Status> {
+        let id = request.get_ref().id;
+        match self.registry.stop(id).await {
+            Ok(true) => Ok(Response::new(RemoveConnectionResponse {})),
+            Ok(false) => Err(Status::not_found(format!("No connection with id {}", id))),
+            Err(e) => Err(Status::internal(format!(
+                "Stopped connection {} but failed to close its sinks: {}",
+                id, e
+            ))),
+        }
+    }
+
+    async fn add_sink(
+        &self,
+        request: Request<AddSinkRequest>,
+    ) -> Result<Response<ConnectionInfo>, Status> {
+        let request = request.get_ref();
+        let connection = self.connection(request.connection_id)?;
+        parse_sink(&request.config_yaml, &connection, self.registry.files())
+            .map_err(Status::invalid_argument)?;
+        Ok(Response::new(connection_info(
+            request.connection_id,
+            &connection,
+        )?))
+    }
+
+    async fn remove_sink(
+        &self,
+        request: Request<RemoveSinkRequest>,
+    ) -> Result<Response<ConnectionInfo>, Status> {
+        let request = request.get_ref();
+        let connection = self.connection(request.connection_id)?;
+        match self.registry.close_sink(&connection, &request.name).await {
+            Ok(true) => Ok(Response::new(connection_info(
+                request.connection_id,
+                &connection,
+            )?)),
+            Ok(false) => Err(Status::not_found(format!(
+                "No sink named {} on connection {}",
+                request.name, request.connection_id
+            ))),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn pause_connection(
+        &self,
+        request: Request<ConnectionRequest>,
+    ) -> Result<Response<ConnectionInfo>, Status> {
+        let id = request.get_ref().id;
+        let connection = self.connection(id)?;
+        connection.pause();
+        Ok(Response::new(connection_info(id, &connection)?))
+    }
+
+    async fn resume_connection(
+        &self,
+        request: Request<ConnectionRequest>,
+    ) -> Result<Response<ConnectionInfo>, Status> {
+        let id = request.get_ref().id;
+        let connection = self.connection(id)?;
+        connection.resume();
+        Ok(Response::new(connection_info(id, &connection)?))
+    }
+
+    async fn flush(
+        &self,
+        request: Request<FlushRequest>,
+    ) -> Result<Response<FlushResponse>, Status> {
+        let ids = match request.get_ref().connection_ids.as_slice() {
+            [] => self.registry.list().iter().map(|(id, _)| *id).collect(),
+            ids => ids.to_vec(),
+        };
+        let mut flushed = vec![];
+        for id in ids {
+            self.connection(id)?
+                .flush_sinks()
+                .await
+                .map_err(|e| Status::internal(format!("Connection {}: {}", id, e)))?;
+            flushed.push(id);
+        }
+        Ok(Response::new(FlushResponse { flushed }))
+    }
+}
+
+/// Serves the control service on `address` until shutdown.
+pub async fn serve(
+    address: SocketAddr,
+    registry: Arc<ConnectionRegistry>,
+    pool: DescriptorPool,
+    mut shutdown: ShutdownSignal,
+) {
+    info!("Serving the control service on {}", address);
+    let result = tonic::transport::Server::builder()
+        .add_service(RecorderControlServer::new(ControlService::new(
+            registry, pool,
+        )))
+        .serve_with_shutdown(address, async move { shutdown.wait().await })
+        .await;
+    if let Err(e) = result {
+        error!("Control service on {} failed: {}", address, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_health::{MonitorEvent, MonitorEventKind};
+
+    const CONNECTION: &str = "
+addr: 127.0.0.1
+port: 45881
+topic: quotes
+file_extension: rec
+";
+
+    fn service() -> ControlService {
+        ControlService::new(Arc::new(ConnectionRegistry::new()), DescriptorPool::new())
+    }
+
+    async fn add_connection(service: &ControlService, yaml: &str) -> ConnectionInfo {
+        service
+            .add_connection(Request::new(AddConnectionRequest {
+                config_yaml: yaml.to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+    }
+
+    #[test]
+    fn test_connection_info_reports_health() {
+        let connection = ZmqConnection::new(
+            crate::endpoint::Endpoint::tcp("127.0.0.1", 45882).unwrap(),
+            None,
+            "rec",
+        );
+        let mode = connection.get_socket_config().mode;
+        for (kind, wall_time_ns) in [
+            (MonitorEventKind::Connected, 1),
+            (MonitorEventKind::Disconnected, 2),
+            (MonitorEventKind::ConnectRetried, 3),
+        ] {
+            let event = MonitorEvent {
+                wall_time_ns,
+                kind,
+                value: 100,
+                address: "tcp://127.0.0.1:45882".to_string(),
+            };
+            connection.get_health().record(&event, mode);
+        }
+
+        let info = connection_info(7, &connection).unwrap();
+        assert_eq!(info.state, "disconnected");
+        assert_eq!(info.state_since_ns, 2);
+        assert_eq!((info.connects, info.disconnects, info.retries), (1, 1, 1));
+        assert_eq!(info.handshake_failures, 0);
+        assert_eq!(info.recent_events.len(), 3);
+        assert_eq!(
+            info.recent_events[2],
+            proto::MonitorEvent {
+                event: "connect_retried".to_string(),
+                value: 100,
+                address: "tcp://127.0.0.1:45882".to_string(),
+                wall_time_ns: 3,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_pause_and_remove_connection() {
+        let service = service();
+        let info = add_connection(&service, CONNECTION).await;
+        assert_eq!(info.host, "tcp://127.0.0.1:45881");
+        assert_eq!(info.topics, vec!["quotes"]);
+        assert!(!info.paused);
+
+        let paused = service
+            .pause_connection(Request::new(ConnectionRequest { id: info.id }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(paused.paused);
+        let resumed = service
+            .resume_connection(Request::new(ConnectionRequest { id: info.id }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!resumed.paused);
+
+        let listed = service
+            .list_connections(Request::new(ListConnectionsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(listed.connections, vec![resumed]);
+
+        service
+            .remove_connection(Request::new(ConnectionRequest { id: info.id }))
+            .await
+            .unwrap();
+        let status = service
+            .remove_connection(Request::new(ConnectionRequest { id: info.id }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_add_flush_and_remove_sink() {
+        let service = service();
+        let info = add_connection(&service, CONNECTION).await;
+        assert!(info.sinks.is_empty());
+        // A counter writes no files
+        let with_sink = service
+            .add_sink(Request::new(AddSinkRequest {
+                connection_id: info.id,
+                config_yaml: "sink_type: Message Counter\nname: counter\n".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(with_sink.sinks.len(), 1);
+        assert_eq!(with_sink.sinks[0].name, "counter");
+
+        let duplicate = service
+            .add_sink(Request::new(AddSinkRequest {
+                connection_id: info.id,
+                config_yaml: "sink_type: Message Counter\nname: counter\n".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(duplicate.code(), tonic::Code::InvalidArgument);
+        let negative = service
+            .add_sink(Request::new(AddSinkRequest {
+                connection_id: info.id,
+                config_yaml: "sink_type: File Sink\nflush_time: -1\n".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(negative.code(), tonic::Code::InvalidArgument);
+
+        let flushed = service
+            .flush(Request::new(FlushRequest {
+                connection_ids: vec![],
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(flushed.flushed, vec![info.id]);
+
+        let without_sink = service
+            .remove_sink(Request::new(RemoveSinkRequest {
+                connection_id: info.id,
+                name: "counter".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(without_sink.sinks.is_empty());
+        let missing = service
+            .remove_sink(Request::new(RemoveSinkRequest {
+                connection_id: info.id,
+                name: "counter".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(missing.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_bad_requests() {
+        let service = service();
+        let status = service
+            .add_connection(Request::new(AddConnectionRequest {
+                config_yaml: "addr: [".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let status = service
+            .flush(Request::new(FlushRequest {
+                connection_ids: vec![7],
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+}
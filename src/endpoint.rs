@@ -0,0 +1,212 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+
+/// Host part of a TCP endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpHost {
+    V4(Ipv4Addr),
+    V6(Ipv6Addr),
+    /// Host name or interface name, resolved by ZMQ
+    Name(String),
+}
+
+impl TryFrom<&str> for TcpHost {
+    type Error = String;
+
+    /// Accepts IPv6 addresses with or without the brackets of an endpoint.
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let unbracketed = value
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'));
+        if let Some(address) = unbracketed {
+            return address
+                .parse()
+                .map(TcpHost::V6)
+                .map_err(|_| format!("'{}' is not an IPv6 address", value));
+        }
+        if let Ok(address) = value.parse() {
+            return Ok(TcpHost::V4(address));
+        }
+        if let Ok(address) = value.parse() {
+            return Ok(TcpHost::V6(address));
+        }
+        if value.is_empty()
+            || value
+                .chars()
+                .any(|c| c.is_whitespace() || matches!(c, '/' | ':' | '[' | ']'))
+        {
+            return Err(format!("'{}' is not a valid host", value));
+        }
+        Ok(TcpHost::Name(value.to_string()))
+    }
+}
+
+impl std::fmt::Display for TcpHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TcpHost::V4(address) => write!(f, "{}", address),
+            TcpHost::V6(address) => write!(f, "[{}]", address),
+            TcpHost::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Where a ZMQ socket connects to. Displays as the ZMQ endpoint string,
+/// e.g. `tcp://[::1]:5555`, `ipc:///tmp/feed.sock` or `inproc://feed`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp {
+        host: TcpHost,
+        port: u16,
+    },
+    /// Unix domain socket
+    Ipc {
+        path: PathBuf,
+    },
+    /// Only reaches sockets of the same ZMQ context
+    Inproc {
+        name: String,
+    },
+}
+
+impl Endpoint {
+    pub fn tcp(host: &str, port: u16) -> Result<Endpoint, String> {
+        Ok(Endpoint::Tcp {
+            host: TcpHost::try_from(host)?,
+            port,
+        })
+    }
+
+    pub fn transport(&self) -> &'static str {
+        match self {
+            Endpoint::Tcp { .. } => "tcp",
+            Endpoint::Ipc { .. } => "ipc",
+            Endpoint::Inproc { .. } => "inproc",
+        }
+    }
+
+    /// The endpoint as the start of a file name. IPv6 colons become dashes,
+    /// other separators are left for the caller to replace.
+    pub fn file_stem(&self) -> String {
+        match self {
+            Endpoint::Tcp {
+                host: TcpHost::V6(address),
+                port,
+            } => format!("tcp://{}:{}", address.to_string().replace(':', "-"), port),
+            endpoint => endpoint.to_string(),
+        }
+    }
+}
+
+impl TryFrom<&str> for Endpoint {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let (transport, address) = value.split_once("://").ok_or_else(|| {
+            format!(
+                "'{}' is not an endpoint, expected tcp://, ipc:// or inproc://",
+                value
+            )
+        })?;
+        match transport {
+            "tcp" => {
+                let (host, port) = address
+                    .rsplit_once(':')
+                    .ok_or_else(|| format!("'{}' has no port", value))?;
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("'{}' is not a valid port in '{}'", port, value))?;
+                Endpoint::tcp(host, port)
+            }
+            "ipc" if !address.is_empty() => Ok(Endpoint::Ipc {
+                path: PathBuf::from(address),
+            }),
+            "inproc" if !address.is_empty() => Ok(Endpoint::Inproc {
+                name: address.to_string(),
+            }),
+            "ipc" | "inproc" => Err(format!("'{}' has no address", value)),
+            _ => Err(format!(
+                "Unknown transport '{}', expected tcp, ipc or inproc",
+                transport
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Endpoint::Tcp { host, port } => write!(f, "tcp://{}:{}", host, port),
+            Endpoint::Ipc { path } => write!(f, "ipc://{}", path.display()),
+            Endpoint::Inproc { name } => write!(f, "inproc://{}", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display_round_trip() {
+        for endpoint in [
+            "tcp://127.0.0.1:5555",
+            "tcp://localhost:5555",
+            "tcp://[::1]:5555",
+            "tcp://[fe80::1:2]:6000",
+            "ipc:///tmp/feeds/md.sock",
+            "ipc://relative.sock",
+            "inproc://feed",
+        ] {
+            assert_eq!(Endpoint::try_from(endpoint).unwrap().to_string(), endpoint);
+        }
+        assert_eq!(
+            Endpoint::try_from("tcp://[::1]:5555").unwrap(),
+            Endpoint::Tcp {
+                host: TcpHost::V6(Ipv6Addr::LOCALHOST),
+                port: 5555
+            }
+        );
+    }
+
+    #[test]
+    fn test_tcp_hosts() {
+        assert_eq!(
+            Endpoint::tcp("::1", 5555).unwrap().to_string(),
+            "tcp://[::1]:5555"
+        );
+        assert_eq!(
+            Endpoint::tcp("10.0.0.1", 1).unwrap(),
+            Endpoint::Tcp {
+                host: TcpHost::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                port: 1
+            }
+        );
+        assert!(Endpoint::tcp("", 5555).is_err());
+        assert!(Endpoint::tcp("bad host", 5555).is_err());
+        assert!(Endpoint::tcp("[not-v6]", 5555).is_err());
+    }
+
+    #[test]
+    fn test_rejects_bad_endpoints() {
+        assert!(Endpoint::try_from("localhost:5555").is_err());
+        assert!(Endpoint::try_from("tcp://localhost").is_err());
+        assert!(Endpoint::try_from("tcp://localhost:99999").is_err());
+        assert!(Endpoint::try_from("ipc://").is_err());
+        assert!(Endpoint::try_from("udp://localhost:5555").is_err());
+    }
+
+    #[test]
+    fn test_file_stem() {
+        assert_eq!(
+            Endpoint::try_from("tcp://[::1]:5555").unwrap().file_stem(),
+            "tcp://--1:5555"
+        );
+        assert_eq!(
+            Endpoint::try_from("ipc:///tmp/md.sock")
+                .unwrap()
+                .file_stem(),
+            "ipc:///tmp/md.sock"
+        );
+    }
+}
@@ -0,0 +1,19 @@
+pub mod capture_socket;
+pub mod connection_health;
+pub mod connection_registry;
+pub mod control;
+pub mod endpoint;
+pub mod message_decoding;
+pub mod message_envelope;
+pub mod metrics;
+pub mod process_zmq_connection;
+pub mod recording;
+pub mod replay;
+pub mod security;
+pub mod shutdown;
+pub mod sink;
+pub mod sink_worker;
+pub mod sinks;
+pub mod topic_filter;
+pub mod utils;
+pub mod zmq_connection;
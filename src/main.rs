@@ -1,14 +1,15 @@
-mod message_decoding;
-mod process_zmq_connection;
-mod sink;
-mod sinks;
-mod utils;
-mod zmq_connection;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use env_logger;
-use log::{error, info};
+use log::{error, info, warn};
 
-use crate::process_zmq_connection::process_zmq_connection;
+use message_recorder::capture_socket;
+use message_recorder::connection_registry::ConnectionRegistry;
+use message_recorder::control;
+use message_recorder::metrics;
+use message_recorder::recording;
+use message_recorder::shutdown::ShutdownCoordinator;
+use message_recorder::utils;
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
@@ -16,34 +17,259 @@ async fn main() {
     env_logger::builder()
         .filter_level(log::LevelFilter::Debug)
         .init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("recover") {
+        recover(&args[1..]);
+        return;
+    }
+    if args.first().map(String::as_str) == Some("index") {
+        index(&args[1..]);
+        return;
+    }
+    if args.first().map(String::as_str) == Some("decode") {
+        decode(&args[1..]);
+        return;
+    }
+    if args.first().map(String::as_str) == Some("train-dictionary") {
+        train_dictionary(&args[1..]);
+        return;
+    }
     info!("Starting up");
 
-    let subscriptions = utils::config::read_config("config/config.yml");
+    let config = utils::config::extract_config("config/config.yml");
+    let io_threads = match utils::config::read_io_threads(&config) {
+        Ok(io_threads) => io_threads,
+        Err(e) => {
+            error!("Bad ZMQ config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let context = match capture_socket::new_context(io_threads) {
+        Ok(context) => context,
+        Err(e) => {
+            error!("Failed to set {:?} ZMQ I/O threads: {}", io_threads, e);
+            std::process::exit(1);
+        }
+    };
+    match context.get_io_threads() {
+        Ok(io_threads) => info!("ZMQ context with {} I/O threads", io_threads),
+        Err(e) => warn!("Failed to read the ZMQ I/O threads: {}", e),
+    }
+    let registry = Arc::new(ConnectionRegistry::with_context(context));
+    let coordinator = ShutdownCoordinator::new();
 
-    let mut handles = vec![];
+    if let Some(address) = utils::config::read_metrics_address(&config) {
+        match tokio::net::TcpListener::bind(&address).await {
+            Ok(listener) => {
+                tokio::spawn(metrics::serve(listener, coordinator.subscribe()));
+            }
+            Err(e) => error!("Failed to serve metrics on {}: {}", address, e),
+        }
+    }
 
-    // Spawn a Tokio task for each subscription
-    for connection in subscriptions {
-        info!("Subscribing to connection: {:?}", connection);
+    // Loaded once for the configured connections and those added later
+    let pool = utils::config::read_descriptor_pool(&config);
+    if let Some(address) = utils::config::read_control_address(&config) {
+        match address.parse() {
+            Ok(address) => {
+                tokio::spawn(control::serve(
+                    address,
+                    Arc::clone(&registry),
+                    pool.clone(),
+                    coordinator.subscribe(),
+                ));
+            }
+            Err(e) => error!("Bad control service address {}: {}", address, e),
+        }
+    }
+
+    for connection in utils::config::read_config(&config, &pool) {
+        if let Err(e) = registry.start(connection) {
+            error!("Skipping connection: {}", e);
+        }
+    }
+
+    if let Err(e) = ShutdownCoordinator::wait_for_signal().await {
+        error!("Failed to listen for shutdown signals: {}", e);
+    }
+    coordinator.trigger();
 
-        let handle = tokio::spawn(async move {
-            match process_zmq_connection(&connection).await {
-                Ok(value) => error!(
-                    "Wait exited for connection {:?} without an error {:?}",
-                    connection, value
-                ),
-                Err(e) => error!(
-                    "Error received from subscribe function for connection {:?}: {}",
-                    connection, e
-                ),
+    // Stops each receive loop before draining its sinks, so nothing else can write to them
+    if registry.stop_all().await.is_err() {
+        std::process::exit(1);
+    }
+    info!("Shut down cleanly");
+}
+
+/// `recover <file>... [--truncate]` scans recordings for damaged records and
+/// optionally cuts them back to their last valid record.
+fn recover(args: &[String]) {
+    let truncate = args.iter().any(|arg| arg == "--truncate");
+    let files: Vec<&String> = args.iter().filter(|arg| *arg != "--truncate").collect();
+    if files.is_empty() {
+        error!("Usage: message-recorder recover <file>... [--truncate]");
+        std::process::exit(2);
+    }
+    let mut damaged = false;
+    for file in files {
+        match recording::framing::recover(Path::new(file), truncate) {
+            Ok(report) => damaged |= !report.problems.is_empty(),
+            Err(e) => {
+                error!("Failed to recover {}: {}", file, e);
+                damaged = true;
+            }
+        }
+    }
+    if damaged {
+        std::process::exit(1);
+    }
+}
+
+/// `index <file>...` rebuilds the index sidecars of recordings, e.g. of
+/// files a crashed recorder left without one.
+fn index(files: &[String]) {
+    if files.is_empty() {
+        error!("Usage: message-recorder index <file>...");
+        std::process::exit(2);
+    }
+    let mut failed = false;
+    for file in files {
+        let path = Path::new(file);
+        let indexed = recording::reader::RecordingReader::open(path)
+            .and_then(|mut reader| reader.index().cloned())
+            .map_err(|e| e.to_string())
+            .and_then(|index| {
+                index.write(path).map_err(|e| e.to_string())?;
+                Ok(index)
+            });
+        match indexed {
+            Ok(index) => info!(
+                "Indexed {} messages of {} with {} entries",
+                index.messages(),
+                file,
+                index.entries().len()
+            ),
+            Err(e) => {
+                error!("Failed to index {}: {}", file, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// `decode <file>...` prints the messages of recordings as JSON lines, with
+/// payloads decoded by the message types configured for their connection.
+/// Recorded monitor events are printed too.
+fn decode(files: &[String]) {
+    if files.is_empty() {
+        error!("Usage: message-recorder decode <file>...");
+        std::process::exit(2);
+    }
+    let decoders =
+        utils::config::read_decoders(&utils::config::extract_config("config/config.yml"));
+    let mut failed = false;
+    for file in files {
+        let mut reader = match recording::reader::RecordingReader::open(Path::new(file)) {
+            Ok(reader) => reader,
+            Err(e) => {
+                error!("Failed to open {}: {}", file, e);
+                failed = true;
+                continue;
+            }
+        };
+        // Shows when the connection dropped, if the sink recorded events
+        reader.set_include_events(true);
+        let decoder = decoders.get(reader.header().connection());
+        if decoder.is_none() {
+            warn!(
+                "No message types are configured for {}, {} is printed undecoded",
+                reader.header().connection(),
+                file
+            );
+        }
+        for message in reader {
+            let message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    error!("Failed to read {}: {}", file, e);
+                    failed = true;
+                    break;
+                }
             };
-        });
-        handles.push(handle);
+            let payload = match decoder.map(|decoder| decoder.decode(&message)) {
+                Some(Ok(Some(decoded))) => serde_json::to_value(&decoded)
+                    .unwrap_or_else(|e| serde_json::Value::String(e.to_string())),
+                Some(Err(e)) => {
+                    warn!("Message {} of {}: {}", message.sequence(), file, e);
+                    serde_json::Value::Null
+                }
+                Some(Ok(None)) | None => serde_json::Value::Null,
+            };
+            let line = serde_json::json!({
+                "sequence": message.sequence(),
+                "wall_time_ns": message.wall_time_ns(),
+                "topic": message.topic(),
+                "payload": payload,
+            });
+            println!("{}", line);
+        }
     }
+    if failed {
+        std::process::exit(1);
+    }
+}
 
-    for handle in handles {
-        if let Err(e) = handle.await {
-            error!("A task failed with error: {:?}", e);
+/// `train-dictionary <output> <recording>... [--size <bytes>]` trains a zstd
+/// dictionary for the `dictionary` option of compressed sinks.
+fn train_dictionary(args: &[String]) {
+    let usage = "Usage: message-recorder train-dictionary <output> <recording>... [--size <bytes>]";
+    let mut size = recording::dictionary::DEFAULT_DICTIONARY_SIZE;
+    let mut paths = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--size" {
+            match args.next().and_then(|size| size.parse().ok()) {
+                Some(parsed) if parsed <= recording::header::MAX_DICTIONARY_SIZE => size = parsed,
+                Some(parsed) => {
+                    error!(
+                        "A {} byte dictionary is more than recordings take, at most {}",
+                        parsed,
+                        recording::header::MAX_DICTIONARY_SIZE
+                    );
+                    std::process::exit(2);
+                }
+                None => {
+                    error!("{}", usage);
+                    std::process::exit(2);
+                }
+            }
+        } else {
+            paths.push(PathBuf::from(arg));
+        }
+    }
+    if paths.len() < 2 {
+        error!("{}", usage);
+        std::process::exit(2);
+    }
+    let output = paths.remove(0);
+    let dictionary = match recording::dictionary::train_dictionary(&paths, size) {
+        Ok(dictionary) => dictionary,
+        Err(e) => {
+            error!("Failed to train a dictionary: {}", e);
+            std::process::exit(1);
         }
+    };
+    if let Err(e) = std::fs::write(&output, &dictionary) {
+        error!("Failed to write {}: {}", output.display(), e);
+        std::process::exit(1);
     }
+    info!(
+        "Wrote a {} byte dictionary to {}",
+        dictionary.len(),
+        output.display()
+    );
 }
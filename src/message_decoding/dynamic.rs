@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use prost_reflect::prost::DecodeError;
+use prost_reflect::{DescriptorError, DescriptorPool, DynamicMessage, MessageDescriptor};
+
+use crate::message_envelope::MessageEnvelope;
+
+#[derive(Debug)]
+pub enum DecodingError {
+    IoError(std::io::Error),
+    DescriptorError(DescriptorError),
+    UnknownMessageType(String),
+    DecodeError {
+        message_type: String,
+        err: DecodeError,
+    },
+    NoPayload,
+}
+
+impl From<std::io::Error> for DecodingError {
+    fn from(err: std::io::Error) -> Self {
+        DecodingError::IoError(err)
+    }
+}
+
+impl From<DescriptorError> for DecodingError {
+    fn from(err: DescriptorError) -> Self {
+        DecodingError::DescriptorError(err)
+    }
+}
+
+impl std::fmt::Display for DecodingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DecodingError::IoError(err) => write!(f, "IO error: {}", err),
+            DecodingError::DescriptorError(err) => write!(f, "Bad descriptor set: {}", err),
+            DecodingError::UnknownMessageType(name) => {
+                write!(f, "No message type {} in the descriptor sets", name)
+            }
+            DecodingError::DecodeError { message_type, err } => {
+                write!(f, "Payload is not a valid {}: {}", message_type, err)
+            }
+            DecodingError::NoPayload => write!(f, "Message has no payload frame"),
+        }
+    }
+}
+
+/// Loads `FileDescriptorSet` files, e.g. from `protoc --include_imports
+/// --descriptor_set_out`, into one pool of message types.
+pub fn load_descriptor_sets<P: AsRef<Path>>(paths: &[P]) -> Result<DescriptorPool, DecodingError> {
+    let mut pool = DescriptorPool::new();
+    for path in paths {
+        let encoded = std::fs::read(path)?;
+        pool.decode_file_descriptor_set(encoded.as_slice())?;
+    }
+    Ok(pool)
+}
+
+/// Decodes the payloads of one connection as the protobuf message type
+/// configured for their topic. The payload is the last frame of a message,
+/// the frame after the topic in the usual topic and payload pair.
+#[derive(Debug, Clone)]
+pub struct MessageDecoder {
+    /// Type of messages whose topic matches no prefix in `by_topic`
+    default: Option<MessageDescriptor>,
+    /// Topic prefixes with their type, longest prefix first
+    by_topic: Vec<(String, MessageDescriptor)>,
+}
+
+impl MessageDecoder {
+    /// Looks up the fully-qualified type names, e.g. `market.Quote`, in `pool`.
+    pub fn new(
+        pool: &DescriptorPool,
+        default: Option<&str>,
+        by_topic: &HashMap<String, String>,
+    ) -> Result<Self, DecodingError> {
+        let find = |name: &str| {
+            pool.get_message_by_name(name)
+                .ok_or_else(|| DecodingError::UnknownMessageType(name.to_string()))
+        };
+        let default = default.map(find).transpose()?;
+        let mut by_topic = by_topic
+            .iter()
+            .map(|(topic, name)| Ok((topic.clone(), find(name)?)))
+            .collect::<Result<Vec<_>, DecodingError>>()?;
+        by_topic.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then(a.cmp(b)));
+        Ok(MessageDecoder { default, by_topic })
+    }
+
+    /// Type configured for messages on `topic`, if any.
+    pub fn message_type(&self, topic: Option<&str>) -> Option<&MessageDescriptor> {
+        let by_topic = topic.and_then(|topic| {
+            self.by_topic
+                .iter()
+                .find(|(prefix, _)| topic.starts_with(prefix.as_str()))
+        });
+        match by_topic {
+            Some((_, descriptor)) => Some(descriptor),
+            None => self.default.as_ref(),
+        }
+    }
+
+    /// Decodes the payload of a received message. Returns `None` if no type
+    /// is configured for its topic.
+    pub fn decode(
+        &self,
+        message: &MessageEnvelope,
+    ) -> Result<Option<DynamicMessage>, DecodingError> {
+        let payload = message.frames().last().ok_or(DecodingError::NoPayload)?;
+        self.decode_payload(message.topic().as_deref(), payload)
+    }
+
+    pub fn decode_payload(
+        &self,
+        topic: Option<&str>,
+        payload: &[u8],
+    ) -> Result<Option<DynamicMessage>, DecodingError> {
+        let Some(descriptor) = self.message_type(topic) else {
+            return Ok(None);
+        };
+        DynamicMessage::decode(descriptor.clone(), payload)
+            .map(Some)
+            .map_err(|err| DecodingError::DecodeError {
+                message_type: descriptor.full_name().to_string(),
+                err,
+            })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use prost_reflect::prost::Message;
+    use prost_reflect::prost_types::field_descriptor_proto::{Label, Type};
+    use prost_reflect::prost_types::{
+        DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+    };
+    use prost_reflect::{ReflectMessage, Value};
+    use tempfile::tempdir;
+
+    fn field(name: &str, number: i32, field_type: Type) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(field_type as i32),
+            json_name: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Descriptor set with `market.Quote {symbol, bid}` and `market.Trade {symbol, size}`.
+    pub(crate) fn market_descriptor_set() -> Vec<u8> {
+        let message = |name: &str, fields| DescriptorProto {
+            name: Some(name.to_string()),
+            field: fields,
+            ..Default::default()
+        };
+        FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some("market.proto".to_string()),
+                package: Some("market".to_string()),
+                syntax: Some("proto3".to_string()),
+                message_type: vec![
+                    message(
+                        "Quote",
+                        vec![
+                            field("symbol", 1, Type::String),
+                            field("bid", 2, Type::Double),
+                        ],
+                    ),
+                    message(
+                        "Trade",
+                        vec![
+                            field("symbol", 1, Type::String),
+                            field("size", 2, Type::Int64),
+                        ],
+                    ),
+                ],
+                ..Default::default()
+            }],
+        }
+        .encode_to_vec()
+    }
+
+    pub(crate) fn market_pool() -> DescriptorPool {
+        DescriptorPool::decode(market_descriptor_set().as_slice()).unwrap()
+    }
+
+    pub(crate) fn quote(pool: &DescriptorPool, symbol: &str, bid: f64) -> Vec<u8> {
+        let mut quote = DynamicMessage::new(pool.get_message_by_name("market.Quote").unwrap());
+        quote.set_field_by_name("symbol", Value::String(symbol.to_string()));
+        quote.set_field_by_name("bid", Value::F64(bid));
+        quote.encode_to_vec()
+    }
+
+    #[test]
+    fn test_load_descriptor_sets() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("market.pb");
+        std::fs::write(&path, market_descriptor_set()).unwrap();
+        let pool = load_descriptor_sets(&[&path]).unwrap();
+        assert!(pool.get_message_by_name("market.Trade").is_some());
+
+        std::fs::write(&path, b"not a descriptor set").unwrap();
+        assert!(load_descriptor_sets(&[&path]).is_err());
+    }
+
+    #[test]
+    fn test_decode_by_topic() {
+        let pool = market_pool();
+        let by_topic = HashMap::from([
+            ("md.".to_string(), "market.Trade".to_string()),
+            ("md.quotes".to_string(), "market.Quote".to_string()),
+        ]);
+        let decoder = MessageDecoder::new(&pool, None, &by_topic).unwrap();
+        let message = MessageEnvelope::new(
+            0,
+            "conn".to_string(),
+            Some("md.quotes.EURUSD".to_string()),
+            vec![quote(&pool, "EURUSD", 1.0842)],
+        );
+
+        let decoded = decoder.decode(&message).unwrap().unwrap();
+        assert_eq!(decoded.descriptor().full_name(), "market.Quote");
+        assert_eq!(
+            decoded.get_field_by_name("symbol").unwrap().as_str(),
+            Some("EURUSD")
+        );
+        assert_eq!(
+            decoder.message_type(Some("md.trades")).unwrap().full_name(),
+            "market.Trade"
+        );
+        assert!(decoder.message_type(Some("orders")).is_none());
+        assert!(decoder
+            .decode_payload(Some("orders"), b"anything")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_default_type_and_errors() {
+        let pool = market_pool();
+        let decoder = MessageDecoder::new(&pool, Some("market.Quote"), &HashMap::new()).unwrap();
+        assert!(decoder
+            .decode_payload(None, &quote(&pool, "X", 1.0))
+            .unwrap()
+            .is_some());
+        assert!(matches!(
+            decoder.decode_payload(None, &[0xFF, 0xFF]),
+            Err(DecodingError::DecodeError { .. })
+        ));
+        let empty = MessageEnvelope::new(0, "conn".to_string(), None, vec![]);
+        assert!(matches!(
+            decoder.decode(&empty),
+            Err(DecodingError::NoPayload)
+        ));
+        assert!(matches!(
+            MessageDecoder::new(&pool, Some("market.Missing"), &HashMap::new()),
+            Err(DecodingError::UnknownMessageType(_))
+        ));
+    }
+}
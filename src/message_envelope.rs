@@ -0,0 +1,118 @@
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use getset::Getters;
+
+use crate::connection_health::MONITOR_TOPIC;
+
+/// Anchor for monotonic timestamps so they can be stored as plain integers.
+static MONOTONIC_START: OnceLock<Instant> = OnceLock::new();
+
+fn monotonic_now_ns() -> u64 {
+    MONOTONIC_START
+        .get_or_init(Instant::now)
+        .elapsed()
+        .as_nanos() as u64
+}
+
+fn wall_clock_now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// A received message together with everything known about its arrival.
+#[derive(Debug, Clone, PartialEq, Getters)]
+pub struct MessageEnvelope {
+    /// Per-connection counter, starting at 0 for the first recorded message
+    #[get = "pub"]
+    sequence: u64,
+    /// Wall-clock receive time in nanoseconds since the unix epoch
+    #[get = "pub"]
+    wall_time_ns: u64,
+    /// Monotonic receive time in nanoseconds since the recorder started
+    #[get = "pub"]
+    monotonic_ns: u64,
+    /// Topic frame the message arrived with, if the connection uses topics
+    #[get = "pub"]
+    topic: Option<String>,
+    /// Identity of the connection the message was received on
+    #[get = "pub"]
+    connection: String,
+    #[get = "pub"]
+    frames: Vec<Vec<u8>>,
+}
+
+impl MessageEnvelope {
+    /// Wraps freshly received frames, stamping them with the current time.
+    pub fn new(
+        sequence: u64,
+        connection: String,
+        topic: Option<String>,
+        frames: Vec<Vec<u8>>,
+    ) -> Self {
+        Self {
+            sequence,
+            wall_time_ns: wall_clock_now_ns(),
+            monotonic_ns: monotonic_now_ns(),
+            topic,
+            connection,
+            frames,
+        }
+    }
+
+    /// Rebuilds an envelope whose timestamps were recorded earlier.
+    pub fn with_timestamps(
+        sequence: u64,
+        wall_time_ns: u64,
+        monotonic_ns: u64,
+        connection: String,
+        topic: Option<String>,
+        frames: Vec<Vec<u8>>,
+    ) -> Self {
+        Self {
+            sequence,
+            wall_time_ns,
+            monotonic_ns,
+            topic,
+            connection,
+            frames,
+        }
+    }
+
+    pub fn wall_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(self.wall_time_ns)
+    }
+
+    /// Whether this is a socket monitor event rather than a received message.
+    pub fn is_monitor_event(&self) -> bool {
+        self.topic.as_deref() == Some(MONITOR_TOPIC)
+    }
+
+    /// Total number of payload bytes across all frames.
+    pub fn payload_len(&self) -> usize {
+        self.frames.iter().map(|frame| frame.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_stamps_receive_times() {
+        let before = wall_clock_now_ns();
+        let first = MessageEnvelope::new(0, "conn".to_string(), None, vec![b"a".to_vec()]);
+        let second = MessageEnvelope::new(
+            1,
+            "conn".to_string(),
+            Some("topic".to_string()),
+            vec![b"bc".to_vec(), b"d".to_vec()],
+        );
+        assert!(*first.wall_time_ns() >= before);
+        assert!(second.monotonic_ns() >= first.monotonic_ns());
+        assert_eq!(second.payload_len(), 3);
+        assert_eq!(second.topic(), &Some("topic".to_string()));
+    }
+}
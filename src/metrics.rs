@@ -0,0 +1,493 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, error, info};
+use prometheus::core::{Collector, MetricVec, MetricVecBuilder};
+use prometheus::{
+    Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    Opts, Registry, TextEncoder,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::message_envelope::MessageEnvelope;
+use crate::shutdown::ShutdownSignal;
+
+/// Path the metrics are served on, every other path answers 404.
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Buckets of the flush latency histogram in seconds, from 100µs to 10s.
+const FLUSH_BUCKETS: [f64; 11] = [
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0,
+];
+
+static GLOBAL: OnceLock<RecorderMetrics> = OnceLock::new();
+/// Never registered or served, shared by every `SinkMetrics::default()`.
+static DETACHED: OnceLock<RecorderMetrics> = OnceLock::new();
+
+/// Metrics of every connection and sink in the process.
+pub fn global() -> &'static RecorderMetrics {
+    GLOBAL.get_or_init(RecorderMetrics::new)
+}
+
+fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Per-connection and per-sink counters, rendered in the Prometheus text format.
+#[derive(Debug)]
+pub struct RecorderMetrics {
+    registry: Registry,
+    messages: IntCounterVec,
+    bytes: IntCounterVec,
+    last_message: GaugeVec,
+    last_message_age: GaugeVec,
+    connection_up: GaugeVec,
+    connection_events: IntCounterVec,
+    sink_messages: IntCounterVec,
+    sink_bytes: IntCounterVec,
+    sink_write_errors: IntCounterVec,
+    sink_dropped: IntCounterVec,
+    sink_flush_seconds: HistogramVec,
+    /// Last receive time of every connection, turned into ages when scraped
+    receive_times: Mutex<HashMap<String, Arc<AtomicU64>>>,
+}
+
+impl Default for RecorderMetrics {
+    fn default() -> Self {
+        RecorderMetrics::new()
+    }
+}
+
+impl RecorderMetrics {
+    pub fn new() -> Self {
+        let connection = ["connection"];
+        let sink = ["connection", "sink"];
+        // The names are fixed and unique, so creating and registering cannot fail
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            IntCounterVec::new(Opts::new(name, help), labels).unwrap()
+        };
+        let gauge =
+            |name: &str, help: &str| GaugeVec::new(Opts::new(name, help), &connection).unwrap();
+        let metrics = RecorderMetrics {
+            registry: Registry::new(),
+            messages: counter(
+                "recorder_messages_received_total",
+                "Messages received and passed to the sinks",
+                &connection,
+            ),
+            bytes: counter(
+                "recorder_bytes_received_total",
+                "Payload bytes received and passed to the sinks",
+                &connection,
+            ),
+            last_message: gauge(
+                "recorder_last_message_timestamp_seconds",
+                "Unix time the last message was received",
+            ),
+            last_message_age: gauge(
+                "recorder_last_message_age_seconds",
+                "Seconds since the last message, or since the connection started",
+            ),
+            connection_up: gauge(
+                "recorder_connection_up",
+                "1 while the socket has a connected peer, per its monitor",
+            ),
+            connection_events: counter(
+                "recorder_connection_events_total",
+                "Socket monitor events of the connection",
+                &["connection", "event"],
+            ),
+            sink_messages: counter(
+                "recorder_sink_messages_total",
+                "Messages a sink has written",
+                &sink,
+            ),
+            sink_bytes: counter(
+                "recorder_sink_bytes_total",
+                "Payload bytes a sink has written",
+                &sink,
+            ),
+            sink_write_errors: counter(
+                "recorder_sink_write_errors_total",
+                "Messages a sink failed to write",
+                &sink,
+            ),
+            sink_dropped: counter(
+                "recorder_sink_dropped_messages_total",
+                "Messages dropped because the sink queue was full",
+                &sink,
+            ),
+            sink_flush_seconds: HistogramVec::new(
+                HistogramOpts::new(
+                    "recorder_sink_flush_duration_seconds",
+                    "Time a sink took to flush its file",
+                )
+                .buckets(FLUSH_BUCKETS.to_vec()),
+                &sink,
+            )
+            .unwrap(),
+            receive_times: Mutex::new(HashMap::new()),
+        };
+        for collector in [
+            Box::new(metrics.messages.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(metrics.bytes.clone()),
+            Box::new(metrics.last_message.clone()),
+            Box::new(metrics.last_message_age.clone()),
+            Box::new(metrics.connection_up.clone()),
+            Box::new(metrics.connection_events.clone()),
+            Box::new(metrics.sink_messages.clone()),
+            Box::new(metrics.sink_bytes.clone()),
+            Box::new(metrics.sink_write_errors.clone()),
+            Box::new(metrics.sink_dropped.clone()),
+            Box::new(metrics.sink_flush_seconds.clone()),
+        ] {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+
+    /// Metrics of the connection to `connection`, e.g. `tcp://localhost:5555`.
+    /// Its last-message age counts from now until the first message arrives.
+    /// Connections to the same endpoint share their series.
+    pub fn connection(&self, connection: &str) -> ConnectionMetrics {
+        let last_received = self
+            .receive_times
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(connection.to_string())
+            .or_insert_with(|| Arc::new(AtomicU64::new(now_ns())))
+            .clone();
+        ConnectionMetrics {
+            messages: self.messages.with_label_values(&[connection]),
+            bytes: self.bytes.with_label_values(&[connection]),
+            last_message: self.last_message.with_label_values(&[connection]),
+            last_received,
+            up: self.connection_up.with_label_values(&[connection]),
+            events: self.connection_events.clone(),
+            connection: connection.to_string(),
+        }
+    }
+
+    /// Metrics of the sink named `sink` on `connection`. Asking twice returns
+    /// handles to the same series.
+    pub fn sink(&self, connection: &str, sink: &str) -> SinkMetrics {
+        let labels = [connection, sink];
+        SinkMetrics {
+            messages: self.sink_messages.with_label_values(&labels),
+            bytes: self.sink_bytes.with_label_values(&labels),
+            write_errors: self.sink_write_errors.with_label_values(&labels),
+            dropped: self.sink_dropped.with_label_values(&labels),
+            flush_seconds: self.sink_flush_seconds.with_label_values(&labels),
+        }
+    }
+
+    /// Forgets every series of `connection` and its sinks, once nothing
+    /// receives from it anymore.
+    pub fn remove_connection(&self, connection: &str) {
+        self.receive_times
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(connection);
+        remove_series(&self.messages, connection);
+        remove_series(&self.bytes, connection);
+        remove_series(&self.last_message, connection);
+        remove_series(&self.last_message_age, connection);
+        remove_series(&self.connection_up, connection);
+        remove_series(&self.connection_events, connection);
+        remove_series(&self.sink_messages, connection);
+        remove_series(&self.sink_bytes, connection);
+        remove_series(&self.sink_write_errors, connection);
+        remove_series(&self.sink_dropped, connection);
+        remove_series(&self.sink_flush_seconds, connection);
+    }
+
+    /// Forgets the series of one sink of `connection` once it is closed.
+    pub fn remove_sink(&self, connection: &str, sink: &str) {
+        let labels = [connection, sink];
+        // A sink that never counted anything has no series to remove
+        let _ = self.sink_messages.remove_label_values(&labels);
+        let _ = self.sink_bytes.remove_label_values(&labels);
+        let _ = self.sink_write_errors.remove_label_values(&labels);
+        let _ = self.sink_dropped.remove_label_values(&labels);
+        let _ = self.sink_flush_seconds.remove_label_values(&labels);
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let now = now_ns();
+        for (connection, last_received) in self
+            .receive_times
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            let age_ns = now.saturating_sub(last_received.load(Ordering::Relaxed));
+            self.last_message_age
+                .with_label_values(&[connection])
+                .set(age_ns as f64 / 1e9);
+        }
+        let mut encoded = vec![];
+        // Encoding into a Vec cannot fail
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut encoded)
+            .unwrap();
+        String::from_utf8(encoded).unwrap()
+    }
+}
+
+/// Removes the series of `vec` labelled with `connection`, whatever their
+/// other labels are.
+fn remove_series<P: MetricVecBuilder>(vec: &MetricVec<P>, connection: &str) {
+    for family in vec.collect() {
+        for metric in family.get_metric() {
+            let labels: HashMap<&str, &str> = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name(), label.get_value()))
+                .collect();
+            if labels.get("connection") == Some(&connection) {
+                // The labels were just read from the vec, so they match it
+                let _ = vec.remove(&labels);
+            }
+        }
+    }
+}
+
+/// Counts what a connection receives.
+#[derive(Debug, Clone)]
+pub struct ConnectionMetrics {
+    messages: IntCounter,
+    bytes: IntCounter,
+    last_message: Gauge,
+    last_received: Arc<AtomicU64>,
+    up: Gauge,
+    events: IntCounterVec,
+    connection: String,
+}
+
+impl ConnectionMetrics {
+    /// Counts a socket monitor event by its name, e.g. `disconnected`.
+    pub fn record_event(&self, event: &str, connected: bool) {
+        self.events
+            .with_label_values(&[&self.connection, event])
+            .inc();
+        self.up.set(if connected { 1.0 } else { 0.0 });
+    }
+
+    pub fn record(&self, message: &MessageEnvelope) {
+        self.messages.inc();
+        self.bytes.inc_by(message.payload_len() as u64);
+        self.last_message.set(*message.wall_time_ns() as f64 / 1e9);
+        self.last_received
+            .store(*message.wall_time_ns(), Ordering::Relaxed);
+    }
+}
+
+/// Counts what a sink writes, fails to write and drops.
+#[derive(Debug, Clone)]
+pub struct SinkMetrics {
+    pub messages: IntCounter,
+    pub bytes: IntCounter,
+    pub write_errors: IntCounter,
+    pub dropped: IntCounter,
+    pub flush_seconds: Histogram,
+}
+
+impl Default for SinkMetrics {
+    /// Metrics that are not registered anywhere, for sinks outside a recorder.
+    /// They all share one series.
+    fn default() -> Self {
+        DETACHED.get_or_init(RecorderMetrics::new).sink("", "")
+    }
+}
+
+/// Serves `global()` on `METRICS_PATH` until shutdown.
+pub async fn serve(listener: TcpListener, mut shutdown: ShutdownSignal) {
+    match listener.local_addr() {
+        Ok(addr) => info!("Serving metrics on http://{}{}", addr, METRICS_PATH),
+        Err(e) => error!("Serving metrics on an unknown address: {}", e),
+    }
+    loop {
+        tokio::select! {
+            _ = shutdown.wait() => {
+                info!("Stopped serving metrics");
+                return;
+            }
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = answer(stream, global()).await {
+                            debug!("Failed to answer the metrics request of {}: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => error!("Failed to accept a metrics connection: {}", e),
+            }
+        }
+    }
+}
+
+/// Answers one HTTP request. Only the request line is looked at, which is
+/// all a Prometheus scrape needs.
+async fn answer(mut stream: TcpStream, metrics: &RecorderMetrics) -> std::io::Result<()> {
+    let mut request = vec![0u8; 4096];
+    let mut read = 0;
+    while !request[..read]
+        .windows(4)
+        .any(|window| window == b"\r\n\r\n")
+    {
+        if read == request.len() {
+            break;
+        }
+        match stream.read(&mut request[read..]).await? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    let request = String::from_utf8_lossy(&request[..read]);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let (method, path) = (request_line.next(), request_line.next());
+    let path = path.map(|path| path.split('?').next().unwrap_or_default());
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some(METRICS_PATH)) => (
+            "200 OK",
+            TextEncoder::new().format_type().to_string(),
+            metrics.encode(),
+        ),
+        (Some("GET"), _) => (
+            "404 Not Found",
+            "text/plain".to_string(),
+            "Not found\n".to_string(),
+        ),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain".to_string(),
+            "Only GET is supported\n".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_counts_connection_and_sink() {
+        let metrics = RecorderMetrics::new();
+        let connection = metrics.connection("tcp://localhost:5555");
+        connection.record(&MessageEnvelope::new(
+            0,
+            "tcp://localhost:5555".to_string(),
+            None,
+            vec![vec![0u8; 10]],
+        ));
+        connection.record_event("connected", true);
+        let sink = metrics.sink("tcp://localhost:5555", "File_Sink_1");
+        sink.messages.inc();
+        sink.flush_seconds.observe(0.002);
+        // A second handle shares the series
+        metrics
+            .sink("tcp://localhost:5555", "File_Sink_1")
+            .dropped
+            .inc_by(3);
+
+        let encoded = metrics.encode();
+        assert!(encoded
+            .contains(r#"recorder_messages_received_total{connection="tcp://localhost:5555"} 1"#));
+        assert!(encoded
+            .contains(r#"recorder_bytes_received_total{connection="tcp://localhost:5555"} 10"#));
+        assert!(encoded.contains(
+            r#"recorder_connection_events_total{connection="tcp://localhost:5555",event="connected"} 1"#
+        ));
+        assert!(encoded.contains(r#"recorder_connection_up{connection="tcp://localhost:5555"} 1"#));
+        assert!(encoded.contains(
+            r#"recorder_sink_dropped_messages_total{connection="tcp://localhost:5555",sink="File_Sink_1"} 3"#
+        ));
+        assert!(encoded.contains(
+            r#"recorder_sink_flush_duration_seconds_count{connection="tcp://localhost:5555",sink="File_Sink_1"} 1"#
+        ));
+        assert!(encoded
+            .contains("recorder_last_message_age_seconds{connection=\"tcp://localhost:5555\"}"));
+    }
+
+    #[test]
+    fn test_remove_connection_forgets_its_series() {
+        let metrics = RecorderMetrics::new();
+        let connection = metrics.connection("tcp://localhost:5555");
+        connection.record_event("connected", true);
+        metrics.sink("tcp://localhost:5555", "file").messages.inc();
+        let other = metrics.connection("tcp://localhost:5556");
+        other.record_event("connected", true);
+        metrics.sink("tcp://localhost:5556", "file").messages.inc();
+        // The same endpoint again shares the receive time instead of adding one
+        metrics.connection("tcp://localhost:5555");
+        assert_eq!(metrics.receive_times.lock().unwrap().len(), 2);
+
+        metrics.remove_connection("tcp://localhost:5555");
+        let encoded = metrics.encode();
+        assert!(!encoded.contains("tcp://localhost:5555"), "{}", encoded);
+        assert!(encoded.contains(
+            r#"recorder_connection_events_total{connection="tcp://localhost:5556",event="connected"} 1"#
+        ));
+        assert!(encoded.contains(
+            r#"recorder_sink_messages_total{connection="tcp://localhost:5556",sink="file"} 1"#
+        ));
+        assert!(encoded
+            .contains("recorder_last_message_age_seconds{connection=\"tcp://localhost:5556\"}"));
+    }
+
+    #[test]
+    fn test_remove_sink_keeps_other_sinks() {
+        let metrics = RecorderMetrics::new();
+        metrics.sink("conn", "file").messages.inc();
+        metrics.sink("conn", "counter").messages.inc();
+
+        metrics.remove_sink("conn", "file");
+        let encoded = metrics.encode();
+        assert!(!encoded.contains(r#"sink="file""#), "{}", encoded);
+        assert!(
+            encoded.contains(r#"recorder_sink_messages_total{connection="conn",sink="counter"} 1"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serves_metrics_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = RecorderMetrics::new();
+        metrics.sink("conn", "http_test").messages.inc();
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            answer(stream, &metrics).await.unwrap();
+        };
+        let client = async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let ((), response) = tokio::join!(server, client);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response
+            .contains(r#"recorder_sink_messages_total{connection="conn",sink="http_test"} 1"#));
+    }
+}
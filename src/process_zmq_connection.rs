@@ -1,87 +1,174 @@
+use crate::capture_socket::{self, CaptureSocket};
+use crate::connection_health::{self, ConnectionState, MonitorEvent};
+use crate::message_envelope::MessageEnvelope;
+use crate::shutdown::ShutdownSignal;
 use crate::zmq_connection::{MessageRecorderError, ZmqConnection};
 use futures::TryStreamExt;
-use log::{debug, error, info};
-use tmq::{subscribe, Context};
+use log::{debug, error, info, warn};
+use tmq::{Context, Multipart};
+use tokio::time::{sleep_until, Instant};
 
 pub async fn process_zmq_connection(
     connection: &ZmqConnection,
+    context: &Context,
+    mut shutdown: ShutdownSignal,
 ) -> Result<(), MessageRecorderError> {
-    // Build the connection string
     let host = connection.get_host();
-    let socket;
+    let topic_filter = connection.get_topic_filter();
+    let socket_config = connection.get_socket_config();
 
-    // Set up topic subscription
-    let topic = match connection.get_topic() {
-        None => {
+    let CaptureSocket {
+        receiver: mut socket,
+        mut monitor,
+        options,
+    } = capture_socket::open(
+        context,
+        connection.get_endpoint(),
+        socket_config,
+        topic_filter,
+    )?;
+    info!(
+        "Receiving on {} with a {} socket ({})",
+        host,
+        socket_config.socket_type.name(),
+        socket_config.mode.name()
+    );
+    info!("Socket options on {}: {}", host, options);
+    if let Some(security) = &socket_config.security {
+        info!("Using {} security on {}", security.mechanism(), host);
+    }
+    // Other socket types have no topic frame, every frame is payload
+    if socket_config.socket_type.has_topics() {
+        if topic_filter.is_empty() {
             info!("Subscribed to NO TOPIC on {}", host);
-            socket = match subscribe(&Context::new()).connect(&host) {
-                Ok(res) => Ok(res),
-                Err(err) => Err(MessageRecorderError::TmqError(err)),
-            }?;
-            ""
+        } else {
+            info!(
+                "Subscribed to topics {:?} ({:?} match) on {}",
+                topic_filter.topics(),
+                topic_filter.mode(),
+                host
+            );
         }
-        Some(topic) => {
-            info!("Subscribed to topic {} on {}", topic, host);
-            socket = match subscribe(&Context::new()).connect(&host) {
-                Ok(res) => Ok(res),
-                Err(err) => Err(MessageRecorderError::TmqError(err)),
-            }?;
-            topic
-        }
-    };
-    let mut subscribe = match socket.subscribe(topic.as_bytes()) {
-        Ok(res) => Ok(res),
-        Err(err) => Err(MessageRecorderError::TmqError(err)),
-    }?;
+    }
+    let mut sequence: u64 = 0;
+    let mut monitoring = true;
+    let idle_timeout = socket_config.options.receive_timeout;
+    let mut idle_deadline = idle_timeout.map(|timeout| Instant::now() + timeout);
 
     loop {
-        match subscribe.try_next().await {
-            Ok(possible_message) => {
-                debug!("Recieved {:?}", possible_message);
-                match possible_message {
-                    Some(message) => {
-                        // tmq messages are Vec<Vec<u8>>, where the first frame is the topic and others are message parts
-                        if let Some(received_topic) = message
-                            .0
-                            .get(0)
-                            .and_then(|frame| std::str::from_utf8(frame).ok())
-                        {
-                            // Check topic matching if a topic is specified
-                            if let Some(expected_topic) = connection.get_topic() {
-                                if received_topic != expected_topic {
-                                    info!(
-                                        "Received unexpected topic: {}. Expected: {}",
-                                        received_topic, expected_topic
-                                    );
-                                    continue; // Skip if topic doesn't match
-                                }
+        tokio::select! {
+            _ = shutdown.wait() => {
+                info!("Stopped receiving on {}", host);
+                return Ok(());
+            }
+            _ = sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                if let Some(timeout) = idle_timeout {
+                    warn!(
+                        "No message received on {} for {}",
+                        host,
+                        humantime::format_duration(timeout)
+                    );
+                    idle_deadline = Some(Instant::now() + timeout);
+                }
+            }
+            received = socket.try_next() => {
+                if let (Ok(Some(_)), Some(timeout)) = (&received, idle_timeout) {
+                    idle_deadline = Some(Instant::now() + timeout);
+                }
+                match received {
+                    Ok(Some(_)) if connection.is_paused() => {
+                        debug!("Discarded a message received on paused {}", host);
+                    }
+                    Ok(Some(message)) => {
+                        debug!("Recieved {:?}", message);
+                        let envelope = build_envelope(connection, &host, sequence, message);
+                        if let Some(envelope) = envelope {
+                            sequence += 1;
+                            // Pass data to connection sinks
+                            if let Err(e) = connection.use_sinks(envelope).await {
+                                error!("Failed to use sinks with error {} from {}", e, &connection);
                             }
                         }
-
-                        // Collect message data (excluding the first frame if it's the topic)
-                        let data: Vec<u8> = match connection.get_topic() {
-                            Some(_) => message
-                                .iter()
-                                .skip(1)
-                                .flat_map(|frame| frame.iter())
-                                .copied()
-                                .collect(),
-                            None => message
-                                .iter()
-                                .flat_map(|frame| frame.iter())
-                                .copied()
-                                .collect(),
-                        };
-
-                        // Pass data to connection sinks
-                        if let Err(e) = connection.use_sinks(&data) {
-                            error!("Failed to use sinks with error {} from {}", e, &connection);
-                        }
                     }
-                    None => error!("Failed to recieve any data from {}", &connection),
+                    Ok(None) => error!("Failed to recieve any data from {}", &connection),
+                    Err(e) => error!("Error receiving message: {} from {}", e, &connection),
+                }
+            }
+            event = monitor.try_next(), if monitoring => match event {
+                Ok(Some(message)) => handle_monitor_event(connection, &host, message).await,
+                Ok(None) | Err(_) => {
+                    debug!("Monitor of {} ended", host);
+                    monitoring = false;
                 }
             }
-            Err(e) => error!("Error receiving message: {} from {}", e, &connection),
         }
     }
 }
+
+/// Updates the connection's health from a monitor event, logs state changes
+/// and hands the event to the sinks recording events.
+async fn handle_monitor_event(connection: &ZmqConnection, host: &str, message: Multipart) {
+    let frames: Vec<&[u8]> = message.iter().map(|frame| &frame[..]).collect();
+    let event = match MonitorEvent::parse(&frames, connection_health::now_ns()) {
+        Some(event) => event,
+        None => {
+            debug!("Ignored a malformed monitor message from {}", host);
+            return;
+        }
+    };
+    let mode = connection.get_socket_config().mode;
+    match connection.get_health().record(&event, mode) {
+        Some(state @ (ConnectionState::Disconnected | ConnectionState::Failed)) => {
+            warn!("{} is {} after {}", host, state.name(), event)
+        }
+        Some(state) => info!("{} is {} after {}", host, state.name(), event),
+        None if event.kind.is_failure() => warn!("{} on {}", event, host),
+        None => debug!("{} on {}", event, host),
+    }
+    connection.get_metrics().record_event(
+        event.kind.name(),
+        connection.get_health().state() == ConnectionState::Connected,
+    );
+    if let Err(e) = connection.record_event(&event).await {
+        error!("Failed to record monitor event from {}: {}", host, e);
+    }
+}
+
+/// Wraps a received message for the sinks, or returns None if its topic is filtered out.
+fn build_envelope(
+    connection: &ZmqConnection,
+    host: &str,
+    sequence: u64,
+    message: Multipart,
+) -> Option<MessageEnvelope> {
+    let topic_filter = connection.get_topic_filter();
+    if topic_filter.is_empty() {
+        let frames = message.iter().map(|frame| frame.to_vec()).collect();
+        return Some(MessageEnvelope::new(
+            sequence,
+            host.to_string(),
+            None,
+            frames,
+        ));
+    }
+
+    // tmq messages are Vec<Vec<u8>>, where the first frame is the topic and others are message parts
+    let topic_frame = message.0.front().map(|frame| &frame[..]);
+    // Check the topic against the configured list
+    if !topic_filter.matches(topic_frame.unwrap_or_default()) {
+        debug!(
+            "Received unexpected topic: {:?}. Expected: {:?}",
+            topic_frame.map(String::from_utf8_lossy),
+            topic_filter.topics()
+        );
+        return None; // Skip if topic doesn't match
+    }
+    let received_topic = topic_frame.map(|frame| String::from_utf8_lossy(frame).into_owned());
+    let frames = message.iter().skip(1).map(|frame| frame.to_vec()).collect();
+    Some(MessageEnvelope::new(
+        sequence,
+        host.to_string(),
+        received_topic,
+        frames,
+    ))
+}
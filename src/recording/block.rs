@@ -0,0 +1,228 @@
+use byteorder::{BigEndian, ByteOrder};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use crate::recording::framing::RECORD_LENGTH_SIZE;
+
+/// Most uncompressed bytes one block may hold, body lengths included.
+/// `decode_block` refuses blocks that decompress to more.
+pub const MAX_BLOCK_INPUT: u64 = 64 * 1024 * 1024;
+
+/// Compresses record bodies into one deflate stream per file, cut into
+/// blocks. Every block ends with a full flush, which resets the compressor,
+/// so each block can be decompressed on its own and the blocks stay
+/// indexable. Inside a block every body is prefixed with its u64 length.
+#[derive(Debug)]
+pub struct BlockEncoder {
+    compress: Compress,
+    block: Vec<u8>,
+    records: u64,
+    input_bytes: u64,
+}
+
+impl BlockEncoder {
+    pub fn new(level: u32) -> Self {
+        BlockEncoder {
+            compress: Compress::new(Compression::new(level), false),
+            block: vec![],
+            records: 0,
+            input_bytes: 0,
+        }
+    }
+
+    /// Records added since the last block was finished.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Uncompressed bytes added since the last block was finished.
+    pub fn input_bytes(&self) -> u64 {
+        self.input_bytes
+    }
+
+    /// Whether a body of `body_len` bytes still fits in the current block.
+    pub fn fits(&self, body_len: usize) -> bool {
+        self.input_bytes + RECORD_LENGTH_SIZE + body_len as u64 <= MAX_BLOCK_INPUT
+    }
+
+    /// Adds a body to the current block. Fails if it does not fit, see `fits`.
+    pub fn push(&mut self, body: &[u8]) -> std::io::Result<()> {
+        if !self.fits(body.len()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "A body of {} bytes does not fit in a block of at most {} bytes",
+                    body.len(),
+                    MAX_BLOCK_INPUT
+                ),
+            ));
+        }
+        let mut length = [0u8; RECORD_LENGTH_SIZE as usize];
+        BigEndian::write_u64(&mut length, body.len() as u64);
+        self.compress_into_block(&length, FlushCompress::None)?;
+        self.compress_into_block(body, FlushCompress::None)?;
+        self.records += 1;
+        self.input_bytes += RECORD_LENGTH_SIZE + body.len() as u64;
+        Ok(())
+    }
+
+    /// Ends the current block. Returns the compressed block and the number
+    /// of records in it, or `None` if nothing was added since the last one.
+    pub fn finish_block(&mut self) -> std::io::Result<Option<(Vec<u8>, u64)>> {
+        if self.records == 0 {
+            return Ok(None);
+        }
+        self.compress_into_block(&[], FlushCompress::Full)?;
+        let records = self.records;
+        self.records = 0;
+        self.input_bytes = 0;
+        Ok(Some((std::mem::take(&mut self.block), records)))
+    }
+
+    fn compress_into_block(
+        &mut self,
+        mut input: &[u8],
+        flush: FlushCompress,
+    ) -> std::io::Result<()> {
+        loop {
+            self.block.reserve(input.len() / 2 + 64);
+            let before = self.compress.total_in();
+            self.compress
+                .compress_vec(input, &mut self.block, flush)
+                .map_err(std::io::Error::other)?;
+            input = &input[(self.compress.total_in() - before) as usize..];
+            // Output stopping short of the capacity means nothing is held back
+            if input.is_empty() && self.block.len() < self.block.capacity() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Decompresses a block written by `BlockEncoder` and splits it back into
+/// the record bodies it holds. Fails once the output grows past
+/// `MAX_BLOCK_INPUT`, which no valid block does.
+pub fn decode_block(block: &[u8]) -> std::io::Result<Vec<Vec<u8>>> {
+    // One byte over the limit shows that the block decompresses to too much
+    let limit = MAX_BLOCK_INPUT as usize + 1;
+    let mut decompress = Decompress::new(false);
+    let mut data = Vec::with_capacity(block.len().saturating_mul(4).min(limit));
+    let mut input = block;
+    loop {
+        // Grows like `reserve` would, but never past the limit
+        let additional = data.capacity().max(block.len() + 64);
+        data.reserve_exact(additional.min(limit - data.len()));
+        let before = decompress.total_in();
+        let status = decompress
+            .decompress_vec(input, &mut data, FlushDecompress::Sync)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        input = &input[(decompress.total_in() - before) as usize..];
+        if data.len() as u64 > MAX_BLOCK_INPUT {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Block decompresses to more than {} bytes", MAX_BLOCK_INPUT),
+            ));
+        }
+        if status == Status::StreamEnd || (input.is_empty() && data.len() < data.capacity()) {
+            break;
+        }
+    }
+
+    let mut bodies = vec![];
+    let mut rest = data.as_slice();
+    while !rest.is_empty() {
+        if rest.len() < RECORD_LENGTH_SIZE as usize {
+            return Err(incomplete_block(rest.len()));
+        }
+        let body_len = BigEndian::read_u64(&rest[..RECORD_LENGTH_SIZE as usize]);
+        rest = &rest[RECORD_LENGTH_SIZE as usize..];
+        if (rest.len() as u64) < body_len {
+            return Err(incomplete_block(rest.len()));
+        }
+        let (body, remaining) = rest.split_at(body_len as usize);
+        bodies.push(body.to_vec());
+        rest = remaining;
+    }
+    Ok(bodies)
+}
+
+fn incomplete_block(remaining: usize) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Block ends inside a record, {} bytes left over", remaining),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks_decode_independently() {
+        let mut encoder = BlockEncoder::new(6);
+        encoder.push(b"first").unwrap();
+        encoder.push(b"").unwrap();
+        assert_eq!(encoder.records(), 2);
+        let (first, records) = encoder.finish_block().unwrap().unwrap();
+        assert_eq!(records, 2);
+
+        encoder.push(b"first").unwrap();
+        let (second, records) = encoder.finish_block().unwrap().unwrap();
+        assert_eq!(records, 1);
+        assert!(encoder.finish_block().unwrap().is_none());
+
+        assert_eq!(
+            decode_block(&first).unwrap(),
+            vec![b"first".to_vec(), vec![]]
+        );
+        // The second block does not depend on the dictionary of the first
+        assert_eq!(decode_block(&second).unwrap(), vec![b"first".to_vec()]);
+    }
+
+    #[test]
+    fn test_stream_compresses_small_repetitive_records() {
+        let mut encoder = BlockEncoder::new(6);
+        let body = b"md.prices|EURUSD|1.0842|1.0843|".to_vec();
+        for _ in 0..1000 {
+            encoder.push(&body).unwrap();
+        }
+        let (block, _) = encoder.finish_block().unwrap().unwrap();
+        assert!(block.len() < 1000 * body.len() / 10);
+        assert_eq!(decode_block(&block).unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn test_large_record() {
+        let body: Vec<u8> = (0..1_000_000u64).map(|i| (i * 7919 % 251) as u8).collect();
+        let mut encoder = BlockEncoder::new(1);
+        encoder.push(&body).unwrap();
+        let (block, _) = encoder.finish_block().unwrap().unwrap();
+        assert_eq!(decode_block(&block).unwrap(), vec![body]);
+    }
+
+    #[test]
+    fn test_truncated_block() {
+        let body: Vec<u8> = (0..4096u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let mut encoder = BlockEncoder::new(6);
+        encoder.push(&body).unwrap();
+        let (block, _) = encoder.finish_block().unwrap().unwrap();
+        assert!(decode_block(&block[..block.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn test_block_size_is_limited() {
+        let mut encoder = BlockEncoder::new(1);
+        encoder.push(b"first").unwrap();
+        let body = vec![0u8; MAX_BLOCK_INPUT as usize - 8];
+        assert!(!encoder.fits(body.len()));
+        assert!(encoder.push(&body).is_err());
+        encoder.finish_block().unwrap();
+        assert!(encoder.fits(body.len()));
+
+        // Written around the encoder, as a damaged or forged block would be
+        let mut deflate = flate2::write::DeflateEncoder::new(vec![], Compression::fast());
+        std::io::Write::write_all(&mut deflate, &vec![0u8; 2 * MAX_BLOCK_INPUT as usize]).unwrap();
+        let block = deflate.finish().unwrap();
+        let err = decode_block(&block).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,59 @@
+/// Reflected CRC-32C (Castagnoli) polynomial.
+const POLYNOMIAL: u32 = 0x82F6_3B78;
+
+const TABLE: [u32; 256] = build_table();
+
+const fn build_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+/// CRC-32C of `data`, the checksum used for every record.
+pub fn checksum(data: &[u8]) -> u32 {
+    checksum_parts(&[data])
+}
+
+/// CRC-32C of the parts one after another, without joining them first.
+pub fn checksum_parts(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        crc = TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum_known_values() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"123456789"), 0xE306_9283);
+        assert_eq!(checksum(&[0u8; 32]), 0x8A91_36AA);
+        assert_eq!(checksum_parts(&[b"1234", b"", b"56789"]), 0xE306_9283);
+    }
+
+    #[test]
+    fn test_checksum_detects_bit_flip() {
+        let data = b"recorded payload".to_vec();
+        let mut flipped = data.clone();
+        flipped[3] ^= 0x10;
+        assert_ne!(checksum(&data), checksum(&flipped));
+    }
+}
@@ -0,0 +1,93 @@
+use std::path::PathBuf;
+
+use log::{info, warn};
+
+use crate::recording::reader::{ReaderError, RecordingReader};
+use crate::recording::record;
+
+/// Size zstd picks for a dictionary when asked for the default, 110 KiB.
+pub const DEFAULT_DICTIONARY_SIZE: usize = 112_640;
+/// Samples beyond this multiple of the dictionary size add little but time.
+const SAMPLE_BYTES_PER_DICTIONARY_BYTE: usize = 100;
+
+/// Trains a zstd dictionary on the messages of existing recordings. The
+/// samples are the record bodies as `CompressedFileSink` compresses them, so
+/// the dictionary also learns the repeated topics and connection names.
+pub fn train_dictionary(recordings: &[PathBuf], max_size: usize) -> Result<Vec<u8>, ReaderError> {
+    let sample_limit = max_size * SAMPLE_BYTES_PER_DICTIONARY_BYTE;
+    let mut samples = vec![];
+    let mut sample_bytes = 0;
+    'files: for path in recordings {
+        for message in RecordingReader::open(path)? {
+            let message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    warn!("Skipping damaged record in {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let body = record::encode_envelope(&message);
+            sample_bytes += body.len();
+            samples.push(body);
+            if sample_bytes >= sample_limit {
+                break 'files;
+            }
+        }
+    }
+    info!(
+        "Training a dictionary of up to {} bytes on {} messages with {} bytes",
+        max_size,
+        samples.len(),
+        sample_bytes
+    );
+    Ok(zstd::dict::from_samples(&samples, max_size)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_envelope::MessageEnvelope;
+    use crate::sink::Sink;
+    use crate::sinks::file_sink::FileSink;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_dictionary_shrinks_small_messages() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("training.rec");
+        let mut sink = FileSink::new(path.to_str().unwrap().to_string(), 0).unwrap();
+        let messages: Vec<_> = (0..2000u64)
+            .map(|sequence| {
+                MessageEnvelope::new(
+                    sequence,
+                    "tcp://marketdata:5555".to_string(),
+                    Some(format!(
+                        "md.prices.{}",
+                        ["EURUSD", "GBPUSD", "USDJPY"][sequence as usize % 3]
+                    )),
+                    vec![format!(
+                        "{{\"bid\":1.08{:02},\"ask\":1.08{:02},\"venue\":\"primary\"}}",
+                        sequence % 97,
+                        sequence % 89
+                    )
+                    .into_bytes()],
+                )
+            })
+            .collect();
+        for message in &messages {
+            sink.write(message).unwrap();
+        }
+        sink.flush().unwrap();
+
+        let dictionary = train_dictionary(&[path], 4096).unwrap();
+        assert!(!dictionary.is_empty() && dictionary.len() <= 4096);
+
+        let body = record::encode_envelope(&messages[7]);
+        let plain = zstd::bulk::compress(&body, 3).unwrap();
+        let with_dictionary = zstd::bulk::Compressor::with_dictionary(3, &dictionary)
+            .unwrap()
+            .compress(&body)
+            .unwrap();
+        assert!(with_dictionary.len() < plain.len() / 2);
+    }
+}
@@ -0,0 +1,430 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder};
+use log::{info, warn};
+
+use crate::recording::crc32c;
+use crate::recording::header::FileHeader;
+
+/// Size of the big-endian u64 length in front of every record body.
+pub const RECORD_LENGTH_SIZE: u64 = 8;
+/// Size of the big-endian u32 CRC-32C of the record length and body.
+pub const CHECKSUM_SIZE: u64 = 4;
+/// Size of everything written in front of a record body.
+pub const RECORD_PREFIX_SIZE: u64 = RECORD_LENGTH_SIZE + CHECKSUM_SIZE;
+/// Bytes read at a time while looking for the next valid record.
+const RESYNC_WINDOW: usize = 64 * 1024;
+
+/// Checksum of a record. It covers the length as well as the body, so a
+/// damaged length is noticed instead of misplacing every following record.
+pub fn record_checksum(body_len: u64, body: &[u8]) -> u32 {
+    crc32c::checksum_parts(&[&body_len.to_be_bytes(), body])
+}
+
+/// Encodes the length and checksum that go in front of `body`.
+pub fn encode_prefix(body: &[u8]) -> [u8; RECORD_PREFIX_SIZE as usize] {
+    let mut prefix = [0u8; RECORD_PREFIX_SIZE as usize];
+    BigEndian::write_u64(
+        &mut prefix[..RECORD_LENGTH_SIZE as usize],
+        body.len() as u64,
+    );
+    BigEndian::write_u32(
+        &mut prefix[RECORD_LENGTH_SIZE as usize..],
+        record_checksum(body.len() as u64, body),
+    );
+    prefix
+}
+
+/// Splits a prefix into the body length and the checksum. Neither can be
+/// trusted before the checksum matched.
+pub fn decode_prefix(prefix: &[u8]) -> (u64, u32) {
+    (
+        BigEndian::read_u64(&prefix[..RECORD_LENGTH_SIZE as usize]),
+        BigEndian::read_u32(&prefix[RECORD_LENGTH_SIZE as usize..RECORD_PREFIX_SIZE as usize]),
+    )
+}
+
+/// Finds the first record at or after `from` whose checksum matches, by
+/// trying every offset up to `end`. This is how reading continues after a
+/// damaged record, whose length cannot be trusted.
+pub fn resync<R: Read + Seek>(reader: &mut R, from: u64, end: u64) -> std::io::Result<Option<u64>> {
+    let prefix_size = RECORD_PREFIX_SIZE as usize;
+    let mut window = vec![];
+    let mut body = vec![];
+    let mut window_start = from;
+    while window_start.saturating_add(RECORD_PREFIX_SIZE) <= end {
+        reader.seek(SeekFrom::Start(window_start))?;
+        window.clear();
+        // Overlaps the next window by a prefix, so no offset is missed
+        reader
+            .take((RESYNC_WINDOW + prefix_size) as u64)
+            .read_to_end(&mut window)?;
+        let candidates = window
+            .len()
+            .saturating_sub(prefix_size - 1)
+            .min(RESYNC_WINDOW);
+        for start in 0..candidates {
+            let offset = window_start + start as u64;
+            let (body_len, checksum) = decode_prefix(&window[start..start + prefix_size]);
+            match (offset + RECORD_PREFIX_SIZE).checked_add(body_len) {
+                Some(record_end) if record_end <= end => {}
+                _ => continue,
+            }
+            let body_start = start + prefix_size;
+            let matches = match window.get(body_start..body_start + body_len as usize) {
+                Some(body) => record_checksum(body_len, body) == checksum,
+                None => {
+                    reader.seek(SeekFrom::Start(offset + RECORD_PREFIX_SIZE))?;
+                    body.resize(body_len as usize, 0);
+                    reader.read_exact(&mut body)?;
+                    record_checksum(body_len, &body) == checksum
+                }
+            };
+            if matches {
+                return Ok(Some(offset));
+            }
+        }
+        window_start += RESYNC_WINDOW as u64;
+    }
+    Ok(None)
+}
+
+/// A record that failed the scan, identified by the offset of its length.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordProblem {
+    /// The record does not match its checksum. `skipped` bytes up to the
+    /// next valid record, or the end of the file, were passed over.
+    Corrupted { offset: u64, skipped: u64 },
+    /// The file ends inside this record, nothing after it can be read
+    Truncated { offset: u64 },
+}
+
+impl std::fmt::Display for RecordProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RecordProblem::Corrupted { offset, skipped } => write!(
+                f,
+                "record at offset {} does not match its checksum, skipped {} bytes",
+                offset, skipped
+            ),
+            RecordProblem::Truncated { offset } => {
+                write!(f, "record at offset {} is incomplete", offset)
+            }
+        }
+    }
+}
+
+/// Result of scanning the records of a file.
+#[derive(Debug, Default, PartialEq)]
+pub struct ScanReport {
+    /// Records whose checksum matched
+    pub valid_records: u64,
+    /// Offset right after the last valid record
+    pub valid_end: u64,
+    pub file_len: u64,
+    pub problems: Vec<RecordProblem>,
+}
+
+impl ScanReport {
+    /// True if the file ends with a valid record, so new records can follow it.
+    pub fn tail_is_valid(&self) -> bool {
+        self.valid_end == self.file_len
+    }
+}
+
+/// What was found at the offset of a record.
+enum Framed {
+    Valid {
+        record_end: u64,
+    },
+    Corrupted,
+    /// Runs past the end of the file, or its length is damaged
+    Incomplete,
+}
+
+/// Reads the record starting at the reader's position, `offset`.
+fn read_record<R: Read>(
+    reader: &mut R,
+    offset: u64,
+    end: u64,
+    body: &mut Vec<u8>,
+) -> std::io::Result<Framed> {
+    if end - offset < RECORD_PREFIX_SIZE {
+        return Ok(Framed::Incomplete);
+    }
+    let mut prefix = [0u8; RECORD_PREFIX_SIZE as usize];
+    reader.read_exact(&mut prefix)?;
+    let (body_len, checksum) = decode_prefix(&prefix);
+    let record_end = match (offset + RECORD_PREFIX_SIZE).checked_add(body_len) {
+        Some(record_end) if record_end <= end => record_end,
+        _ => return Ok(Framed::Incomplete),
+    };
+    // Bounded by the file length above
+    body.resize(body_len as usize, 0);
+    reader.read_exact(body)?;
+    match record_checksum(body_len, body) == checksum {
+        true => Ok(Framed::Valid { record_end }),
+        false => Ok(Framed::Corrupted),
+    }
+}
+
+/// Checks the length and checksum of every record from `start`, the offset
+/// of the first record, to the end of the file. After a damaged record the
+/// scan continues at the next record whose checksum matches.
+pub fn scan(path: &Path, start: u64) -> std::io::Result<ScanReport> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(start))?;
+
+    let mut report = ScanReport {
+        valid_end: start,
+        file_len,
+        ..Default::default()
+    };
+    let mut offset = start;
+    let mut body = vec![];
+    while offset < file_len {
+        let framed = read_record(&mut reader, offset, file_len, &mut body)?;
+        if let Framed::Valid { record_end } = framed {
+            report.valid_records += 1;
+            report.valid_end = record_end;
+            offset = record_end;
+            continue;
+        }
+        match (resync(&mut reader, offset + 1, file_len)?, framed) {
+            (Some(next), _) => {
+                report.problems.push(RecordProblem::Corrupted {
+                    offset,
+                    skipped: next - offset,
+                });
+                reader.seek(SeekFrom::Start(next))?;
+                offset = next;
+            }
+            (None, Framed::Corrupted) => {
+                report.problems.push(RecordProblem::Corrupted {
+                    offset,
+                    skipped: file_len - offset,
+                });
+                break;
+            }
+            (None, _) => {
+                report.problems.push(RecordProblem::Truncated { offset });
+                break;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Scans a recording and logs every damaged record. With `truncate` the file
+/// is cut back to the end of its last valid record, so a sink can append to
+/// it again. Damaged records followed by valid ones are only reported, the
+/// valid ones are never cut off.
+pub fn recover(path: &Path, truncate: bool) -> std::io::Result<ScanReport> {
+    let (_, records_start) = FileHeader::read_from(&mut BufReader::new(File::open(path)?))?;
+    let report = scan(path, records_start)?;
+    for problem in &report.problems {
+        warn!("{}: {}", path.display(), problem);
+    }
+    info!(
+        "{} holds {} valid records, the last one ends at offset {} of {}",
+        path.display(),
+        report.valid_records,
+        report.valid_end,
+        report.file_len
+    );
+    if truncate && !report.tail_is_valid() {
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(report.valid_end)?;
+        info!(
+            "Truncated {} from {} to {} bytes",
+            path.display(),
+            report.file_len,
+            report.valid_end
+        );
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::header::Codec;
+    use tempfile::tempdir;
+
+    fn framed(bodies: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![];
+        for body in bodies {
+            data.extend_from_slice(&encode_prefix(body));
+            data.extend_from_slice(body);
+        }
+        data
+    }
+
+    fn recording(bodies: &[&[u8]]) -> Vec<u8> {
+        let mut data = FileHeader::new(Codec::Uncompressed, "conn".to_string(), vec![]).encode();
+        data.extend_from_slice(&framed(bodies));
+        data
+    }
+
+    #[test]
+    fn test_scan_complete_file() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("complete.rec");
+        let data = framed(&[b"first", b"", b"third"]);
+        std::fs::write(&path, &data).unwrap();
+        let report = scan(&path, 0).unwrap();
+        assert_eq!(report.valid_records, 3);
+        assert!(report.problems.is_empty());
+        assert!(report.tail_is_valid());
+    }
+
+    #[test]
+    fn test_scan_empty_file() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("empty.rec");
+        std::fs::write(&path, b"").unwrap();
+        assert_eq!(scan(&path, 0).unwrap(), ScanReport::default());
+    }
+
+    #[test]
+    fn test_scan_truncated_body() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("truncated.rec");
+        let data = framed(&[b"first", b"second"]);
+        std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+        let report = scan(&path, 0).unwrap();
+        assert_eq!(report.valid_records, 1);
+        assert_eq!(report.valid_end, 17);
+        assert_eq!(
+            report.problems,
+            vec![RecordProblem::Truncated { offset: 17 }]
+        );
+        assert!(!report.tail_is_valid());
+    }
+
+    #[test]
+    fn test_scan_truncated_prefix() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("truncated.rec");
+        let data = framed(&[b"first"]);
+        std::fs::write(&path, [&data[..], &[0u8, 0, 0]].concat()).unwrap();
+        let report = scan(&path, 0).unwrap();
+        assert_eq!(
+            report.problems,
+            vec![RecordProblem::Truncated { offset: 17 }]
+        );
+    }
+
+    #[test]
+    fn test_scan_reports_corrupted_record_and_continues() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("corrupted.rec");
+        let mut data = framed(&[b"first", b"second", b"third"]);
+        // Flip a bit inside the body of the second record
+        data[17 + 12] ^= 0x01;
+        std::fs::write(&path, &data).unwrap();
+        let report = scan(&path, 0).unwrap();
+        assert_eq!(report.valid_records, 2);
+        assert_eq!(
+            report.problems,
+            vec![RecordProblem::Corrupted {
+                offset: 17,
+                skipped: 18
+            }]
+        );
+        assert!(report.tail_is_valid());
+    }
+
+    #[test]
+    fn test_scan_resyncs_after_corrupted_length() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("corrupted.rec");
+        let mut data = framed(&[b"first", b"second", b"third", b"fourth"]);
+        // A longer length would otherwise swallow the third record
+        data[17 + 7] = 30;
+        std::fs::write(&path, &data).unwrap();
+        let report = scan(&path, 0).unwrap();
+        assert_eq!(report.valid_records, 3);
+        assert_eq!(
+            report.problems,
+            vec![RecordProblem::Corrupted {
+                offset: 17,
+                skipped: 18
+            }]
+        );
+        assert!(report.tail_is_valid());
+    }
+
+    #[test]
+    fn test_scan_rejects_huge_length() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("huge.rec");
+        let mut data = framed(&[b"first", b"second"]);
+        data[17..25].copy_from_slice(&(u64::MAX - 4).to_be_bytes());
+        std::fs::write(&path, &data).unwrap();
+        let report = scan(&path, 0).unwrap();
+        assert_eq!(report.valid_records, 1);
+        assert_eq!(
+            report.problems,
+            vec![RecordProblem::Truncated { offset: 17 }]
+        );
+    }
+
+    #[test]
+    fn test_recover_truncates_to_last_valid_record() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("recover.rec");
+        let complete = recording(&[b"first", b"second"]);
+        let mut data = complete.clone();
+        data.extend_from_slice(&framed(&[b"lost in a power cut"])[..20]);
+        std::fs::write(&path, &data).unwrap();
+
+        let report = recover(&path, false).unwrap();
+        assert!(!report.tail_is_valid());
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        let report = recover(&path, true).unwrap();
+        assert_eq!(report.valid_records, 2);
+        assert_eq!(std::fs::read(&path).unwrap(), complete);
+        assert!(recover(&path, false).unwrap().tail_is_valid());
+    }
+
+    #[test]
+    fn test_recover_keeps_records_after_mid_file_corruption() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("recover.rec");
+        let mut data = recording(&[b"first", b"second", b"third"]);
+        let header_len = data.len() - 3 * 12 - 16;
+        data[header_len + 7] ^= 0x40;
+        data.extend_from_slice(&framed(&[b"torn"])[..6]);
+        std::fs::write(&path, &data).unwrap();
+
+        let report = recover(&path, true).unwrap();
+        assert_eq!(report.valid_records, 2);
+        assert_eq!(report.problems.len(), 2);
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            data[..data.len() - 6].to_vec()
+        );
+    }
+
+    #[test]
+    fn test_recover_drops_corrupted_last_record() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("recover.rec");
+        let complete = recording(&[b"first"]);
+        let mut data = [complete.clone(), framed(&[b"second"])].concat();
+        let last = data.len() - 1;
+        data[last] ^= 0xFF;
+        std::fs::write(&path, &data).unwrap();
+
+        recover(&path, true).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), complete);
+    }
+}
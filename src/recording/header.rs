@@ -0,0 +1,398 @@
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use getset::Getters;
+
+use crate::recording::record::{clamp_string, BodyReader, RecordError};
+
+/// First bytes of every recording written by a file sink.
+pub const MAGIC: [u8; 8] = *b"ZMQ-REC\0";
+/// Version of the header and record layout written by this recorder.
+pub const FORMAT_VERSION: u16 = 3;
+/// Size of the magic, the format version and the header length.
+const PREAMBLE_SIZE: u64 = 8 + 2 + 4;
+/// Largest header fields accepted, far above any trained dictionary.
+const MAX_FIELDS_SIZE: u32 = 16 << 20;
+/// Largest dictionary a header may carry, leaving the rest of
+/// `MAX_FIELDS_SIZE` to the other fields.
+pub const MAX_DICTIONARY_SIZE: usize = 8 << 20;
+
+/// How every record body in a recording is encoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Codec {
+    #[default]
+    Uncompressed,
+    /// Every record gzipped on its own
+    Gzip,
+    /// One deflate stream per file, each framed record is a `block::BlockEncoder` block
+    DeflateStream,
+    /// Every record compressed on its own as a zstd frame, optionally with
+    /// the dictionary stored in the header
+    Zstd,
+    /// Every record compressed on its own as an lz4 block behind its
+    /// little-endian u32 uncompressed size
+    Lz4,
+}
+
+impl Codec {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Uncompressed => "none",
+            Codec::Gzip => "gzip",
+            Codec::DeflateStream => "deflate-stream",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+        }
+    }
+
+    /// True if a framed record holds a block of several messages.
+    pub fn is_block_codec(&self) -> bool {
+        matches!(self, Codec::DeflateStream)
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            Codec::Uncompressed => 0,
+            Codec::Gzip => 1,
+            Codec::DeflateStream => 2,
+            Codec::Zstd => 3,
+            Codec::Lz4 => 4,
+        }
+    }
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = HeaderError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Codec::Uncompressed),
+            1 => Ok(Codec::Gzip),
+            2 => Ok(Codec::DeflateStream),
+            3 => Ok(Codec::Zstd),
+            4 => Ok(Codec::Lz4),
+            _ => Err(HeaderError::UnknownCodec(value)),
+        }
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug)]
+pub enum HeaderError {
+    IoError(std::io::Error),
+    BadMagic([u8; 8]),
+    UnsupportedVersion(u16),
+    UnknownCodec(u8),
+    Malformed(RecordError),
+    TooLarge(u32),
+}
+
+impl From<std::io::Error> for HeaderError {
+    fn from(err: std::io::Error) -> Self {
+        HeaderError::IoError(err)
+    }
+}
+
+impl From<RecordError> for HeaderError {
+    fn from(err: RecordError) -> Self {
+        HeaderError::Malformed(err)
+    }
+}
+
+impl From<HeaderError> for std::io::Error {
+    fn from(err: HeaderError) -> Self {
+        match err {
+            HeaderError::IoError(err) => err,
+            err => std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HeaderError::IoError(err) => write!(f, "Failed to read the file header: {}", err),
+            HeaderError::BadMagic(magic) => {
+                write!(f, "Not a message-recorder file, it starts with {:?}", magic)
+            }
+            HeaderError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported recording format version {}, this build reads version {}",
+                version, FORMAT_VERSION
+            ),
+            HeaderError::UnknownCodec(codec) => write!(f, "Unknown codec id {}", codec),
+            HeaderError::Malformed(err) => write!(f, "Malformed file header: {}", err),
+            HeaderError::TooLarge(len) => write!(
+                f,
+                "File header claims {} bytes, more than the {} allowed",
+                len, MAX_FIELDS_SIZE
+            ),
+        }
+    }
+}
+
+/// Describes a recording. Written once at the start of every file sink output.
+#[derive(Debug, Clone, PartialEq, Getters)]
+#[get = "pub"]
+pub struct FileHeader {
+    format_version: u16,
+    codec: Codec,
+    created_at_ns: u64,
+    connection: String,
+    topics: Vec<String>,
+    recorder_version: String,
+    /// Compression dictionary the records need to be decoded, empty if none
+    dictionary: Vec<u8>,
+}
+
+impl FileHeader {
+    /// Header for a file created now by this build of the recorder.
+    pub fn new(codec: Codec, connection: String, topics: Vec<String>) -> Self {
+        FileHeader::new_with_dictionary(codec, connection, topics, vec![])
+    }
+
+    pub fn new_with_dictionary(
+        codec: Codec,
+        connection: String,
+        topics: Vec<String>,
+        dictionary: Vec<u8>,
+    ) -> Self {
+        let created_at_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_nanos() as u64)
+            .unwrap_or(0);
+        FileHeader {
+            format_version: FORMAT_VERSION,
+            codec,
+            created_at_ns,
+            connection,
+            topics,
+            recorder_version: env!("CARGO_PKG_VERSION").to_string(),
+            dictionary,
+        }
+    }
+
+    /// Encodes the header.
+    ///
+    /// The 8 byte magic is followed by a big-endian u16 format version and a
+    /// u32 length of the rest of the header. The rest is a u8 codec id, the
+    /// u64 creation time in nanoseconds since the epoch, the connection, a
+    /// u16 topic count with the topics, the recorder version and the
+    /// dictionary behind its u32 length. Strings are u16 length-prefixed utf8.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut fields = vec![];
+        // Writing into a Vec cannot fail
+        fields.write_u8(self.codec.id()).unwrap();
+        fields.write_u64::<BigEndian>(self.created_at_ns).unwrap();
+        write_string(&mut fields, &self.connection);
+        let topic_count = self.topics.len().min(u16::MAX as usize);
+        fields.write_u16::<BigEndian>(topic_count as u16).unwrap();
+        for topic in &self.topics[..topic_count] {
+            write_string(&mut fields, topic);
+        }
+        write_string(&mut fields, &self.recorder_version);
+        fields
+            .write_u32::<BigEndian>(self.dictionary.len() as u32)
+            .unwrap();
+        fields.extend_from_slice(&self.dictionary);
+
+        let mut header = Vec::with_capacity(PREAMBLE_SIZE as usize + fields.len());
+        header.extend_from_slice(&MAGIC);
+        header.write_u16::<BigEndian>(self.format_version).unwrap();
+        header.write_u32::<BigEndian>(fields.len() as u32).unwrap();
+        header.extend_from_slice(&fields);
+        header
+    }
+
+    /// Reads a header from the start of a recording. Returns it together with
+    /// the offset of the first record.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<(FileHeader, u64), HeaderError> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(HeaderError::BadMagic(magic));
+        }
+        let format_version = reader.read_u16::<BigEndian>()?;
+        if format_version != FORMAT_VERSION {
+            return Err(HeaderError::UnsupportedVersion(format_version));
+        }
+        let fields_len = reader.read_u32::<BigEndian>()?;
+        if fields_len > MAX_FIELDS_SIZE {
+            return Err(HeaderError::TooLarge(fields_len));
+        }
+        // Grows with what is actually read, a short file cannot make it allocate the claimed length
+        let mut fields = vec![];
+        reader.take(fields_len as u64).read_to_end(&mut fields)?;
+        if fields.len() != fields_len as usize {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let mut fields_reader = BodyReader::new(&fields);
+        let codec = Codec::try_from(fields_reader.take(1)?[0])?;
+        let created_at_ns = fields_reader.read_u64()?;
+        let connection = read_string(&mut fields_reader)?;
+        let topic_count = fields_reader.read_u16()?;
+        let topics = (0..topic_count)
+            .map(|_| read_string(&mut fields_reader))
+            .collect::<Result<Vec<_>, _>>()?;
+        let recorder_version = read_string(&mut fields_reader)?;
+        // Headers written before dictionaries existed end here
+        let dictionary = match fields_reader.remaining().is_empty() {
+            true => vec![],
+            false => {
+                let len = fields_reader.read_u32()?;
+                fields_reader.take(len as usize)?.to_vec()
+            }
+        };
+        // Anything after the known fields was added by a newer recorder
+        // without changing the layout, so it is skipped
+
+        let header = FileHeader {
+            format_version,
+            codec,
+            created_at_ns,
+            connection,
+            topics,
+            recorder_version,
+            dictionary,
+        };
+        Ok((header, PREAMBLE_SIZE + fields_len as u64))
+    }
+}
+
+fn write_string(buffer: &mut Vec<u8>, value: &str) {
+    let value = clamp_string(value);
+    buffer.write_u16::<BigEndian>(value.len() as u16).unwrap();
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+fn read_string(reader: &mut BodyReader) -> Result<String, RecordError> {
+    let len = reader.read_u16()?;
+    reader.read_string(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ByteOrder;
+
+    fn header() -> FileHeader {
+        FileHeader::new(
+            Codec::Gzip,
+            "tcp://localhost:5555".to_string(),
+            vec!["md.".to_string(), "orders.".to_string()],
+        )
+    }
+
+    #[test]
+    fn test_round_trip_header() {
+        let header = header();
+        let encoded = header.encode();
+        let (decoded, records_start) = FileHeader::read_from(&mut encoded.as_slice()).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(records_start, encoded.len() as u64);
+        assert_eq!(decoded.recorder_version(), env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn test_header_skips_unknown_trailing_fields() {
+        let header = header();
+        let mut encoded = header.encode();
+        encoded.extend_from_slice(b"new");
+        let fields_len = BigEndian::read_u32(&encoded[10..14]) + 3;
+        encoded[10..14].copy_from_slice(&fields_len.to_be_bytes());
+        encoded.extend_from_slice(b"first record");
+
+        let (decoded, records_start) = FileHeader::read_from(&mut encoded.as_slice()).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(&encoded[records_start as usize..], b"first record");
+    }
+
+    #[test]
+    fn test_round_trip_dictionary() {
+        let header = FileHeader::new_with_dictionary(
+            Codec::Zstd,
+            "tcp://localhost:5555".to_string(),
+            vec![],
+            b"dictionary".to_vec(),
+        );
+        let (decoded, _) = FileHeader::read_from(&mut header.encode().as_slice()).unwrap();
+        assert_eq!(decoded.dictionary(), b"dictionary");
+
+        // The largest dictionary a sink takes is still readable
+        let largest = FileHeader::new_with_dictionary(
+            Codec::Zstd,
+            "tcp://localhost:5555".to_string(),
+            vec![],
+            vec![7; MAX_DICTIONARY_SIZE],
+        );
+        let (decoded, _) = FileHeader::read_from(&mut largest.encode().as_slice()).unwrap();
+        assert_eq!(decoded.dictionary().len(), MAX_DICTIONARY_SIZE);
+    }
+
+    #[test]
+    fn test_header_without_dictionary_field() {
+        let header = header();
+        let mut encoded = header.encode();
+        // Drop the empty dictionary, as written before it was added
+        encoded.truncate(encoded.len() - 4);
+        let fields_len = BigEndian::read_u32(&encoded[10..14]) - 4;
+        encoded[10..14].copy_from_slice(&fields_len.to_be_bytes());
+
+        let (decoded, records_start) = FileHeader::read_from(&mut encoded.as_slice()).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(records_start, encoded.len() as u64);
+    }
+
+    #[test]
+    fn test_header_rejects_unknown_version() {
+        let mut encoded = header().encode();
+        encoded[8..10].copy_from_slice(&99u16.to_be_bytes());
+        assert!(matches!(
+            FileHeader::read_from(&mut encoded.as_slice()),
+            Err(HeaderError::UnsupportedVersion(99))
+        ));
+    }
+
+    #[test]
+    fn test_header_rejects_headerless_file() {
+        let mut legacy = vec![];
+        legacy.write_u64::<BigEndian>(5).unwrap();
+        legacy.extend_from_slice(b"hello");
+        assert!(matches!(
+            FileHeader::read_from(&mut legacy.as_slice()),
+            Err(HeaderError::BadMagic(_))
+        ));
+    }
+
+    #[test]
+    fn test_header_rejects_oversized_fields() {
+        let mut encoded = header().encode();
+        encoded[10..14].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            FileHeader::read_from(&mut encoded.as_slice()),
+            Err(HeaderError::TooLarge(u32::MAX))
+        ));
+        encoded[10..14].copy_from_slice(&(MAX_FIELDS_SIZE).to_be_bytes());
+        assert!(matches!(
+            FileHeader::read_from(&mut encoded.as_slice()),
+            Err(HeaderError::IoError(_))
+        ));
+    }
+
+    #[test]
+    fn test_header_rejects_unknown_codec() {
+        let mut encoded = header().encode();
+        encoded[14] = 200;
+        assert!(matches!(
+            FileHeader::read_from(&mut encoded.as_slice()),
+            Err(HeaderError::UnknownCodec(200))
+        ));
+    }
+}
@@ -0,0 +1,377 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, WriteBytesExt};
+use getset::Getters;
+use log::{info, warn};
+
+use crate::message_envelope::MessageEnvelope;
+use crate::recording::crc32c;
+use crate::recording::record::BodyReader;
+
+/// First bytes of every index sidecar.
+pub const INDEX_MAGIC: [u8; 8] = *b"ZMQ-IDX\0";
+pub const INDEX_VERSION: u16 = 1;
+/// Appended to the recording's name to name its index, `x.rec.idx`.
+pub const INDEX_EXTENSION: &str = "idx";
+/// A new entry is added once the records moved this far past the last one.
+pub const INDEX_INTERVAL_BYTES: u64 = 1024 * 1024;
+/// Five u64 fields per entry.
+const ENTRY_SIZE: usize = 5 * 8;
+
+/// Sequence number and receive time of the first message in a record.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexKey {
+    pub sequence: u64,
+    pub wall_time_ns: u64,
+}
+
+impl IndexKey {
+    /// Key of a message, `None` for a monitor event. Events are numbered
+    /// apart from the messages, so their sequence numbers cannot be looked up.
+    pub fn of(message: &MessageEnvelope) -> Option<IndexKey> {
+        if message.is_monitor_event() {
+            return None;
+        }
+        Some(IndexKey {
+            sequence: *message.sequence(),
+            wall_time_ns: *message.wall_time_ns(),
+        })
+    }
+}
+
+/// Where one record starts and what its first message is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexEntry {
+    /// Index of the record, counting framed records from zero
+    pub record: u64,
+    /// File offset of the record's length
+    pub offset: u64,
+    /// Index of the record's first message
+    pub first_message: u64,
+    pub sequence: u64,
+    pub wall_time_ns: u64,
+}
+
+/// Sparse index of a recording, one entry about every `INDEX_INTERVAL_BYTES`
+/// and one for every block of messages.
+/// Lookups by time and sequence number expect both to grow through the file,
+/// as they do within one run of the recorder.
+#[derive(Debug, Clone, Default, PartialEq, Getters)]
+#[get = "pub"]
+pub struct RecordingIndex {
+    entries: Vec<IndexEntry>,
+    /// Records and messages seen so far, damaged ones included
+    records: u64,
+    messages: u64,
+    /// Length of the recording up to the end of the last record seen
+    covered_len: u64,
+}
+
+impl RecordingIndex {
+    /// Empty index of a recording whose records start at `records_start`.
+    pub fn new(records_start: u64) -> Self {
+        RecordingIndex {
+            covered_len: records_start,
+            ..Default::default()
+        }
+    }
+
+    /// Name of the index sidecar of a recording.
+    pub fn sidecar_path(recording: &Path) -> PathBuf {
+        let mut name = recording.as_os_str().to_owned();
+        name.push(".");
+        name.push(INDEX_EXTENSION);
+        PathBuf::from(name)
+    }
+
+    /// Accounts for the record at `offset`. `key` is `None` for a damaged
+    /// record, whose messages cannot be indexed, or a monitor event.
+    pub fn add_record(
+        &mut self,
+        offset: u64,
+        record_len: u64,
+        messages: u64,
+        key: Option<IndexKey>,
+    ) {
+        let due = match self.entries.last() {
+            Some(last) => offset >= last.offset + INDEX_INTERVAL_BYTES,
+            None => true,
+        };
+        self.add(offset, record_len, messages, key, due);
+    }
+
+    /// Accounts for a block of messages at `offset`. Every block with a key
+    /// gets an entry, so the messages of a block damaged later can still be
+    /// counted from where the next one starts.
+    pub fn add_block(
+        &mut self,
+        offset: u64,
+        record_len: u64,
+        messages: u64,
+        key: Option<IndexKey>,
+    ) {
+        self.add(offset, record_len, messages, key, true);
+    }
+
+    fn add(
+        &mut self,
+        offset: u64,
+        record_len: u64,
+        messages: u64,
+        key: Option<IndexKey>,
+        due: bool,
+    ) {
+        if let (true, Some(key)) = (due, key) {
+            self.entries.push(IndexEntry {
+                record: self.records,
+                offset,
+                first_message: self.messages,
+                sequence: key.sequence,
+                wall_time_ns: key.wall_time_ns,
+            });
+        }
+        self.records += 1;
+        self.messages += messages;
+        self.covered_len = offset + record_len;
+    }
+
+    /// Last entry at or before the first message received at `wall_time_ns`.
+    pub fn entry_for_time(&self, wall_time_ns: u64) -> Option<&IndexEntry> {
+        self.last_entry_where(|entry| entry.wall_time_ns <= wall_time_ns)
+    }
+
+    /// Last entry at or before the message numbered `sequence`.
+    pub fn entry_for_sequence(&self, sequence: u64) -> Option<&IndexEntry> {
+        self.last_entry_where(|entry| entry.sequence <= sequence)
+    }
+
+    /// Last entry at or before message `index`.
+    pub fn entry_for_message(&self, index: u64) -> Option<&IndexEntry> {
+        self.last_entry_where(|entry| entry.first_message <= index)
+    }
+
+    /// Index of the first message of the record at `offset`, if an entry
+    /// starts there. At the end of the index, the number of messages.
+    pub fn first_message_at(&self, offset: u64) -> Option<u64> {
+        if offset == self.covered_len {
+            return Some(self.messages);
+        }
+        let entry = self
+            .entries
+            .binary_search_by_key(&offset, |entry| entry.offset)
+            .ok()?;
+        Some(self.entries[entry].first_message)
+    }
+
+    fn last_entry_where<F: Fn(&IndexEntry) -> bool>(&self, before: F) -> Option<&IndexEntry> {
+        match self.entries.partition_point(before) {
+            0 => None,
+            end => Some(&self.entries[end - 1]),
+        }
+    }
+
+    /// Encodes the index.
+    ///
+    /// The 8 byte magic is followed by a big-endian u16 version, the u64
+    /// record and message counts, the u64 covered length and the u64 entry
+    /// count. Every entry is five u64: record, offset, first message,
+    /// sequence number and wall time. A u32 CRC-32C of everything before it
+    /// ends the index.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(46 + self.entries.len() * ENTRY_SIZE);
+        // Writing into a Vec cannot fail
+        encoded.extend_from_slice(&INDEX_MAGIC);
+        encoded.write_u16::<BigEndian>(INDEX_VERSION).unwrap();
+        for value in [
+            self.records,
+            self.messages,
+            self.covered_len,
+            self.entries.len() as u64,
+        ] {
+            encoded.write_u64::<BigEndian>(value).unwrap();
+        }
+        for entry in &self.entries {
+            for value in [
+                entry.record,
+                entry.offset,
+                entry.first_message,
+                entry.sequence,
+                entry.wall_time_ns,
+            ] {
+                encoded.write_u64::<BigEndian>(value).unwrap();
+            }
+        }
+        let checksum = crc32c::checksum(&encoded);
+        encoded.write_u32::<BigEndian>(checksum).unwrap();
+        encoded
+    }
+
+    pub fn decode(encoded: &[u8]) -> std::io::Result<Self> {
+        let invalid = |reason: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Bad recording index: {}", reason),
+            )
+        };
+        if encoded.len() < 4 {
+            return Err(invalid("too short"));
+        }
+        let (content, checksum) = encoded.split_at(encoded.len() - 4);
+        if crc32c::checksum(content) != u32::from_be_bytes(checksum.try_into().unwrap()) {
+            return Err(invalid("checksum mismatch"));
+        }
+        let mut reader = BodyReader::new(content);
+        let truncated = |_| invalid("truncated");
+        if reader.take(INDEX_MAGIC.len()).map_err(truncated)? != INDEX_MAGIC {
+            return Err(invalid("not an index file"));
+        }
+        let version = reader.read_u16().map_err(truncated)?;
+        if version != INDEX_VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+        let mut index = RecordingIndex {
+            records: reader.read_u64().map_err(truncated)?,
+            messages: reader.read_u64().map_err(truncated)?,
+            covered_len: reader.read_u64().map_err(truncated)?,
+            entries: vec![],
+        };
+        let count = reader.read_u64().map_err(truncated)?;
+        if reader.remaining().len() as u64 != count.saturating_mul(ENTRY_SIZE as u64) {
+            return Err(invalid("wrong entry count"));
+        }
+        for _ in 0..count {
+            index.entries.push(IndexEntry {
+                record: reader.read_u64().map_err(truncated)?,
+                offset: reader.read_u64().map_err(truncated)?,
+                first_message: reader.read_u64().map_err(truncated)?,
+                sequence: reader.read_u64().map_err(truncated)?,
+                wall_time_ns: reader.read_u64().map_err(truncated)?,
+            });
+        }
+        Ok(index)
+    }
+
+    /// Writes the sidecar of `recording`. The index goes to a temporary file
+    /// first, so a crash never leaves a half written index behind.
+    pub fn write(&self, recording: &Path) -> std::io::Result<()> {
+        let path = RecordingIndex::sidecar_path(recording);
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        writer.write_all(&self.encode())?;
+        writer.into_inner()?.sync_all()?;
+        std::fs::rename(&temporary, &path)
+    }
+
+    /// Loads the sidecar of `recording`. Returns `None` if there is none, or
+    /// if it is damaged or does not end where the recording ends, e.g. after
+    /// a crash. The caller then rebuilds the index by scanning.
+    pub fn load(recording: &Path) -> std::io::Result<Option<Self>> {
+        let path = RecordingIndex::sidecar_path(recording);
+        let encoded = match std::fs::read(&path) {
+            Ok(encoded) => encoded,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let index = match RecordingIndex::decode(&encoded) {
+            Ok(index) => index,
+            Err(e) => {
+                warn!("Ignoring {}: {}", path.display(), e);
+                return Ok(None);
+            }
+        };
+        let recording_len = recording.metadata()?.len();
+        if index.covered_len != recording_len {
+            info!(
+                "Ignoring {}, it covers {} of {} bytes",
+                path.display(),
+                index.covered_len,
+                recording_len
+            );
+            return Ok(None);
+        }
+        Ok(Some(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn key(sequence: u64) -> Option<IndexKey> {
+        Some(IndexKey {
+            sequence,
+            wall_time_ns: 1_000 + sequence * 10,
+        })
+    }
+
+    fn sparse_index() -> RecordingIndex {
+        let mut index = RecordingIndex::new(50);
+        let record_len = INDEX_INTERVAL_BYTES / 2;
+        for record in 0..6 {
+            let offset = 50 + record * record_len;
+            if record == 2 {
+                index.add_record(offset, record_len, 1, None);
+            } else {
+                index.add_record(offset, record_len, 2, key(record * 2));
+            }
+        }
+        index
+    }
+
+    #[test]
+    fn test_entries_are_sparse() {
+        let index = sparse_index();
+        let offsets: Vec<_> = index.entries().iter().map(|entry| entry.record).collect();
+        // The damaged record 2 was due but cannot be indexed, 3 takes its place
+        assert_eq!(offsets, vec![0, 3, 5]);
+        assert_eq!(index.entries()[1].first_message, 5);
+        assert_eq!(*index.messages(), 11);
+        assert_eq!(*index.covered_len(), 50 + 6 * INDEX_INTERVAL_BYTES / 2);
+    }
+
+    #[test]
+    fn test_lookups() {
+        let index = sparse_index();
+        assert_eq!(index.entry_for_time(999), None);
+        assert_eq!(index.entry_for_time(1_000).unwrap().record, 0);
+        assert_eq!(index.entry_for_time(1_075).unwrap().record, 3);
+        assert_eq!(index.entry_for_sequence(100).unwrap().record, 5);
+        assert_eq!(index.entry_for_message(4).unwrap().record, 0);
+        assert_eq!(index.entry_for_message(5).unwrap().record, 3);
+    }
+
+    #[test]
+    fn test_round_trip_and_damage() {
+        let index = sparse_index();
+        let mut encoded = index.encode();
+        assert_eq!(RecordingIndex::decode(&encoded).unwrap(), index);
+        encoded[20] ^= 0x01;
+        assert!(RecordingIndex::decode(&encoded).is_err());
+        assert!(RecordingIndex::decode(b"ZMQ").is_err());
+    }
+
+    #[test]
+    fn test_load_ignores_stale_sidecar() {
+        let temp_dir = tempdir().unwrap();
+        let recording = temp_dir.path().join("stale.rec");
+        let mut index = RecordingIndex::new(10);
+        index.add_record(10, 20, 1, key(0));
+        std::fs::write(&recording, [0u8; 30]).unwrap();
+        assert_eq!(RecordingIndex::load(&recording).unwrap(), None);
+
+        index.write(&recording).unwrap();
+        assert_eq!(
+            RecordingIndex::sidecar_path(&recording),
+            temp_dir.path().join("stale.rec.idx")
+        );
+        assert_eq!(RecordingIndex::load(&recording).unwrap(), Some(index));
+
+        std::fs::write(&recording, [0u8; 40]).unwrap();
+        assert_eq!(RecordingIndex::load(&recording).unwrap(), None);
+    }
+}
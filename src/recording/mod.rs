@@ -0,0 +1,8 @@
+pub mod block;
+pub mod crc32c;
+pub mod dictionary;
+pub mod framing;
+pub mod header;
+pub mod index;
+pub mod reader;
+pub mod record;
@@ -0,0 +1,1036 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use zstd::dict::DecoderDictionary;
+
+use crate::message_envelope::MessageEnvelope;
+use crate::recording::block;
+use crate::recording::framing::{self, RECORD_PREFIX_SIZE};
+use crate::recording::header::{Codec, FileHeader, HeaderError};
+use crate::recording::index::{IndexEntry, IndexKey, RecordingIndex};
+use crate::recording::record::{self, RecordError};
+
+/// Errors name the record by its index and the file offset of its length.
+/// Without a block codec every record holds one message.
+#[derive(Debug)]
+pub enum ReaderError {
+    IoError(std::io::Error),
+    HeaderError(HeaderError),
+    Truncated {
+        index: u64,
+        offset: u64,
+        needed: u64,
+        available: u64,
+    },
+    ChecksumMismatch {
+        index: u64,
+        offset: u64,
+        expected: u32,
+        actual: u32,
+    },
+    DecompressError {
+        index: u64,
+        offset: u64,
+        err: std::io::Error,
+    },
+    MalformedRecord {
+        index: u64,
+        offset: u64,
+        err: RecordError,
+    },
+    IndexOutOfRange {
+        index: u64,
+        records: u64,
+    },
+}
+
+impl From<std::io::Error> for ReaderError {
+    fn from(err: std::io::Error) -> Self {
+        ReaderError::IoError(err)
+    }
+}
+
+impl From<HeaderError> for ReaderError {
+    fn from(err: HeaderError) -> Self {
+        ReaderError::HeaderError(err)
+    }
+}
+
+impl std::fmt::Display for ReaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReaderError::IoError(err) => write!(f, "IO error: {}", err),
+            ReaderError::HeaderError(err) => write!(f, "{}", err),
+            ReaderError::Truncated {
+                index,
+                offset,
+                needed,
+                available,
+            } => write!(
+                f,
+                "Record {} at offset {} is truncated: needed {} bytes but only {} are left",
+                index, offset, needed, available
+            ),
+            ReaderError::ChecksumMismatch {
+                index,
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Record {} at offset {} is corrupted: checksum {:#010x} does not match {:#010x}",
+                index, offset, actual, expected
+            ),
+            ReaderError::DecompressError { index, offset, err } => write!(
+                f,
+                "Record {} at offset {} failed to decompress: {}",
+                index, offset, err
+            ),
+            ReaderError::MalformedRecord { index, offset, err } => {
+                write!(
+                    f,
+                    "Record {} at offset {} is malformed: {}",
+                    index, offset, err
+                )
+            }
+            ReaderError::IndexOutOfRange { index, records } => write!(
+                f,
+                "Record {} does not exist, the recording holds {} records",
+                index, records
+            ),
+        }
+    }
+}
+
+/// End of a walked record and its body, no body if the record was damaged.
+type WalkedRecord = (u64, Option<Vec<u8>>);
+
+/// Reads the records written by `FileSink` and `CompressedFileSink` back as
+/// messages, undoing the codec named in the file header. With a block codec
+/// one record holds many messages, `position` and `seek_to_record` count
+/// messages while errors name the damaged record. Recorded monitor events
+/// are skipped unless `set_include_events` asks for them, they still count
+/// towards positions.
+pub struct RecordingReader<R = BufReader<File>> {
+    reader: R,
+    header: FileHeader,
+    /// The zstd dictionary of the header, prepared once for every record
+    dictionary: Option<DecoderDictionary<'static>>,
+    /// Offset of the first record, right after the header
+    records_start: u64,
+    /// Offset and first message index of the records walked so far, starting
+    /// with record `first_record`
+    records: Vec<(u64, u64)>,
+    first_record: u64,
+    /// Index of the record read once `pending` is empty
+    next_record: usize,
+    /// Messages of the current block that were not returned yet
+    pending: VecDeque<MessageEnvelope>,
+    /// Index of the message returned by the next call to `next_record`
+    next_index: u64,
+    /// Set once nothing more is readable: the file ended inside a record, no
+    /// valid record follows a damaged one, or reading failed
+    truncated: bool,
+    index: Option<RecordingIndex>,
+    include_events: bool,
+}
+
+impl RecordingReader<BufReader<File>> {
+    /// Opens a recording together with its index sidecar, if it has an up to
+    /// date one.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ReaderError> {
+        let mut reader = RecordingReader::new(BufReader::new(File::open(&path)?))?;
+        reader.index = RecordingIndex::load(path.as_ref())?;
+        Ok(reader)
+    }
+}
+
+impl<R: Read + Seek> RecordingReader<R> {
+    /// Reads the file header, leaving the reader at the first record.
+    pub fn new(mut reader: R) -> Result<Self, ReaderError> {
+        let (header, records_start) = FileHeader::read_from(&mut reader)?;
+        reader.seek(SeekFrom::Start(records_start))?;
+        let dictionary = match header.dictionary().is_empty() {
+            true => None,
+            false => Some(DecoderDictionary::copy(header.dictionary())),
+        };
+        Ok(RecordingReader {
+            reader,
+            header,
+            dictionary,
+            records_start,
+            records: vec![(records_start, 0)],
+            first_record: 0,
+            next_record: 0,
+            pending: VecDeque::new(),
+            next_index: 0,
+            truncated: false,
+            index: None,
+            include_events: false,
+        })
+    }
+
+    /// Connection, topics, codec and creation time of the recording.
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    /// Also returns the monitor events recorded with `record_events`.
+    pub fn set_include_events(&mut self, include_events: bool) {
+        self.include_events = include_events;
+    }
+
+    /// Index of the message the next call to `next_record` returns.
+    pub fn position(&self) -> u64 {
+        self.next_index
+    }
+
+    /// Reads the next message. Returns `None` at the end of the file. A record
+    /// with a bad checksum or body is reported and skipped, so reading can
+    /// continue after it. A truncated record or a failed read ends the recording.
+    pub fn next_record(&mut self) -> Result<Option<MessageEnvelope>, ReaderError> {
+        loop {
+            match self.next_message() {
+                Ok(Some(message)) if message.is_monitor_event() && !self.include_events => {}
+                result => return result,
+            }
+        }
+    }
+
+    /// Reads the next message or monitor event.
+    fn next_message(&mut self) -> Result<Option<MessageEnvelope>, ReaderError> {
+        let result = self.read_next_record();
+        if let Err(ReaderError::IoError(_)) = result {
+            // Reading again would fail at the same place
+            self.truncated = true;
+        }
+        result
+    }
+
+    fn read_next_record(&mut self) -> Result<Option<MessageEnvelope>, ReaderError> {
+        loop {
+            if let Some(message) = self.pending.pop_front() {
+                self.next_index += 1;
+                return Ok(Some(message));
+            }
+            if self.truncated {
+                return Ok(None);
+            }
+            let index = self.first_record + self.next_record as u64;
+            let (offset, _) = self.records[self.next_record];
+            let mut prefix = [0u8; RECORD_PREFIX_SIZE as usize];
+            match self.read_up_to(&mut prefix)? {
+                0 => return Ok(None),
+                read if read < prefix.len() => {
+                    return Err(self.truncated_at(index, offset, RECORD_PREFIX_SIZE, read as u64))
+                }
+                _ => {}
+            }
+            let (body_len, expected) = framing::decode_prefix(&prefix);
+
+            let mut body = vec![];
+            let read = (&mut self.reader).take(body_len).read_to_end(&mut body)? as u64;
+            if read < body_len {
+                // A damaged length reaches past the end, valid records may still follow
+                self.resync_after(offset)?;
+                return Err(ReaderError::Truncated {
+                    index,
+                    offset,
+                    needed: body_len,
+                    available: read,
+                });
+            }
+            let record_end = offset + RECORD_PREFIX_SIZE + body_len;
+
+            let actual = framing::record_checksum(body_len, &body);
+            if actual != expected {
+                // The length is not verified either, so it cannot say where the next record is
+                self.resync_after(offset)?;
+                return Err(ReaderError::ChecksumMismatch {
+                    index,
+                    offset,
+                    expected,
+                    actual,
+                });
+            }
+            match self.decode_messages(index, offset, body) {
+                Ok(messages) => {
+                    self.advance(record_end, messages.len() as u64);
+                    self.pending = messages.into();
+                }
+                Err(err) => {
+                    self.skip_damaged(record_end)?;
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// Moves to message `index`, counting from zero. Records are found by
+    /// walking their lengths, the bodies are read to verify the lengths
+    /// walked. Blocks have to be decompressed to count their messages. The
+    /// walk starts at the closest index entry if the recording has an index.
+    pub fn seek_to_record(&mut self, index: u64) -> Result<(), ReaderError> {
+        let end = self.reader.seek(SeekFrom::End(0))?;
+        let position = self.next_index;
+        let known_end = self.records[self.records.len() - 1].1;
+        let entry = self
+            .index
+            .as_ref()
+            .and_then(|recording_index| recording_index.entry_for_message(index))
+            .copied();
+        match entry {
+            Some(entry) if entry.first_message > known_end || index < self.records[0].1 => {
+                self.restart_at(entry.record, entry.offset, entry.first_message);
+            }
+            _ if index < self.records[0].1 => {
+                self.restart_at(0, self.records_start, 0);
+            }
+            _ => {}
+        }
+        while self.records[self.records.len() - 1].1 <= index {
+            let (offset, first_message) = self.records[self.records.len() - 1];
+            match self.count_messages(offset, first_message, end)? {
+                Some((record_end, messages)) => {
+                    self.records.push((record_end, first_message + messages))
+                }
+                // Seeking to the end of what is readable
+                None if first_message == index => break,
+                None => {
+                    // Back to where reading was, which may be before a restart
+                    self.seek_to_record(position)?;
+                    return Err(ReaderError::IndexOutOfRange {
+                        index,
+                        records: first_message,
+                    });
+                }
+            }
+        }
+        let record = self.records.partition_point(|&(_, first)| first <= index) - 1;
+        self.move_to_record(record)?;
+        for _ in self.records[record].1..index {
+            self.next_message()?;
+        }
+        Ok(())
+    }
+
+    /// Moves to the first message received at or after `wall_time_ns`, or to
+    /// the end if there is none. Uses the index, building it first if the
+    /// recording has none, then reads forward from the closest entry.
+    pub fn seek_to_time(&mut self, wall_time_ns: u64) -> Result<(), ReaderError> {
+        let entry = self.index()?.entry_for_time(wall_time_ns).copied();
+        self.seek_forward(entry, |message| *message.wall_time_ns() >= wall_time_ns)
+    }
+
+    /// Moves to the first message with a sequence number of at least
+    /// `sequence`, or to the end if there is none.
+    pub fn seek_to_sequence(&mut self, sequence: u64) -> Result<(), ReaderError> {
+        let entry = self.index()?.entry_for_sequence(sequence).copied();
+        self.seek_forward(entry, |message| *message.sequence() >= sequence)
+    }
+
+    /// Index of the recording. `open` loads the sidecar written by the sink,
+    /// if it is missing or out of date the index is built by scanning the
+    /// whole recording once.
+    pub fn index(&mut self) -> Result<&RecordingIndex, ReaderError> {
+        if self.index.is_none() {
+            let position = self.next_index;
+            let index = self.scan_index()?;
+            self.index = Some(index);
+            self.seek_to_record(position)?;
+        }
+        Ok(self.index.as_ref().unwrap())
+    }
+
+    /// Reads from `entry`, or the start, up to the first message `found`
+    /// accepts, which the next call to `next_record` returns. Damaged records
+    /// on the way are skipped.
+    fn seek_forward<F: Fn(&MessageEnvelope) -> bool>(
+        &mut self,
+        entry: Option<IndexEntry>,
+        found: F,
+    ) -> Result<(), ReaderError> {
+        let record = match entry {
+            Some(entry) => self.restart_at(entry.record, entry.offset, entry.first_message),
+            None => self.restart_at(0, self.records_start, 0),
+        };
+        self.move_to_record(record)?;
+        loop {
+            match self.next_message() {
+                Ok(Some(message)) if !message.is_monitor_event() && found(&message) => {
+                    self.pending.push_front(message);
+                    self.next_index -= 1;
+                    return Ok(());
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => return Ok(()),
+                Err(
+                    ReaderError::ChecksumMismatch { .. }
+                    | ReaderError::DecompressError { .. }
+                    | ReaderError::MalformedRecord { .. }
+                    | ReaderError::Truncated { .. },
+                ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Forgets the records seen so far unless `record` is one of them, so
+    /// walking continues from `record`. Returns where `record` is in `records`.
+    fn restart_at(&mut self, record: u64, offset: u64, first_message: u64) -> usize {
+        match record.checked_sub(self.first_record) {
+            Some(known) if known < self.records.len() as u64 => known as usize,
+            _ => {
+                self.records = vec![(offset, first_message)];
+                self.first_record = record;
+                self.next_record = 0;
+                0
+            }
+        }
+    }
+
+    /// Continues reading at the start of a record in `records`.
+    fn move_to_record(&mut self, record: usize) -> std::io::Result<()> {
+        let (offset, first_message) = self.records[record];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.next_record = record;
+        self.next_index = first_message;
+        self.pending.clear();
+        self.truncated = false;
+        Ok(())
+    }
+
+    /// Reads every record once to index it. A damaged record is counted but
+    /// not indexed.
+    fn scan_index(&mut self) -> Result<RecordingIndex, ReaderError> {
+        let end = self.reader.seek(SeekFrom::End(0))?;
+        let mut index = RecordingIndex::new(self.records_start);
+        let mut offset = self.records_start;
+        while let Some((record_end, body)) = self.walk_record(offset, end)? {
+            let messages =
+                body.and_then(|body| self.decode_messages(*index.records(), offset, body).ok());
+            let (messages, key) = match messages {
+                Some(messages) => (
+                    messages.len() as u64,
+                    messages.iter().find_map(IndexKey::of),
+                ),
+                None => (self.lost_messages(*index.messages(), record_end), None),
+            };
+            match self.header.codec().is_block_codec() {
+                true => index.add_block(offset, record_end - offset, messages, key),
+                false => index.add_record(offset, record_end - offset, messages, key),
+            }
+            offset = record_end;
+        }
+        Ok(index)
+    }
+
+    /// Finds the end of the record at `offset`, whose first message is
+    /// numbered `first_message`, and the number of messages in it, `None` if
+    /// nothing readable follows. Bodies are read to verify the lengths walked.
+    fn count_messages(
+        &mut self,
+        offset: u64,
+        first_message: u64,
+        end: u64,
+    ) -> Result<Option<(u64, u64)>, ReaderError> {
+        let Some((record_end, body)) = self.walk_record(offset, end)? else {
+            return Ok(None);
+        };
+        let messages = match body {
+            Some(body) if self.header.codec().is_block_codec() => {
+                block::decode_block(&body).map_or(0, |bodies| bodies.len() as u64)
+            }
+            Some(_) => 1,
+            None => self.lost_messages(first_message, record_end),
+        };
+        Ok(Some((record_end, messages)))
+    }
+
+    /// Reads the record at `offset`. Returns its end and body, or for a
+    /// damaged record the offset of the next valid one and no body. `None`
+    /// if no valid record follows.
+    fn walk_record(&mut self, offset: u64, end: u64) -> Result<Option<WalkedRecord>, ReaderError> {
+        if let Some((record_end, checksum)) = self.read_prefix_at(offset, end)? {
+            if let Some(body) = self.read_valid_body(record_end - offset, checksum)? {
+                return Ok(Some((record_end, Some(body))));
+            }
+        }
+        Ok(framing::resync(&mut self.reader, offset + 1, end)?.map(|next| (next, None)))
+    }
+
+    /// Reads the length and checksum of the record at `offset`. Returns the
+    /// end of the record and its checksum, `None` if it does not fit in the file.
+    fn read_prefix_at(&mut self, offset: u64, end: u64) -> Result<Option<(u64, u32)>, ReaderError> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut prefix = [0u8; RECORD_PREFIX_SIZE as usize];
+        if self.read_up_to(&mut prefix)? < prefix.len() {
+            return Ok(None);
+        }
+        let (body_len, checksum) = framing::decode_prefix(&prefix);
+        match (offset + RECORD_PREFIX_SIZE).checked_add(body_len) {
+            Some(record_end) if record_end <= end => Ok(Some((record_end, checksum))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Reads the body following a prefix, `None` if it does not match its checksum.
+    fn read_valid_body(
+        &mut self,
+        record_len: u64,
+        checksum: u32,
+    ) -> Result<Option<Vec<u8>>, ReaderError> {
+        let body_len = record_len - RECORD_PREFIX_SIZE;
+        let mut body = vec![0u8; body_len as usize];
+        self.reader.read_exact(&mut body)?;
+        Ok(Some(body).filter(|body| framing::record_checksum(body_len, body) == checksum))
+    }
+
+    /// Messages counted for the damaged records from the one numbered
+    /// `first_message` up to `next_offset`. The index sidecar has an entry
+    /// for every block, so it knows where the messages after a damaged block
+    /// start. Without it, as while building the index, a damaged block counts
+    /// none and a damaged record of another codec one.
+    fn lost_messages(&self, first_message: u64, next_offset: u64) -> u64 {
+        let recorded = self
+            .index
+            .as_ref()
+            .and_then(|index| index.first_message_at(next_offset))
+            .and_then(|next| next.checked_sub(first_message));
+        match (recorded, self.header.codec().is_block_codec()) {
+            (Some(messages), _) => messages,
+            (None, true) => 0,
+            (None, false) => 1,
+        }
+    }
+
+    fn advance(&mut self, next_offset: u64, messages: u64) {
+        let first_message = self.records[self.next_record].1;
+        self.next_record += 1;
+        if self.records.len() == self.next_record {
+            self.records.push((next_offset, first_message + messages));
+        }
+    }
+
+    /// Moves past a record that could not be read.
+    fn skip_damaged(&mut self, next_offset: u64) -> std::io::Result<()> {
+        let first_message = self.records[self.next_record].1;
+        self.advance(next_offset, self.lost_messages(first_message, next_offset));
+        self.next_index = self.records[self.next_record].1;
+        self.reader.seek(SeekFrom::Start(next_offset))?;
+        Ok(())
+    }
+
+    /// Continues after the damaged record at `offset` with the next record
+    /// whose checksum matches, or ends reading if there is none.
+    fn resync_after(&mut self, offset: u64) -> std::io::Result<()> {
+        let end = self.reader.seek(SeekFrom::End(0))?;
+        match framing::resync(&mut self.reader, offset + 1, end)? {
+            Some(next) => self.skip_damaged(next),
+            None => {
+                self.truncated = true;
+                Ok(())
+            }
+        }
+    }
+
+    fn truncated_at(
+        &mut self,
+        index: u64,
+        offset: u64,
+        needed: u64,
+        available: u64,
+    ) -> ReaderError {
+        self.truncated = true;
+        ReaderError::Truncated {
+            index,
+            offset,
+            needed,
+            available,
+        }
+    }
+
+    /// Fills as much of `buffer` as the file still holds.
+    fn read_up_to(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match self.reader.read(&mut buffer[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(filled)
+    }
+
+    fn decode_zstd(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decoded = vec![];
+        match &self.dictionary {
+            Some(dictionary) => zstd::Decoder::with_prepared_dictionary(body, dictionary)?
+                .read_to_end(&mut decoded)?,
+            None => zstd::Decoder::new(body)?.read_to_end(&mut decoded)?,
+        };
+        Ok(decoded)
+    }
+
+    fn decode_messages(
+        &self,
+        index: u64,
+        offset: u64,
+        body: Vec<u8>,
+    ) -> Result<Vec<MessageEnvelope>, ReaderError> {
+        let bodies = match self.header.codec() {
+            Codec::Uncompressed => Ok(vec![body]),
+            Codec::Gzip => {
+                let mut decoded = vec![];
+                GzDecoder::new(body.as_slice())
+                    .read_to_end(&mut decoded)
+                    .map(|_| vec![decoded])
+            }
+            Codec::DeflateStream => block::decode_block(&body),
+            Codec::Zstd => self.decode_zstd(&body).map(|decoded| vec![decoded]),
+            Codec::Lz4 => lz4::block::decompress(&body, None).map(|decoded| vec![decoded]),
+        }
+        .map_err(|err| ReaderError::DecompressError { index, offset, err })?;
+        bodies
+            .iter()
+            .map(|body| record::decode_envelope(body))
+            .collect::<Result<_, _>>()
+            .map_err(|err| ReaderError::MalformedRecord { index, offset, err })
+    }
+}
+
+impl<R: Read + Seek> Iterator for RecordingReader<R> {
+    type Item = Result<MessageEnvelope, ReaderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::framing::encode_prefix;
+    use crate::sink::Sink;
+    use crate::sinks::compressed_file_sink::{
+        CompressedFileSink, CompressionMode, CompressionOptions,
+    };
+    use crate::sinks::file_sink::{FileSink, FileSinkOptions};
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    fn messages(count: u64) -> Vec<MessageEnvelope> {
+        (0..count)
+            .map(|sequence| {
+                MessageEnvelope::new(
+                    sequence,
+                    "tcp://localhost:5555".to_string(),
+                    Some("topic".to_string()),
+                    vec![
+                        b"header".to_vec(),
+                        format!("payload {}", sequence).into_bytes(),
+                    ],
+                )
+            })
+            .collect()
+    }
+
+    fn recording(messages: &[MessageEnvelope]) -> Vec<u8> {
+        let mut data = FileHeader::new(Codec::Uncompressed, "conn".to_string(), vec![]).encode();
+        for message in messages {
+            let body = record::encode_envelope(message);
+            data.extend_from_slice(&encode_prefix(&body));
+            data.extend_from_slice(&body);
+        }
+        data
+    }
+
+    #[test]
+    fn test_read_file_sink_output() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("plain.rec");
+        let options = FileSinkOptions {
+            connection: "tcp://localhost:5555".to_string(),
+            topics: vec!["topic".to_string()],
+            ..Default::default()
+        };
+        let mut sink =
+            FileSink::new_with_options(path.to_str().unwrap().to_string(), options).unwrap();
+        let expected = messages(3);
+        for message in &expected {
+            sink.write(message).unwrap();
+        }
+        sink.flush().unwrap();
+
+        let reader = RecordingReader::open(&path).unwrap();
+        assert_eq!(reader.header().connection(), "tcp://localhost:5555");
+        assert_eq!(reader.header().topics(), &vec!["topic".to_string()]);
+        let read: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn test_read_compressed_sink_output() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("compressed.rec");
+        let mut sink = CompressedFileSink::new(path.to_str().unwrap().to_string(), 0, 6).unwrap();
+        let expected = messages(3);
+        for message in &expected {
+            sink.write(message).unwrap();
+        }
+        sink.flush().unwrap();
+
+        let reader = RecordingReader::open(&path).unwrap();
+        assert_eq!(*reader.header().codec(), Codec::Gzip);
+        let read: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+        assert_eq!(read, expected);
+    }
+
+    fn stream_recording(path: &Path, blocks: &[&[MessageEnvelope]]) {
+        let mut sink = CompressedFileSink::new_with_options(
+            path.to_str().unwrap().to_string(),
+            FileSinkOptions {
+                flush_time_s: 60,
+                ..Default::default()
+            },
+            CompressionOptions {
+                level: 6,
+                mode: CompressionMode::Stream,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        for block in blocks {
+            for message in *block {
+                sink.write(message).unwrap();
+            }
+            sink.flush().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_read_stream_blocks() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("stream.rec");
+        let expected = messages(7);
+        stream_recording(&path, &[&expected[..3], &expected[3..4], &expected[4..]]);
+
+        let mut reader = RecordingReader::open(&path).unwrap();
+        assert_eq!(*reader.header().codec(), Codec::DeflateStream);
+        reader.seek_to_record(5).unwrap();
+        assert_eq!(reader.position(), 5);
+        assert_eq!(reader.next_record().unwrap().unwrap(), expected[5]);
+        reader.seek_to_record(1).unwrap();
+        let read: Vec<_> = (&mut reader).collect::<Result<_, _>>().unwrap();
+        assert_eq!(read, expected[1..]);
+        assert_eq!(reader.position(), 7);
+        reader.seek_to_record(7).unwrap();
+        assert!(matches!(
+            reader.seek_to_record(8),
+            Err(ReaderError::IndexOutOfRange {
+                index: 8,
+                records: 7
+            })
+        ));
+    }
+
+    #[test]
+    fn test_corrupted_block_is_skipped() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("stream.rec");
+        let expected = messages(5);
+        stream_recording(&path, &[&expected[..2], &expected[2..4], &expected[4..]]);
+        let mut data = std::fs::read(&path).unwrap();
+        let mut reader = RecordingReader::new(Cursor::new(data.clone())).unwrap();
+        reader.seek_to_record(2).unwrap();
+        let second_block = reader.records[1].0 as usize;
+        data[second_block + RECORD_PREFIX_SIZE as usize] ^= 0xFF;
+
+        let results: Vec<_> = RecordingReader::new(Cursor::new(data)).unwrap().collect();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[1].as_ref().unwrap(), &expected[1]);
+        assert!(matches!(
+            results[2],
+            Err(ReaderError::ChecksumMismatch { index: 1, .. })
+        ));
+        assert_eq!(results[3].as_ref().unwrap(), &expected[4]);
+    }
+
+    #[test]
+    fn test_damaged_block_keeps_message_numbers() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("stream.rec");
+        let expected = messages(6);
+        stream_recording(&path, &[&expected[..2], &expected[2..5], &expected[5..]]);
+        let mut data = std::fs::read(&path).unwrap();
+        let mut reader = RecordingReader::new(Cursor::new(data.clone())).unwrap();
+        reader.seek_to_record(2).unwrap();
+        let second_block = reader.records[1].0 as usize;
+        data[second_block + RECORD_PREFIX_SIZE as usize] ^= 0xFF;
+        std::fs::write(&path, &data).unwrap();
+
+        // The sidecar says how many messages the damaged block held
+        let mut reader = RecordingReader::open(&path).unwrap();
+        let results: Vec<_> = (&mut reader).collect();
+        assert_eq!(results.len(), 4);
+        assert_eq!(reader.position(), 6);
+        reader.seek_to_record(5).unwrap();
+        assert_eq!(reader.next_record().unwrap().unwrap(), expected[5]);
+        reader.seek_to_sequence(3).unwrap();
+        assert_eq!(reader.position(), 5);
+
+        // Without it only the readable messages are numbered
+        let mut reader = RecordingReader::new(Cursor::new(data)).unwrap();
+        reader.seek_to_record(2).unwrap();
+        assert_eq!(reader.next_record().unwrap().unwrap(), expected[5]);
+        assert_eq!(*reader.index().unwrap().messages(), 3);
+    }
+
+    /// Messages a second apart, large enough for several index entries.
+    fn timed_messages(count: u64) -> Vec<MessageEnvelope> {
+        (0..count)
+            .map(|sequence| {
+                MessageEnvelope::with_timestamps(
+                    sequence + 100,
+                    (1_700_000_000 + sequence) * 1_000_000_000,
+                    sequence * 1_000_000_000,
+                    "conn".to_string(),
+                    None,
+                    vec![vec![sequence as u8; 100_000]],
+                )
+            })
+            .collect()
+    }
+
+    fn write_timed_recording(path: &Path, messages: &[MessageEnvelope]) {
+        let mut sink = FileSink::new(path.to_str().unwrap().to_string(), 0).unwrap();
+        for message in messages {
+            sink.write(message).unwrap();
+        }
+    }
+
+    fn assert_seeks(reader: &mut RecordingReader, expected: &[MessageEnvelope]) {
+        reader.seek_to_time(*expected[57].wall_time_ns()).unwrap();
+        assert_eq!(reader.next_record().unwrap().unwrap(), expected[57]);
+        reader
+            .seek_to_time(expected[12].wall_time_ns() - 1)
+            .unwrap();
+        assert_eq!(reader.next_record().unwrap().unwrap(), expected[12]);
+        reader.seek_to_sequence(180).unwrap();
+        assert_eq!(reader.next_record().unwrap().unwrap(), expected[80]);
+        reader.seek_to_time(0).unwrap();
+        assert_eq!(reader.next_record().unwrap().unwrap(), expected[0]);
+        reader.seek_to_time(u64::MAX).unwrap();
+        assert!(reader.next_record().unwrap().is_none());
+        reader.seek_to_record(99).unwrap();
+        assert_eq!(reader.next_record().unwrap().unwrap(), expected[99]);
+    }
+
+    #[test]
+    fn test_seek_with_index_sidecar() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("indexed.rec");
+        let expected = timed_messages(100);
+        write_timed_recording(&path, &expected);
+
+        let mut reader = RecordingReader::open(&path).unwrap();
+        let index = reader
+            .index
+            .clone()
+            .expect("The sink writes an index on close");
+        assert!(index.entries().len() > 5);
+        assert_eq!(*index.messages(), 100);
+        assert_seeks(&mut reader, &expected);
+    }
+
+    #[test]
+    fn test_seek_rebuilds_missing_index() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("unindexed.rec");
+        let expected = timed_messages(100);
+        write_timed_recording(&path, &expected);
+        let sidecar = RecordingIndex::sidecar_path(&path);
+        let written = RecordingIndex::decode(&std::fs::read(&sidecar).unwrap()).unwrap();
+        std::fs::remove_file(&sidecar).unwrap();
+
+        let mut reader = RecordingReader::open(&path).unwrap();
+        assert!(reader.index.is_none());
+        reader.seek_to_record(3).unwrap();
+        assert_eq!(reader.index().unwrap(), &written);
+        assert_eq!(reader.next_record().unwrap().unwrap(), expected[3]);
+        assert_seeks(&mut reader, &expected);
+    }
+
+    #[test]
+    fn test_seek_to_time_in_stream_blocks() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("stream.rec");
+        let expected = timed_messages(9);
+        stream_recording(&path, &[&expected[..4], &expected[4..]]);
+
+        let mut reader = RecordingReader::open(&path).unwrap();
+        reader.seek_to_time(*expected[6].wall_time_ns()).unwrap();
+        assert_eq!(reader.position(), 6);
+        let read: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+        assert_eq!(read, expected[6..]);
+    }
+
+    #[test]
+    fn test_seek_to_record() {
+        let expected = messages(5);
+        let mut reader = RecordingReader::new(Cursor::new(recording(&expected))).unwrap();
+
+        reader.seek_to_record(3).unwrap();
+        assert_eq!(reader.next_record().unwrap().unwrap(), expected[3]);
+        reader.seek_to_record(1).unwrap();
+        assert_eq!(reader.position(), 1);
+        assert_eq!(reader.next_record().unwrap().unwrap(), expected[1]);
+        reader.seek_to_record(5).unwrap();
+        assert!(reader.next_record().unwrap().is_none());
+        reader.seek_to_record(2).unwrap();
+        assert!(matches!(
+            reader.seek_to_record(6),
+            Err(ReaderError::IndexOutOfRange {
+                index: 6,
+                records: 5
+            })
+        ));
+        assert_eq!(reader.next_record().unwrap().unwrap(), expected[2]);
+    }
+
+    #[test]
+    fn test_truncated_record() {
+        let expected = messages(2);
+        let data = recording(&expected);
+        let mut reader =
+            RecordingReader::new(Cursor::new(data[..data.len() - 4].to_vec())).unwrap();
+
+        assert_eq!(reader.next_record().unwrap().unwrap(), expected[0]);
+        let err = reader.next_record().unwrap_err();
+        assert!(matches!(err, ReaderError::Truncated { index: 1, .. }));
+        assert!(err.to_string().contains("Record 1 at offset"));
+        assert!(reader.next_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_corrupted_record_is_skipped() {
+        let expected = messages(3);
+        let corrupted_offset = recording(&expected[..1]).len();
+        let mut data = recording(&expected);
+        data[corrupted_offset + RECORD_PREFIX_SIZE as usize] ^= 0xFF;
+
+        let results: Vec<_> = RecordingReader::new(Cursor::new(data)).unwrap().collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), &expected[0]);
+        assert!(matches!(
+            results[1],
+            Err(ReaderError::ChecksumMismatch { index: 1, offset, .. })
+                if offset == corrupted_offset as u64
+        ));
+        assert_eq!(results[2].as_ref().unwrap(), &expected[2]);
+    }
+
+    #[test]
+    fn test_corrupted_length_resyncs() {
+        let expected = messages(3);
+        let corrupted_offset = recording(&expected[..1]).len();
+        let mut data = recording(&expected);
+        // Points into the middle of the third record
+        data[corrupted_offset + 7] ^= 0x02;
+
+        let mut reader = RecordingReader::new(Cursor::new(data.clone())).unwrap();
+        let results: Vec<_> = (&mut reader).collect();
+        assert_eq!(results.len(), 3);
+        assert!(matches!(
+            results[1],
+            Err(ReaderError::ChecksumMismatch { index: 1, .. })
+        ));
+        assert_eq!(results[2].as_ref().unwrap(), &expected[2]);
+
+        // A length past the end is not taken for a torn tail
+        data[corrupted_offset..corrupted_offset + 8]
+            .copy_from_slice(&u32::MAX.to_be_bytes().repeat(2));
+        let results: Vec<_> = RecordingReader::new(Cursor::new(data.clone()))
+            .unwrap()
+            .collect();
+        assert!(matches!(
+            results[1],
+            Err(ReaderError::Truncated { index: 1, .. })
+        ));
+        assert_eq!(results[2].as_ref().unwrap(), &expected[2]);
+
+        let mut reader = RecordingReader::new(Cursor::new(data)).unwrap();
+        reader.seek_to_record(2).unwrap();
+        assert_eq!(reader.next_record().unwrap().unwrap(), expected[2]);
+    }
+
+    /// Fails every read past `fail_at`.
+    struct FailingReader {
+        inner: Cursor<Vec<u8>>,
+        fail_at: u64,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.inner.position() >= self.fail_at {
+                return Err(std::io::Error::other("disk gone"));
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for FailingReader {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_io_error_ends_iteration() {
+        let expected = messages(2);
+        let data = recording(&expected);
+        let fail_at = recording(&expected[..1]).len() as u64;
+        let reader = RecordingReader::new(FailingReader {
+            inner: Cursor::new(data),
+            fail_at,
+        })
+        .unwrap();
+        let results: Vec<_> = reader.take(10).collect();
+        assert_eq!(results.len(), 2);
+        assert!(matches!(results[1], Err(ReaderError::IoError(_))));
+    }
+
+    #[test]
+    fn test_malformed_record() {
+        let mut data = FileHeader::new(Codec::Uncompressed, "conn".to_string(), vec![]).encode();
+        data.extend_from_slice(&encode_prefix(b"short"));
+        data.extend_from_slice(b"short");
+
+        let mut reader = RecordingReader::new(Cursor::new(data)).unwrap();
+        assert!(matches!(
+            reader.next_record(),
+            Err(ReaderError::MalformedRecord {
+                index: 0,
+                err: RecordError::Truncated { .. },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_rejects_unknown_version() {
+        let mut data = recording(&messages(1));
+        data[8..10].copy_from_slice(&99u16.to_be_bytes());
+        assert!(matches!(
+            RecordingReader::new(Cursor::new(data)),
+            Err(ReaderError::HeaderError(HeaderError::UnsupportedVersion(
+                99
+            )))
+        ));
+    }
+}
@@ -0,0 +1,298 @@
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+use crate::message_envelope::MessageEnvelope;
+
+/// Size of the frame count that starts every record body.
+const FRAME_COUNT_SIZE: usize = 4;
+/// Size of each per-frame length entry.
+const FRAME_LENGTH_SIZE: usize = 8;
+/// Size of the sequence number and both receive timestamps.
+const FIXED_METADATA_SIZE: usize = 24;
+/// Size of the length in front of the topic and connection strings.
+const STRING_LENGTH_SIZE: usize = 2;
+/// Topic length marking a message that was received without a topic.
+const NO_TOPIC: u16 = u16::MAX;
+
+#[derive(Debug, PartialEq)]
+pub enum RecordError {
+    Truncated { needed: usize, available: usize },
+    TrailingBytes(usize),
+    InvalidUtf8(std::string::FromUtf8Error),
+}
+
+impl std::fmt::Display for RecordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RecordError::Truncated { needed, available } => write!(
+                f,
+                "Record is truncated: needed {} bytes but only {} are available",
+                needed, available
+            ),
+            RecordError::TrailingBytes(count) => {
+                write!(f, "Record has {} unexpected trailing bytes", count)
+            }
+            RecordError::InvalidUtf8(err) => write!(f, "Record string is not utf8: {}", err),
+        }
+    }
+}
+
+/// Cursor over a record body that reports truncation instead of panicking.
+pub(crate) struct BodyReader<'a> {
+    body: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> BodyReader<'a> {
+    pub(crate) fn new(body: &'a [u8]) -> Self {
+        BodyReader { body, offset: 0 }
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], RecordError> {
+        let end = self.offset.saturating_add(len);
+        if self.body.len() < end {
+            return Err(RecordError::Truncated {
+                needed: end,
+                available: self.body.len(),
+            });
+        }
+        let bytes = &self.body[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    pub(crate) fn read_u64(&mut self) -> Result<u64, RecordError> {
+        Ok(BigEndian::read_u64(self.take(8)?))
+    }
+
+    pub(crate) fn read_u32(&mut self) -> Result<u32, RecordError> {
+        Ok(BigEndian::read_u32(self.take(4)?))
+    }
+
+    pub(crate) fn read_string(&mut self, len: u16) -> Result<String, RecordError> {
+        String::from_utf8(self.take(len as usize)?.to_vec()).map_err(RecordError::InvalidUtf8)
+    }
+
+    pub(crate) fn read_u16(&mut self) -> Result<u16, RecordError> {
+        Ok(BigEndian::read_u16(self.take(STRING_LENGTH_SIZE)?))
+    }
+
+    pub(crate) fn remaining(&self) -> &'a [u8] {
+        &self.body[self.offset..]
+    }
+}
+
+/// Truncates a string to the longest char boundary that fits a u16 length.
+pub(crate) fn clamp_string(value: &str) -> &str {
+    let mut end = value.len().min(NO_TOPIC as usize - 1);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+/// Encodes a received message and its metadata into a single record body.
+///
+/// The metadata comes first: big-endian u64 sequence number, wall-clock and
+/// monotonic receive times in nanoseconds, then the topic and connection as
+/// u16 length-prefixed utf8 (a topic length of `u16::MAX` means no topic).
+/// The frames follow in the layout produced by `encode_frames`.
+pub fn encode_envelope(message: &MessageEnvelope) -> Vec<u8> {
+    let topic = message.topic().as_deref().map(clamp_string);
+    let connection = clamp_string(message.connection());
+    let mut body = Vec::with_capacity(
+        FIXED_METADATA_SIZE
+            + 2 * STRING_LENGTH_SIZE
+            + topic.map_or(0, |t| t.len())
+            + connection.len(),
+    );
+    // Writing into a Vec cannot fail
+    body.write_u64::<BigEndian>(*message.sequence()).unwrap();
+    body.write_u64::<BigEndian>(*message.wall_time_ns())
+        .unwrap();
+    body.write_u64::<BigEndian>(*message.monotonic_ns())
+        .unwrap();
+    match topic {
+        Some(topic) => {
+            body.write_u16::<BigEndian>(topic.len() as u16).unwrap();
+            body.extend_from_slice(topic.as_bytes());
+        }
+        None => body.write_u16::<BigEndian>(NO_TOPIC).unwrap(),
+    }
+    body.write_u16::<BigEndian>(connection.len() as u16)
+        .unwrap();
+    body.extend_from_slice(connection.as_bytes());
+    body.extend_from_slice(&encode_frames(message.frames()));
+    body
+}
+
+/// Rebuilds a message from a record body produced by `encode_envelope`.
+pub fn decode_envelope(body: &[u8]) -> Result<MessageEnvelope, RecordError> {
+    let mut reader = BodyReader::new(body);
+    let sequence = reader.read_u64()?;
+    let wall_time_ns = reader.read_u64()?;
+    let monotonic_ns = reader.read_u64()?;
+    let topic = match reader.read_u16()? {
+        NO_TOPIC => None,
+        len => Some(reader.read_string(len)?),
+    };
+    let connection_len = reader.read_u16()?;
+    let connection = reader.read_string(connection_len)?;
+    let frames = decode_frames(reader.remaining())?;
+    Ok(MessageEnvelope::with_timestamps(
+        sequence,
+        wall_time_ns,
+        monotonic_ns,
+        connection,
+        topic,
+        frames,
+    ))
+}
+
+/// Encodes the frames of a multipart message into a single record body.
+///
+/// The layout is a big-endian u32 frame count, followed by a big-endian u64
+/// length for every frame, followed by the frame bytes in order.
+pub fn encode_frames(frames: &[Vec<u8>]) -> Vec<u8> {
+    let data_len: usize = frames.iter().map(|frame| frame.len()).sum();
+    let mut body =
+        Vec::with_capacity(FRAME_COUNT_SIZE + frames.len() * FRAME_LENGTH_SIZE + data_len);
+    // Writing into a Vec cannot fail
+    body.write_u32::<BigEndian>(frames.len() as u32).unwrap();
+    for frame in frames {
+        body.write_u64::<BigEndian>(frame.len() as u64).unwrap();
+    }
+    for frame in frames {
+        body.extend_from_slice(frame);
+    }
+    body
+}
+
+/// Splits a record body produced by `encode_frames` back into its frames.
+pub fn decode_frames(body: &[u8]) -> Result<Vec<Vec<u8>>, RecordError> {
+    if body.len() < FRAME_COUNT_SIZE {
+        return Err(RecordError::Truncated {
+            needed: FRAME_COUNT_SIZE,
+            available: body.len(),
+        });
+    }
+    let frame_count = BigEndian::read_u32(&body[..FRAME_COUNT_SIZE]) as usize;
+    let lengths_end = FRAME_COUNT_SIZE + frame_count * FRAME_LENGTH_SIZE;
+    if body.len() < lengths_end {
+        return Err(RecordError::Truncated {
+            needed: lengths_end,
+            available: body.len(),
+        });
+    }
+
+    let mut frames = Vec::with_capacity(frame_count);
+    let mut offset = lengths_end;
+    for length_bytes in body[FRAME_COUNT_SIZE..lengths_end].chunks_exact(FRAME_LENGTH_SIZE) {
+        // The length comes from the file, so it may be anything
+        let frame_end = usize::try_from(BigEndian::read_u64(length_bytes))
+            .ok()
+            .and_then(|frame_len| offset.checked_add(frame_len))
+            .unwrap_or(usize::MAX);
+        if body.len() < frame_end {
+            return Err(RecordError::Truncated {
+                needed: frame_end,
+                available: body.len(),
+            });
+        }
+        frames.push(body[offset..frame_end].to_vec());
+        offset = frame_end;
+    }
+
+    if offset != body.len() {
+        return Err(RecordError::TrailingBytes(body.len() - offset));
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_envelope() {
+        let message = MessageEnvelope::with_timestamps(
+            42,
+            1_700_000_000_000_000_000,
+            123_456,
+            "tcp://localhost:5555".to_string(),
+            Some("md.prices".to_string()),
+            vec![b"header".to_vec(), b"payload".to_vec()],
+        );
+        let body = encode_envelope(&message);
+        assert_eq!(decode_envelope(&body).unwrap(), message);
+    }
+
+    #[test]
+    fn test_round_trip_envelope_without_topic() {
+        let message = MessageEnvelope::with_timestamps(
+            0,
+            1,
+            2,
+            "conn".to_string(),
+            None,
+            vec![b"payload".to_vec()],
+        );
+        let body = encode_envelope(&message);
+        assert_eq!(decode_envelope(&body).unwrap(), message);
+    }
+
+    #[test]
+    fn test_decode_envelope_truncated_metadata() {
+        let message = MessageEnvelope::with_timestamps(0, 1, 2, "conn".to_string(), None, vec![]);
+        let body = encode_envelope(&message);
+        assert!(matches!(
+            decode_envelope(&body[..10]),
+            Err(RecordError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn test_round_trip_multipart() {
+        let frames = vec![b"header".to_vec(), vec![], b"payload".to_vec()];
+        let body = encode_frames(&frames);
+        assert_eq!(body.len(), 4 + 3 * 8 + 13);
+        assert_eq!(decode_frames(&body).unwrap(), frames);
+    }
+
+    #[test]
+    fn test_frame_boundaries_are_preserved() {
+        let split = encode_frames(&[b"ab".to_vec(), b"c".to_vec()]);
+        let joined = encode_frames(&[b"abc".to_vec()]);
+        assert_ne!(split, joined);
+        assert_eq!(decode_frames(&joined).unwrap(), vec![b"abc".to_vec()]);
+    }
+
+    #[test]
+    fn test_decode_truncated_frame() {
+        let body = encode_frames(&[b"payload".to_vec()]);
+        let result = decode_frames(&body[..body.len() - 1]);
+        assert_eq!(
+            result,
+            Err(RecordError::Truncated {
+                needed: body.len(),
+                available: body.len() - 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_frame_length_overflow() {
+        let mut body = encode_frames(&[b"a".to_vec(), b"payload".to_vec()]);
+        body[12..20].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(
+            decode_frames(&body),
+            Err(RecordError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn test_decode_trailing_bytes() {
+        let mut body = encode_frames(&[b"payload".to_vec()]);
+        body.push(0);
+        assert_eq!(decode_frames(&body), Err(RecordError::TrailingBytes(1)));
+    }
+}
@@ -0,0 +1,370 @@
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use futures::{Sink, SinkExt};
+use log::{info, warn};
+use tmq::Multipart;
+use tokio::time::Instant;
+
+use crate::message_envelope::MessageEnvelope;
+use crate::recording::reader::{ReaderError, RecordingReader};
+
+#[derive(Debug)]
+pub enum ReplayError {
+    ReaderError(ReaderError),
+    TmqError(tmq::TmqError),
+    InvalidSpeed(f64),
+}
+
+impl From<ReaderError> for ReplayError {
+    fn from(err: ReaderError) -> Self {
+        ReplayError::ReaderError(err)
+    }
+}
+
+impl From<tmq::TmqError> for ReplayError {
+    fn from(err: tmq::TmqError) -> Self {
+        ReplayError::TmqError(err)
+    }
+}
+
+impl From<Infallible> for ReplayError {
+    fn from(err: Infallible) -> Self {
+        match err {}
+    }
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReplayError::ReaderError(err) => write!(f, "Failed to read the recording: {}", err),
+            ReplayError::TmqError(err) => write!(f, "Failed to publish: {}", err),
+            ReplayError::InvalidSpeed(speed) => {
+                write!(f, "Replay speed must be above zero, got {}", speed)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// Multiplier on the recorded pace, 2.0 replays twice as fast
+    pub speed: f64,
+    /// Publish as fast as possible, ignoring the recorded timing
+    pub max_rate: bool,
+    /// Start over from the first file after the last one ends
+    pub looping: bool,
+    /// Skip messages recorded less than this long after the first one
+    pub start: Option<Duration>,
+    /// Stop at the first message recorded more than this long after the first one
+    pub stop: Option<Duration>,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        ReplayOptions {
+            speed: 1.0,
+            max_rate: false,
+            looping: false,
+            start: None,
+            stop: None,
+        }
+    }
+}
+
+/// Time that passed between receiving two recorded messages. The monotonic
+/// clock is used unless it went backwards, which happens when a recorder
+/// restart appended to the same file, then the wall clock is used instead.
+pub fn recorded_gap(previous: &MessageEnvelope, next: &MessageEnvelope) -> Duration {
+    let nanos = match next.monotonic_ns().checked_sub(*previous.monotonic_ns()) {
+        Some(nanos) => nanos,
+        None => next.wall_time_ns().saturating_sub(*previous.wall_time_ns()),
+    };
+    Duration::from_nanos(nanos)
+}
+
+/// Rebuilds the multipart message as it was published, topic frame first.
+pub fn to_multipart(message: &MessageEnvelope) -> Multipart {
+    let mut frames: Vec<Vec<u8>> = Vec::with_capacity(message.frames().len() + 1);
+    if let Some(topic) = message.topic() {
+        frames.push(topic.as_bytes().to_vec());
+    }
+    frames.extend(message.frames().iter().cloned());
+    Multipart::from(frames)
+}
+
+/// Counts of one replay run.
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplayStats {
+    pub published: u64,
+    pub skipped: u64,
+    pub damaged: u64,
+}
+
+/// Publishes the messages of `files` on `socket`, treating the files as one
+/// continuous recording, e.g. the output of a rotating sink in order.
+/// Recorded monitor events are not published.
+pub async fn replay<S>(
+    files: &[PathBuf],
+    socket: &mut S,
+    options: &ReplayOptions,
+) -> Result<ReplayStats, ReplayError>
+where
+    S: Sink<Multipart> + Unpin,
+    ReplayError: From<S::Error>,
+{
+    if options.speed.is_nan() || options.speed <= 0.0 {
+        return Err(ReplayError::InvalidSpeed(options.speed));
+    }
+    let mut stats = ReplayStats::default();
+    loop {
+        let published = stats.published;
+        replay_once(files, socket, options, &mut stats).await?;
+        if !options.looping {
+            return Ok(stats);
+        }
+        if stats.published == published {
+            warn!("Nothing to publish, not looping over an empty replay");
+            return Ok(stats);
+        }
+        info!("Reached the end of the recording, starting over");
+    }
+}
+
+async fn replay_once<S>(
+    files: &[PathBuf],
+    socket: &mut S,
+    options: &ReplayOptions,
+    stats: &mut ReplayStats,
+) -> Result<(), ReplayError>
+where
+    S: Sink<Multipart> + Unpin,
+    ReplayError: From<S::Error>,
+{
+    let mut previous: Option<MessageEnvelope> = None;
+    // Recorded time since the first message, and when the first published
+    // message went out together with its recorded offset
+    let mut offset = Duration::ZERO;
+    let mut started: Option<(Instant, Duration)> = None;
+
+    for file in files {
+        let reader = RecordingReader::open(file)?;
+        info!(
+            "Replaying {} recorded from {}",
+            file.display(),
+            reader.header().connection()
+        );
+        for message in reader {
+            let message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    warn!("Skipping damaged record in {}: {}", file.display(), e);
+                    stats.damaged += 1;
+                    continue;
+                }
+            };
+            if let Some(previous) = &previous {
+                offset += recorded_gap(previous, &message);
+            }
+            previous = Some(message.clone());
+
+            if options.stop.is_some_and(|stop| offset > stop) {
+                return Ok(());
+            }
+            if options.start.is_some_and(|start| offset < start) {
+                stats.skipped += 1;
+                continue;
+            }
+            if !options.max_rate {
+                match started {
+                    Some((started_at, first_offset)) => {
+                        let due = (offset - first_offset).div_f64(options.speed);
+                        tokio::time::sleep_until(started_at + due).await;
+                    }
+                    None => started = Some((Instant::now(), offset)),
+                }
+            }
+            socket.send(to_multipart(&message)).await?;
+            stats.published += 1;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_health::MONITOR_TOPIC;
+    use crate::sink::Sink as _;
+    use crate::sinks::file_sink::FileSink;
+    use tempfile::tempdir;
+
+    const SECOND_NS: u64 = 1_000_000_000;
+
+    fn message(sequence: u64, monotonic_s: u64, topic: Option<&str>) -> MessageEnvelope {
+        MessageEnvelope::with_timestamps(
+            sequence,
+            1_700_000_000 * SECOND_NS + monotonic_s * SECOND_NS,
+            monotonic_s * SECOND_NS,
+            "conn".to_string(),
+            topic.map(str::to_string),
+            vec![format!("payload {}", sequence).into_bytes()],
+        )
+    }
+
+    fn write_recording(path: &std::path::Path, messages: &[MessageEnvelope]) {
+        let mut sink = FileSink::new(path.to_str().unwrap().to_string(), 0).unwrap();
+        for message in messages {
+            sink.write(message).unwrap();
+        }
+        sink.flush().unwrap();
+    }
+
+    fn max_rate() -> ReplayOptions {
+        ReplayOptions {
+            max_rate: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_recorded_gap() {
+        assert_eq!(
+            recorded_gap(&message(0, 10, None), &message(1, 12, None)),
+            Duration::from_secs(2)
+        );
+        // A restart resets the monotonic clock, the wall clock still moves on
+        let restarted = MessageEnvelope::with_timestamps(
+            2,
+            1_700_000_000 * SECOND_NS + 15 * SECOND_NS,
+            0,
+            "conn".to_string(),
+            None,
+            vec![],
+        );
+        assert_eq!(
+            recorded_gap(&message(1, 12, None), &restarted),
+            Duration::from_secs(3)
+        );
+    }
+
+    #[test]
+    fn test_to_multipart_puts_topic_first() {
+        assert_eq!(
+            to_multipart(&message(0, 0, Some("md"))),
+            Multipart::from(vec![b"md".to_vec(), b"payload 0".to_vec()])
+        );
+        assert_eq!(
+            to_multipart(&message(0, 0, None)),
+            Multipart::from(vec![b"payload 0".to_vec()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_files_in_order() {
+        let temp_dir = tempdir().unwrap();
+        let first = temp_dir.path().join("first.rec");
+        let second = temp_dir.path().join("second.rec");
+        write_recording(
+            &first,
+            &[message(0, 0, Some("a")), message(1, 1, Some("b"))],
+        );
+        write_recording(&second, &[message(2, 2, Some("a"))]);
+
+        let mut published: Vec<Multipart> = vec![];
+        let stats = replay(&[first, second], &mut published, &max_rate())
+            .await
+            .unwrap();
+
+        assert_eq!(stats.published, 3);
+        assert_eq!(
+            published,
+            vec![
+                to_multipart(&message(0, 0, Some("a"))),
+                to_multipart(&message(1, 1, Some("b"))),
+                to_multipart(&message(2, 2, Some("a"))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_skips_monitor_events() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("events.rec");
+        let messages = [
+            message(0, 0, Some("a")),
+            message(0, 1, Some(MONITOR_TOPIC)),
+            message(1, 2, Some("a")),
+        ];
+        write_recording(&path, &messages);
+
+        let mut published: Vec<Multipart> = vec![];
+        let stats = replay(&[path], &mut published, &max_rate()).await.unwrap();
+
+        assert_eq!(stats.published, 2);
+        assert_eq!(
+            published,
+            vec![to_multipart(&messages[0]), to_multipart(&messages[2])]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_start_and_stop_offsets() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("offsets.rec");
+        let messages: Vec<_> = (0..6).map(|i| message(i, 100 + i * 10, None)).collect();
+        write_recording(&path, &messages);
+
+        let mut published: Vec<Multipart> = vec![];
+        let options = ReplayOptions {
+            start: Some(Duration::from_secs(15)),
+            stop: Some(Duration::from_secs(40)),
+            ..max_rate()
+        };
+        let stats = replay(&[path], &mut published, &options).await.unwrap();
+
+        assert_eq!(stats.skipped, 2);
+        assert_eq!(
+            published,
+            messages[2..5].iter().map(to_multipart).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_honours_timing_and_speed() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("timing.rec");
+        let messages = [
+            message(0, 0, None),
+            MessageEnvelope::with_timestamps(1, 0, 200_000_000, "conn".to_string(), None, vec![]),
+        ];
+        write_recording(&path, &messages);
+
+        let mut published: Vec<Multipart> = vec![];
+        let options = ReplayOptions {
+            speed: 2.0,
+            ..Default::default()
+        };
+        let started = std::time::Instant::now();
+        replay(&[path], &mut published, &options).await.unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(published.len(), 2);
+        assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(190), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_replay_rejects_bad_speed() {
+        let mut published: Vec<Multipart> = vec![];
+        let options = ReplayOptions {
+            speed: 0.0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            replay(&[], &mut published, &options).await,
+            Err(ReplayError::InvalidSpeed(_))
+        ));
+    }
+}
@@ -0,0 +1,192 @@
+use crate::capture_socket::SocketMode;
+
+/// A CURVE key in binary form.
+pub type CurveKey = [u8; 32];
+
+const Z85_ALPHABET: &str =
+    "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ.-:+=^!/*?&<>()[]{}@%$#";
+
+/// Parses a CURVE key in Z85, as written by `zmq_curve_keypair`, or in hex.
+/// Surrounding whitespace is ignored. Errors never repeat the key.
+pub fn parse_curve_key(text: &str) -> Result<CurveKey, String> {
+    let text = text.trim();
+    let decoded = match text.len() {
+        40 if text.chars().all(|c| Z85_ALPHABET.contains(c)) => {
+            zmq::z85_decode(text).map_err(|e| format!("invalid Z85 key: {:?}", e))?
+        }
+        40 => return Err("invalid Z85 key: contains characters outside Z85".to_string()),
+        // Only ASCII can be sliced by byte offsets, and from_str_radix would take a sign
+        64 if text.bytes().all(|b| b.is_ascii_hexdigit()) => (0..64)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|e| format!("invalid hex key: {}", e))?,
+        64 => return Err("invalid hex key: contains non-hex characters".to_string()),
+        len => {
+            return Err(format!(
+                "expected a 40 character Z85 or 64 character hex key, found {} characters",
+                len
+            ))
+        }
+    };
+    decoded
+        .try_into()
+        .map_err(|_| "key does not decode to 32 bytes".to_string())
+}
+
+/// Fails if the linked libzmq was built without CURVE.
+pub fn check_curve_support() -> Result<(), String> {
+    match zmq::has("curve") {
+        Some(true) => Ok(()),
+        _ => Err("this libzmq was built without CURVE support".to_string()),
+    }
+}
+
+/// Security mechanism of a connection's socket.
+#[derive(Clone, PartialEq)]
+pub enum Security {
+    /// Encrypts and authenticates with CURVE keys. A connecting socket needs
+    /// the server's public key, a bound socket acts as the CURVE server.
+    Curve {
+        server_key: Option<CurveKey>,
+        public_key: CurveKey,
+        secret_key: CurveKey,
+    },
+    /// Sends a clear text user name and password, only when connecting
+    Plain { username: String, password: String },
+}
+
+impl Security {
+    pub fn mechanism(&self) -> &'static str {
+        match self {
+            Security::Curve { .. } => "curve",
+            Security::Plain { .. } => "plain",
+        }
+    }
+
+    /// Whether the mechanism can be used with the socket mode.
+    pub fn check_mode(&self, mode: SocketMode) -> Result<(), String> {
+        match (self, mode) {
+            (
+                Security::Curve {
+                    server_key: None, ..
+                },
+                SocketMode::Connect,
+            ) => Err("connecting with CURVE needs the server's public key".to_string()),
+            (Security::Plain { .. }, SocketMode::Bind) => {
+                Err("PLAIN is only supported when connecting".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sets the socket options of the mechanism. Must be called before
+    /// connecting or binding.
+    pub fn apply(&self, socket: &zmq::Socket, mode: SocketMode) -> Result<(), zmq::Error> {
+        match self {
+            Security::Curve {
+                server_key,
+                public_key,
+                secret_key,
+            } => {
+                match (mode, server_key) {
+                    (SocketMode::Bind, _) => socket.set_curve_server(true)?,
+                    (SocketMode::Connect, Some(server_key)) => {
+                        socket.set_curve_serverkey(server_key)?
+                    }
+                    (SocketMode::Connect, None) => return Err(zmq::Error::EINVAL),
+                }
+                socket.set_curve_publickey(public_key)?;
+                socket.set_curve_secretkey(secret_key)
+            }
+            Security::Plain { username, password } => {
+                socket.set_plain_username(Some(username))?;
+                socket.set_plain_password(Some(password))
+            }
+        }
+    }
+}
+
+/// Leaves out secret keys and passwords, connections are logged with `{:?}`.
+impl std::fmt::Debug for Security {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let z85 = |key: &CurveKey| zmq::z85_encode(key).unwrap_or_default();
+        match self {
+            Security::Curve {
+                server_key,
+                public_key,
+                ..
+            } => f
+                .debug_struct("Curve")
+                .field("server_key", &server_key.as_ref().map(z85))
+                .field("public_key", &z85(public_key))
+                .field("secret_key", &"<redacted>")
+                .finish(),
+            Security::Plain { username, .. } => f
+                .debug_struct("Plain")
+                .field("username", username)
+                .field("password", &"<redacted>")
+                .finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // The key pairs of the CURVE examples in the ZMQ guide
+    pub(crate) const SERVER_PUBLIC: &str = "rq:rM>}U?@Lns47E1%kR.o@n%FcmmsL/@{H8]yf7";
+    pub(crate) const SERVER_SECRET: &str = "JTKVSB%%)wK0E.X)V>+}o?pNmC{O&4W4b!Ni{Lh6";
+    pub(crate) const CLIENT_PUBLIC: &str = "Yne@$w-vo<fVvi]a<NY6T1ed:M$fCG*[IaLV{hID";
+    pub(crate) const CLIENT_SECRET: &str = "D:)Q[IlAW!ahhC2ac:9*A}h:p?([4%wOTJ%JR%cs";
+
+    pub(crate) fn key(z85: &str) -> CurveKey {
+        zmq::z85_decode(z85).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_parse_curve_key() {
+        let z85 = CLIENT_PUBLIC;
+        assert_eq!(
+            parse_curve_key(&format!("  {}\n", z85)).unwrap(),
+            key(CLIENT_PUBLIC)
+        );
+        let hex: String = key(CLIENT_SECRET)
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        assert_eq!(parse_curve_key(&hex).unwrap(), key(CLIENT_SECRET));
+
+        assert!(parse_curve_key(&z85[..39])
+            .unwrap_err()
+            .contains("found 39 characters"));
+        assert!(parse_curve_key(&format!("{}\"", &z85[..39])).is_err());
+        assert!(parse_curve_key(&"g".repeat(64)).is_err());
+        // 64 bytes, but multibyte characters must not be split
+        assert!(parse_curve_key(&format!("{}é", &hex[..62]))
+            .unwrap_err()
+            .contains("non-hex"));
+        assert!(parse_curve_key(&format!("+1{}", &hex[2..])).is_err());
+    }
+
+    #[test]
+    fn test_debug_redacts_secrets() {
+        let curve = Security::Curve {
+            server_key: None,
+            public_key: [0; 32],
+            secret_key: [7; 32],
+        };
+        let plain = Security::Plain {
+            username: "recorder".to_string(),
+            password: "hunter2".to_string(),
+        };
+        let shown = format!("{:?} {:?}", curve, plain);
+        assert!(shown.contains("recorder"));
+        assert!(!shown.contains("hunter2"));
+        assert!(!shown.contains(&zmq::z85_encode(&[7; 32]).unwrap()));
+        assert!(plain.check_mode(SocketMode::Bind).is_err());
+        assert!(curve.check_mode(SocketMode::Connect).is_err());
+        assert!(curve.check_mode(SocketMode::Bind).is_ok());
+    }
+}
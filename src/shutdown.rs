@@ -0,0 +1,97 @@
+use log::info;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+/// Handed to every long running task so it can notice a shutdown request.
+#[derive(Debug, Clone)]
+pub struct ShutdownSignal {
+    receiver: watch::Receiver<bool>,
+}
+
+impl ShutdownSignal {
+    /// Resolves once shutdown has been triggered, or the coordinator is gone.
+    pub async fn wait(&mut self) {
+        let _ = self.receiver.wait_for(|triggered| *triggered).await;
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+}
+
+/// Fans a single shutdown request out to every subscribed task.
+#[derive(Debug)]
+pub struct ShutdownCoordinator {
+    sender: watch::Sender<bool>,
+}
+
+impl Default for ShutdownCoordinator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownCoordinator {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self { sender }
+    }
+
+    pub fn subscribe(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            receiver: self.sender.subscribe(),
+        }
+    }
+
+    pub fn trigger(&self) {
+        info!("Shutdown requested, stopping all connections");
+        self.sender.send_replace(true);
+    }
+
+    /// Waits for SIGINT or SIGTERM.
+    pub async fn wait_for_signal() -> std::io::Result<()> {
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                result?;
+                info!("Received SIGINT");
+            }
+            _ = terminate.recv() => info!("Received SIGTERM"),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_trigger_wakes_every_subscriber() {
+        let coordinator = ShutdownCoordinator::new();
+        let mut first = coordinator.subscribe();
+        let mut second = coordinator.subscribe();
+        assert!(!first.is_triggered());
+
+        coordinator.trigger();
+
+        tokio::time::timeout(Duration::from_secs(1), first.wait())
+            .await
+            .expect("First subscriber was not woken");
+        tokio::time::timeout(Duration::from_secs(1), second.wait())
+            .await
+            .expect("Second subscriber was not woken");
+        assert!(second.is_triggered());
+    }
+
+    #[tokio::test]
+    async fn test_late_subscriber_sees_trigger() {
+        let coordinator = ShutdownCoordinator::new();
+        coordinator.trigger();
+        let mut late = coordinator.subscribe();
+        tokio::time::timeout(Duration::from_secs(1), late.wait())
+            .await
+            .expect("Late subscriber was not woken");
+    }
+}
@@ -1,6 +1,8 @@
+use crate::message_envelope::MessageEnvelope;
 use crate::sinks::compressed_file_sink::CompressedFileSink;
 use crate::sinks::console_sink::ConsoleSink;
 use crate::sinks::file_sink::FileSink;
+use crate::sinks::json_lines_sink::JsonLinesSink;
 use crate::sinks::message_counter::MessageCounter;
 
 #[derive(Debug)]
@@ -14,6 +16,14 @@ impl From<std::io::Error> for SinkError {
     }
 }
 
+impl From<SinkError> for std::io::Error {
+    fn from(err: SinkError) -> std::io::Error {
+        match err {
+            SinkError::IoError(err) => err,
+        }
+    }
+}
+
 impl std::fmt::Display for SinkError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -23,8 +33,13 @@ impl std::fmt::Display for SinkError {
 }
 
 pub trait Sink: Sync + Send {
-    fn write(&mut self, data: &Vec<u8>) -> Result<(), SinkError>;
+    fn write(&mut self, message: &MessageEnvelope) -> Result<(), SinkError>;
     fn flush(&mut self) -> Result<(), SinkError>;
+    /// Called by the sink worker about once a second, also while no messages
+    /// arrive, so time based work like flushing happens on time.
+    fn tick(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -32,5 +47,38 @@ pub enum SinksEnum {
     ConsoleSink(ConsoleSink),
     FileSink(FileSink),
     CompressedFileSink(CompressedFileSink),
+    JsonLinesSink(JsonLinesSink),
     MessageCounter(MessageCounter),
 }
+
+impl Sink for SinksEnum {
+    fn write(&mut self, message: &MessageEnvelope) -> Result<(), SinkError> {
+        match self {
+            SinksEnum::ConsoleSink(s) => s.write(message),
+            SinksEnum::FileSink(s) => s.write(message),
+            SinksEnum::CompressedFileSink(s) => s.write(message),
+            SinksEnum::JsonLinesSink(s) => s.write(message),
+            SinksEnum::MessageCounter(s) => s.write(message),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        match self {
+            SinksEnum::ConsoleSink(s) => s.flush(),
+            SinksEnum::FileSink(s) => s.flush(),
+            SinksEnum::CompressedFileSink(s) => s.flush(),
+            SinksEnum::JsonLinesSink(s) => s.flush(),
+            SinksEnum::MessageCounter(s) => s.flush(),
+        }
+    }
+
+    fn tick(&mut self) -> Result<(), SinkError> {
+        match self {
+            SinksEnum::ConsoleSink(s) => s.tick(),
+            SinksEnum::FileSink(s) => s.tick(),
+            SinksEnum::CompressedFileSink(s) => s.tick(),
+            SinksEnum::JsonLinesSink(s) => s.tick(),
+            SinksEnum::MessageCounter(s) => s.tick(),
+        }
+    }
+}
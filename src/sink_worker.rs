@@ -0,0 +1,465 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinHandle;
+
+use crate::message_envelope::MessageEnvelope;
+use crate::metrics::SinkMetrics;
+use crate::sink::{Sink, SinkError, SinksEnum};
+
+pub const DEFAULT_QUEUE_SIZE: usize = 1024;
+/// How often a worker calls `Sink::tick`.
+pub const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a sink queue does with a new message when it is already full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// Wait for the sink to catch up, pushing back on the receive loop
+    Block,
+    /// Discard the message that did not fit
+    DropNewest,
+    /// Discard the oldest queued message to make room
+    DropOldest,
+}
+
+impl TryFrom<&str> for OverflowPolicy {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            _ => Err(format!(
+                "Unknown overflow policy '{}', expected block, drop-newest or drop-oldest",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum SinkCommand {
+    Write(Arc<MessageEnvelope>),
+    Flush(oneshot::Sender<Result<(), SinkError>>),
+}
+
+/// What a worker waiting on its queue woke up for.
+#[derive(Debug)]
+enum Popped {
+    Command(SinkCommand),
+    /// The deadline passed with nothing queued
+    Idle,
+    /// The queue is closed and drained
+    Closed,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    items: VecDeque<SinkCommand>,
+    closed: bool,
+}
+
+/// Bounded hand-off between an async receive loop and a blocking sink worker.
+#[derive(Debug)]
+pub struct SinkQueue {
+    sink_name: String,
+    state: Mutex<QueueState>,
+    item_available: Condvar,
+    space_available: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: AtomicU64,
+    metrics: SinkMetrics,
+}
+
+impl SinkQueue {
+    fn new(
+        sink_name: String,
+        capacity: usize,
+        policy: OverflowPolicy,
+        metrics: SinkMetrics,
+    ) -> Self {
+        Self {
+            sink_name,
+            state: Mutex::new(QueueState::default()),
+            item_available: Condvar::new(),
+            space_available: Notify::new(),
+            capacity: capacity.max(1),
+            policy,
+            dropped: AtomicU64::new(0),
+            metrics,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        // The lock is never held while a sink runs, so a poisoned state is still consistent
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Queues a message for the sink, applying the overflow policy if the queue is full.
+    pub async fn push(&self, message: Arc<MessageEnvelope>) {
+        loop {
+            let space_available = self.space_available.notified();
+            {
+                let mut state = self.lock();
+                if state.closed {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    self.metrics.dropped.inc();
+                    return;
+                }
+                if state.items.len() < self.capacity {
+                    state.items.push_back(SinkCommand::Write(message));
+                    self.item_available.notify_one();
+                    return;
+                }
+                match self.policy {
+                    OverflowPolicy::Block => {}
+                    OverflowPolicy::DropNewest => {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        self.metrics.dropped.inc();
+                        debug!(
+                            "Queue for {} is full, dropped message {}",
+                            self.sink_name,
+                            message.sequence()
+                        );
+                        return;
+                    }
+                    OverflowPolicy::DropOldest => {
+                        let oldest = state
+                            .items
+                            .iter()
+                            .position(|item| matches!(item, SinkCommand::Write(_)));
+                        if let Some(index) = oldest {
+                            state.items.remove(index);
+                            self.dropped.fetch_add(1, Ordering::Relaxed);
+                            self.metrics.dropped.inc();
+                            debug!(
+                                "Queue for {} is full, dropped the oldest queued message",
+                                self.sink_name
+                            );
+                        }
+                        state.items.push_back(SinkCommand::Write(message));
+                        self.item_available.notify_one();
+                        return;
+                    }
+                }
+            }
+            space_available.await;
+        }
+    }
+
+    /// Waits until every message queued before this call has been written and the sink flushed.
+    pub async fn flush(&self) -> Result<(), SinkError> {
+        let (sender, receiver) = oneshot::channel();
+        if self.push_command(SinkCommand::Flush(sender)).is_err() {
+            return Err(worker_stopped_error(&self.sink_name));
+        }
+        match receiver.await {
+            Ok(result) => result,
+            Err(_) => Err(worker_stopped_error(&self.sink_name)),
+        }
+    }
+
+    /// Control commands skip the capacity check so they are never dropped.
+    fn push_command(&self, command: SinkCommand) -> Result<(), SinkCommand> {
+        let mut state = self.lock();
+        if state.closed {
+            return Err(command);
+        }
+        state.items.push_back(command);
+        self.item_available.notify_one();
+        Ok(())
+    }
+
+    /// Blocks until there is something to do or `deadline` passed.
+    fn pop(&self, deadline: Instant) -> Popped {
+        let mut state = self.lock();
+        loop {
+            if let Some(command) = state.items.pop_front() {
+                drop(state);
+                self.space_available.notify_one();
+                return Popped::Command(command);
+            }
+            if state.closed {
+                return Popped::Closed;
+            }
+            let Some(timeout) = deadline.checked_duration_since(Instant::now()) else {
+                return Popped::Idle;
+            };
+            state = self
+                .item_available
+                .wait_timeout(state, timeout)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.item_available.notify_all();
+    }
+
+    pub fn queued_messages(&self) -> usize {
+        self.lock().items.len()
+    }
+
+    pub fn dropped_messages(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn sink_name(&self) -> &String {
+        &self.sink_name
+    }
+}
+
+fn worker_stopped_error(sink_name: &str) -> SinkError {
+    SinkError::IoError(std::io::Error::other(format!(
+        "Worker for sink {} is not running",
+        sink_name
+    )))
+}
+
+/// Runs one sink on its own blocking task, fed through a bounded queue.
+#[derive(Debug)]
+pub struct SinkWorker {
+    name: String,
+    queue: Arc<SinkQueue>,
+    handle: Option<JoinHandle<Result<(), SinkError>>>,
+}
+
+impl SinkWorker {
+    /// Starts the worker. Must be called from within a tokio runtime.
+    pub fn spawn(
+        name: String,
+        sink: Box<SinksEnum>,
+        queue_size: usize,
+        policy: OverflowPolicy,
+    ) -> Self {
+        SinkWorker::spawn_with_metrics(name, sink, queue_size, policy, SinkMetrics::default())
+    }
+
+    /// Starts the worker, counting what it writes and drops in `metrics`.
+    pub fn spawn_with_metrics(
+        name: String,
+        sink: Box<SinksEnum>,
+        queue_size: usize,
+        policy: OverflowPolicy,
+        metrics: SinkMetrics,
+    ) -> Self {
+        let queue = Arc::new(SinkQueue::new(name.clone(), queue_size, policy, metrics));
+        let worker_queue = queue.clone();
+        let worker_name = name.clone();
+        let handle =
+            tokio::task::spawn_blocking(move || run_worker(&worker_name, sink, &worker_queue));
+        Self {
+            name,
+            queue,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn queue(&self) -> &Arc<SinkQueue> {
+        &self.queue
+    }
+
+    pub fn dropped_messages(&self) -> u64 {
+        self.queue.dropped_messages()
+    }
+
+    pub async fn flush(&self) -> Result<(), SinkError> {
+        self.queue.flush().await
+    }
+
+    /// Writes out everything still queued, flushes and drops the sink.
+    pub async fn close(mut self) -> Result<(), SinkError> {
+        self.queue.close();
+        let result = match self.handle.take() {
+            Some(handle) => match handle.await {
+                Ok(result) => result,
+                Err(e) => Err(SinkError::IoError(std::io::Error::other(format!(
+                    "Worker for sink {} panicked: {}",
+                    self.name, e
+                )))),
+            },
+            None => Ok(()),
+        };
+        info!(
+            "Closed sink {}, {} messages were dropped",
+            self.name,
+            self.dropped_messages()
+        );
+        result
+    }
+}
+
+impl Drop for SinkWorker {
+    fn drop(&mut self) {
+        // Lets a detached worker drain its queue and exit instead of waiting forever
+        self.queue.close();
+    }
+}
+
+fn run_worker(
+    sink_name: &str,
+    mut sink: Box<SinksEnum>,
+    queue: &SinkQueue,
+) -> Result<(), SinkError> {
+    let mut next_tick = Instant::now() + TICK_INTERVAL;
+    loop {
+        match queue.pop(next_tick) {
+            Popped::Command(SinkCommand::Write(message)) => {
+                if let Err(e) = sink.write(&message) {
+                    queue.metrics.write_errors.inc();
+                    error!(
+                        "Sink {} failed to write message {}: {}",
+                        sink_name,
+                        message.sequence(),
+                        e
+                    );
+                } else {
+                    queue.metrics.messages.inc();
+                    queue.metrics.bytes.inc_by(message.payload_len() as u64);
+                }
+            }
+            Popped::Command(SinkCommand::Flush(reply)) => {
+                // The caller may have stopped waiting, which is fine
+                let _ = reply.send(sink.flush());
+            }
+            Popped::Idle => {}
+            Popped::Closed => break,
+        }
+        // Checked after every command too, so a busy queue does not hold back the tick
+        if Instant::now() >= next_tick {
+            if let Err(e) = sink.tick() {
+                error!("Sink {} failed its periodic work: {}", sink_name, e);
+            }
+            next_tick = Instant::now() + TICK_INTERVAL;
+        }
+    }
+    sink.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::message_counter::MessageCounter;
+    use std::time::Duration;
+
+    fn message(sequence: u64) -> Arc<MessageEnvelope> {
+        Arc::new(MessageEnvelope::new(
+            sequence,
+            "conn".to_string(),
+            None,
+            vec![vec![0u8; 4]],
+        ))
+    }
+
+    fn queued_sequences(queue: &SinkQueue) -> Vec<u64> {
+        queue
+            .lock()
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                SinkCommand::Write(message) => Some(*message.sequence()),
+                SinkCommand::Flush(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_overflow_policy_from_str() {
+        assert_eq!(OverflowPolicy::try_from("block"), Ok(OverflowPolicy::Block));
+        assert_eq!(
+            OverflowPolicy::try_from("drop-newest"),
+            Ok(OverflowPolicy::DropNewest)
+        );
+        assert_eq!(
+            OverflowPolicy::try_from("drop-oldest"),
+            Ok(OverflowPolicy::DropOldest)
+        );
+        assert!(OverflowPolicy::try_from("drop").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_drop_newest_keeps_queued_messages() {
+        let queue = SinkQueue::new(
+            "test".to_string(),
+            2,
+            OverflowPolicy::DropNewest,
+            crate::metrics::RecorderMetrics::new().sink("conn", "test"),
+        );
+        for sequence in 0..4 {
+            queue.push(message(sequence)).await;
+        }
+        assert_eq!(queued_sequences(&queue), vec![0, 1]);
+        assert_eq!(queue.dropped_messages(), 2);
+        assert_eq!(queue.metrics.dropped.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_keeps_latest_messages() {
+        let queue = SinkQueue::new(
+            "test".to_string(),
+            2,
+            OverflowPolicy::DropOldest,
+            SinkMetrics::default(),
+        );
+        for sequence in 0..4 {
+            queue.push(message(sequence)).await;
+        }
+        assert_eq!(queued_sequences(&queue), vec![2, 3]);
+        assert_eq!(queue.dropped_messages(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_block_waits_for_space() {
+        let queue = Arc::new(SinkQueue::new(
+            "test".to_string(),
+            1,
+            OverflowPolicy::Block,
+            SinkMetrics::default(),
+        ));
+        queue.push(message(0)).await;
+
+        let blocked_queue = queue.clone();
+        let blocked = tokio::spawn(async move { blocked_queue.push(message(1)).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished());
+
+        assert!(matches!(
+            queue.pop(Instant::now()),
+            Popped::Command(SinkCommand::Write(_))
+        ));
+        tokio::time::timeout(Duration::from_secs(1), blocked)
+            .await
+            .expect("Push was not woken")
+            .unwrap();
+        assert_eq!(queued_sequences(&queue), vec![1]);
+        assert_eq!(queue.dropped_messages(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_worker_writes_flushes_and_closes() {
+        let worker = SinkWorker::spawn(
+            "counter".to_string(),
+            Box::new(SinksEnum::MessageCounter(MessageCounter::new())),
+            8,
+            OverflowPolicy::Block,
+        );
+        for sequence in 0..3 {
+            worker.queue().push(message(sequence)).await;
+        }
+        worker.flush().await.unwrap();
+        assert_eq!(worker.queue().queued_messages(), 0);
+        worker.close().await.unwrap();
+    }
+}
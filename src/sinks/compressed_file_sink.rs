@@ -1,39 +1,206 @@
 use std::io::Write;
+use std::time::{Duration, Instant};
 
 use log::{debug, error};
 
+use crate::message_envelope::MessageEnvelope;
+use crate::recording::block::BlockEncoder;
+use crate::recording::header::{Codec, MAX_DICTIONARY_SIZE};
+use crate::recording::index::IndexKey;
+use crate::recording::record;
 use crate::sink::{Sink, SinkError};
-use crate::sinks::file_sink::FileSink;
+use crate::sinks::file_sink::{FileSink, FileSinkOptions};
 
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use getset::Getters;
 
+/// Uncompressed bytes after which a block is written even if its flush time
+/// has not passed yet.
+pub const BLOCK_TARGET_INPUT: u64 = 1024 * 1024;
+
+/// How long a stream block stays open when `flush_time` is 0, so blocks
+/// still span many messages instead of one each.
+pub const DEFAULT_BLOCK_TIME: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CompressionCodec {
+    #[default]
+    Gzip,
+    /// Best ratio for small messages, especially with a trained dictionary
+    Zstd,
+    /// Fastest, for connections where gzip cannot keep up
+    Lz4,
+}
+
+impl CompressionCodec {
+    /// Levels the codec accepts. Level 0 of lz4 is its default fast mode,
+    /// 1 to 12 select its high compression mode.
+    pub fn level_range(&self) -> std::ops::RangeInclusive<i32> {
+        match self {
+            CompressionCodec::Gzip => {
+                Compression::fast().level() as i32..=Compression::best().level() as i32
+            }
+            CompressionCodec::Zstd => zstd::compression_level_range(),
+            CompressionCodec::Lz4 => 0..=12,
+        }
+    }
+
+    /// Level used when the config does not set one.
+    pub fn default_level(&self) -> i32 {
+        match self {
+            CompressionCodec::Gzip => 1,
+            CompressionCodec::Zstd => zstd::DEFAULT_COMPRESSION_LEVEL,
+            CompressionCodec::Lz4 => 0,
+        }
+    }
+}
+
+impl TryFrom<&str> for CompressionCodec {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "gzip" => Ok(CompressionCodec::Gzip),
+            "zstd" => Ok(CompressionCodec::Zstd),
+            "lz4" => Ok(CompressionCodec::Lz4),
+            _ => Err(format!(
+                "Unknown codec '{}', expected gzip, zstd or lz4",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CompressionMode {
+    /// Every message is compressed on its own
+    #[default]
+    PerMessage,
+    /// One deflate stream across messages, written in blocks that can be
+    /// decompressed independently. Much smaller for small, similar messages.
+    /// Only available with gzip
+    Stream,
+}
+
+impl TryFrom<&str> for CompressionMode {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "per-message" => Ok(CompressionMode::PerMessage),
+            "stream" => Ok(CompressionMode::Stream),
+            _ => Err(format!(
+                "Unknown compression mode '{}', expected per-message or stream",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressionOptions {
+    pub codec: CompressionCodec,
+    pub level: i32,
+    pub mode: CompressionMode,
+    /// zstd dictionary, e.g. from `message-recorder train-dictionary`. It is
+    /// stored in the file header so readers do not need a copy
+    pub dictionary: Vec<u8>,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        CompressionOptions {
+            codec: CompressionCodec::default(),
+            level: CompressionCodec::default().default_level(),
+            mode: CompressionMode::default(),
+            dictionary: vec![],
+        }
+    }
+}
+
+/// Compression state kept between messages.
+enum MessageEncoder {
+    Gzip,
+    /// Open block of the stream mode
+    Stream(BlockEncoder),
+    Zstd(zstd::bulk::Compressor<'static>),
+    Lz4(lz4::block::CompressionMode),
+}
+
+impl std::fmt::Debug for MessageEncoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MessageEncoder::Gzip => write!(f, "Gzip"),
+            MessageEncoder::Stream(encoder) => f.debug_tuple("Stream").field(encoder).finish(),
+            MessageEncoder::Zstd(_) => write!(f, "Zstd"),
+            MessageEncoder::Lz4(mode) => f.debug_tuple("Lz4").field(mode).finish(),
+        }
+    }
+}
+
 #[derive(Debug, Getters)]
 pub struct CompressedFileSink {
     file_sink: FileSink,
     #[get = "pub"]
     compression_level: i32,
+    #[get = "pub"]
+    codec: CompressionCodec,
+    #[get = "pub"]
+    mode: CompressionMode,
+    encoder: MessageEncoder,
+    /// When the first message of the open stream block arrived, and the key
+    /// of its first message that is not a monitor event
+    block_started: Option<(Instant, Option<IndexKey>)>,
+    block_time: Duration,
 }
 
 impl Sink for CompressedFileSink {
-    fn write(&mut self, data: &Vec<u8>) -> Result<(), SinkError> {
-        let mut _encoder =
-            GzEncoder::new(Vec::new(), Compression::new(self.compression_level as u32));
-        _encoder.write_all(data)?;
-        match _encoder.finish() {
+    fn write(&mut self, message: &MessageEnvelope) -> Result<(), SinkError> {
+        let body = record::encode_envelope(message);
+        if let MessageEncoder::Stream(encoder) = &self.encoder {
+            if !encoder.fits(body.len()) {
+                self.end_block()?;
+            }
+        }
+        let compressed = match &mut self.encoder {
+            MessageEncoder::Stream(encoder) => {
+                encoder.push(&body)?;
+                let (started, key) = self
+                    .block_started
+                    .get_or_insert_with(|| (Instant::now(), None));
+                if key.is_none() {
+                    *key = IndexKey::of(message);
+                }
+                let started = *started;
+                if encoder.input_bytes() >= BLOCK_TARGET_INPUT
+                    || started.elapsed() >= self.block_time
+                {
+                    self.end_block()?;
+                }
+                return Ok(());
+            }
+            MessageEncoder::Gzip => {
+                let mut _encoder =
+                    GzEncoder::new(Vec::new(), Compression::new(self.compression_level as u32));
+                _encoder.write_all(&body)?;
+                _encoder.finish()
+            }
+            MessageEncoder::Zstd(compressor) => compressor.compress(&body),
+            MessageEncoder::Lz4(mode) => lz4::block::compress(&body, Some(*mode), true),
+        };
+        match compressed {
             Ok(res) => {
                 debug!(
                     "Compressed the message from size {} to size {}",
-                    data.len(),
+                    body.len(),
                     res.len()
                 );
-                self.file_sink.write(&res)
+                self.file_sink.write_record(&res, IndexKey::of(message))
             }
             Err(e) => {
                 error!("Failed with error {}", e);
-                Err(SinkError::IoError(std::io::Error::new(
-                    std::io::ErrorKind::Other,
+                Err(SinkError::IoError(std::io::Error::other(
                     "Failed to compress data",
                 )))
             }
@@ -41,35 +208,169 @@ impl Sink for CompressedFileSink {
     }
 
     fn flush(&mut self) -> Result<(), SinkError> {
+        self.end_block()?;
         self.file_sink.flush()
     }
+
+    /// Writes a stream block whose flush time passed while no message arrived.
+    fn tick(&mut self) -> Result<(), SinkError> {
+        if let Some((started, _)) = self.block_started {
+            if started.elapsed() >= self.block_time {
+                self.end_block()?;
+            }
+        }
+        self.file_sink.tick()
+    }
+}
+
+impl Drop for CompressedFileSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.end_block() {
+            error!(
+                "Failed to write the last block of {}: {}",
+                self.filename(),
+                e
+            );
+        }
+    }
 }
 
 impl CompressedFileSink {
+    /// Gzips every message on its own.
     pub fn new(
         filename: String,
         flush_time_s: i32,
         compression_level: i32,
     ) -> std::io::Result<Self> {
-        if (compression_level as u32) < Compression::level(&Compression::fast())
-            || (compression_level as u32) > Compression::level(&Compression::best())
-        {
-            error!("Failed to create the CompressedFileSink with filename:{}, flush_time_s:{}, compression_leve:{}", filename, flush_time_s, compression_level);
+        CompressedFileSink::new_with_options(
+            filename,
+            FileSinkOptions {
+                flush_time_s,
+                ..Default::default()
+            },
+            CompressionOptions {
+                level: compression_level,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// In stream mode a block is written once `flush_time_s`, or
+    /// `DEFAULT_BLOCK_TIME` if that is 0, passed since its first message, or
+    /// once it holds `BLOCK_TARGET_INPUT` bytes.
+    pub fn new_with_options(
+        filename: String,
+        mut options: FileSinkOptions,
+        compression: CompressionOptions,
+    ) -> std::io::Result<Self> {
+        let CompressionOptions {
+            codec,
+            level: compression_level,
+            mode,
+            dictionary,
+        } = compression;
+        let level_range = codec.level_range();
+        if !level_range.contains(&compression_level) {
+            error!("Failed to create the CompressedFileSink with filename:{}, flush_time_s:{}, compression_level:{}", filename, options.flush_time_s, compression_level);
             return Err(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "Bad compression value",
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Bad compression value {} for {:?}, expected {} to {}",
+                    compression_level,
+                    codec,
+                    level_range.start(),
+                    level_range.end()
+                ),
             ));
         }
-        let f_sink = FileSink::new(filename.clone(), flush_time_s)?;
+        if mode == CompressionMode::Stream && codec != CompressionCodec::Gzip {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("The stream compression mode needs gzip, not {:?}", codec),
+            ));
+        }
+        if dictionary.len() > MAX_DICTIONARY_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "The dictionary has {} bytes, readers take at most {}",
+                    dictionary.len(),
+                    MAX_DICTIONARY_SIZE
+                ),
+            ));
+        }
+        if !dictionary.is_empty() && codec != CompressionCodec::Zstd {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Dictionaries need zstd, not {:?}", codec),
+            ));
+        }
+
+        let (header_codec, encoder) = match (codec, mode) {
+            (CompressionCodec::Gzip, CompressionMode::PerMessage) => {
+                (Codec::Gzip, MessageEncoder::Gzip)
+            }
+            (CompressionCodec::Gzip, CompressionMode::Stream) => (
+                Codec::DeflateStream,
+                MessageEncoder::Stream(BlockEncoder::new(compression_level as u32)),
+            ),
+            (CompressionCodec::Zstd, _) => (
+                Codec::Zstd,
+                MessageEncoder::Zstd(zstd::bulk::Compressor::with_dictionary(
+                    compression_level,
+                    &dictionary,
+                )?),
+            ),
+            (CompressionCodec::Lz4, _) => {
+                let lz4_mode = match compression_level {
+                    0 => lz4::block::CompressionMode::DEFAULT,
+                    level => lz4::block::CompressionMode::HIGHCOMPRESSION(level),
+                };
+                (Codec::Lz4, MessageEncoder::Lz4(lz4_mode))
+            }
+        };
+        options.codec = header_codec;
+        options.dictionary = dictionary;
+        let block_time = match options.flush_time_s {
+            flush_time_s if flush_time_s > 0 => Duration::from_secs(flush_time_s as u64),
+            _ => DEFAULT_BLOCK_TIME,
+        };
+        let f_sink = FileSink::new_with_options(filename, options)?;
         Ok(CompressedFileSink {
             file_sink: f_sink,
-            compression_level: compression_level,
+            compression_level,
+            codec,
+            mode,
+            encoder,
+            block_started: None,
+            block_time,
         })
     }
 
     pub fn filename(&self) -> &String {
         self.file_sink.filename()
     }
+
+    /// Writes the open block of the stream mode, if it holds any messages.
+    fn end_block(&mut self) -> Result<(), SinkError> {
+        let MessageEncoder::Stream(encoder) = &mut self.encoder else {
+            return Ok(());
+        };
+        let Some((_, key)) = self.block_started.take() else {
+            return Ok(());
+        };
+        let input_bytes = encoder.input_bytes();
+        if let Some((block, messages)) = encoder.finish_block()? {
+            debug!(
+                "Compressed a block of {} messages from size {} to size {}",
+                messages,
+                input_bytes,
+                block.len()
+            );
+            self.file_sink.write_block(&block, messages, key)?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -100,7 +401,8 @@ mod tests {
         let mut sink = CompressedFileSink::new(file_path_str.clone(), 5, 5).unwrap();
 
         let data = b"Hello, world!".to_vec();
-        let write_result = sink.write(&data);
+        let message = MessageEnvelope::new(0, "conn".to_string(), None, vec![data.clone()]);
+        let write_result = sink.write(&message);
         assert!(
             write_result.is_ok(),
             "Write operation failed on CompressedFileSink"
@@ -120,6 +422,296 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_compressed_file_sink_write_round_trip() {
+        use crate::recording::framing;
+        use crate::recording::header::FileHeader;
+        use byteorder::{BigEndian, ReadBytesExt};
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("test_file.gz");
+        let file_path_str = file_path.to_str().unwrap().to_string();
+
+        let mut sink = CompressedFileSink::new(file_path_str.clone(), 5, 5).unwrap();
+        let message = MessageEnvelope::new(
+            7,
+            "conn".to_string(),
+            Some("topic".to_string()),
+            vec![b"header".to_vec(), b"payload".to_vec()],
+        );
+        sink.write(&message).unwrap();
+        sink.flush().unwrap();
+
+        let mut file = fs::File::open(file_path).unwrap();
+        let (header, _) = FileHeader::read_from(&mut file).unwrap();
+        assert_eq!(*header.codec(), Codec::Gzip);
+        let size = file.read_u64::<BigEndian>().unwrap();
+        let checksum = file.read_u32::<BigEndian>().unwrap();
+        let mut compressed = vec![0u8; size as usize];
+        file.read_exact(&mut compressed).unwrap();
+        assert_eq!(framing::record_checksum(size, &compressed), checksum);
+        let mut body = vec![];
+        GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut body)
+            .unwrap();
+
+        assert_eq!(record::decode_envelope(&body).unwrap(), message);
+    }
+
+    fn stream_sink(path: &std::path::Path, flush_time_s: i32) -> CompressedFileSink {
+        let options = FileSinkOptions {
+            flush_time_s,
+            ..Default::default()
+        };
+        let compression = CompressionOptions {
+            level: 6,
+            mode: CompressionMode::Stream,
+            ..Default::default()
+        };
+        CompressedFileSink::new_with_options(
+            path.to_str().unwrap().to_string(),
+            options,
+            compression,
+        )
+        .unwrap()
+    }
+
+    fn small_messages(count: u64) -> Vec<MessageEnvelope> {
+        (0..count)
+            .map(|sequence| {
+                MessageEnvelope::new(
+                    sequence,
+                    "conn".to_string(),
+                    Some("md.prices".to_string()),
+                    vec![
+                        format!("EURUSD|1.08{:02}|1.08{:02}", sequence % 50, sequence % 49)
+                            .into_bytes(),
+                    ],
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_stream_mode_round_trip() {
+        use crate::recording::reader::RecordingReader;
+
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("stream.rec");
+        let expected = small_messages(500);
+        let mut sink = stream_sink(&path, 60);
+        for message in &expected {
+            sink.write(message).unwrap();
+        }
+        // Nothing is written before the block ends
+        let header_len = fs::metadata(&path).unwrap().len();
+        sink.flush().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > header_len);
+        sink.write(&expected[0]).unwrap();
+        drop(sink);
+
+        let reader = RecordingReader::open(&path).unwrap();
+        assert_eq!(*reader.header().codec(), Codec::DeflateStream);
+        let read: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+        assert_eq!(read.len(), 501);
+        assert_eq!(read[..500], expected[..]);
+        assert_eq!(read[500], expected[0]);
+    }
+
+    #[test]
+    fn test_stream_mode_beats_per_message_on_small_messages() {
+        let temp_dir = tempdir().unwrap();
+        let stream_path = temp_dir.path().join("stream.rec");
+        let per_message_path = temp_dir.path().join("per_message.rec");
+        let mut stream = stream_sink(&stream_path, 60);
+        let mut per_message =
+            CompressedFileSink::new(per_message_path.to_str().unwrap().to_string(), 60, 6).unwrap();
+        for message in small_messages(1000) {
+            stream.write(&message).unwrap();
+            per_message.write(&message).unwrap();
+        }
+        stream.flush().unwrap();
+        per_message.flush().unwrap();
+
+        let stream_len = fs::metadata(&stream_path).unwrap().len();
+        let per_message_len = fs::metadata(&per_message_path).unwrap().len();
+        assert!(
+            stream_len * 5 < per_message_len,
+            "stream {} per-message {}",
+            stream_len,
+            per_message_len
+        );
+    }
+
+    #[test]
+    fn test_stream_mode_without_flush_time_keeps_blocks_open() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("stream.rec");
+        let mut sink = stream_sink(&path, 0);
+        for message in small_messages(3) {
+            sink.write(&message).unwrap();
+        }
+        assert!(sink.block_started.is_some());
+
+        let (_, key) = sink.block_started.unwrap();
+        sink.block_started = Some((Instant::now() - DEFAULT_BLOCK_TIME, key));
+        sink.write(&small_messages(1)[0]).unwrap();
+        assert!(matches!(&sink.encoder, MessageEncoder::Stream(encoder) if encoder.records() == 0));
+        assert!(sink.block_started.is_none());
+    }
+
+    #[test]
+    fn test_tick_ends_block_without_new_messages() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("stream.rec");
+        let mut sink = stream_sink(&path, 1);
+        sink.write(&small_messages(1)[0]).unwrap();
+        sink.tick().unwrap();
+        assert!(sink.block_started.is_some());
+
+        let (_, key) = sink.block_started.unwrap();
+        sink.block_started = Some((Instant::now() - Duration::from_secs(2), key));
+        sink.tick().unwrap();
+        assert!(matches!(&sink.encoder, MessageEncoder::Stream(encoder) if encoder.records() == 0));
+        assert!(sink.block_started.is_none());
+    }
+
+    fn codec_sink(
+        path: &std::path::Path,
+        codec: CompressionCodec,
+        level: i32,
+        dictionary: Vec<u8>,
+    ) -> std::io::Result<CompressedFileSink> {
+        CompressedFileSink::new_with_options(
+            path.to_str().unwrap().to_string(),
+            FileSinkOptions::default(),
+            CompressionOptions {
+                codec,
+                level,
+                dictionary,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_zstd_and_lz4_round_trip() {
+        use crate::recording::reader::RecordingReader;
+
+        let temp_dir = tempdir().unwrap();
+        let expected = small_messages(50);
+        for (codec, level, header_codec) in [
+            (CompressionCodec::Zstd, 3, Codec::Zstd),
+            (CompressionCodec::Zstd, 19, Codec::Zstd),
+            (CompressionCodec::Lz4, 0, Codec::Lz4),
+            (CompressionCodec::Lz4, 9, Codec::Lz4),
+        ] {
+            let path = temp_dir.path().join(format!("{:?}_{}.rec", codec, level));
+            let mut sink = codec_sink(&path, codec, level, vec![]).unwrap();
+            for message in &expected {
+                sink.write(message).unwrap();
+            }
+            sink.flush().unwrap();
+
+            let reader = RecordingReader::open(&path).unwrap();
+            assert_eq!(*reader.header().codec(), header_codec);
+            let read: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+            assert_eq!(read, expected, "{:?} level {}", codec, level);
+        }
+    }
+
+    #[test]
+    fn test_zstd_dictionary_is_stored_in_the_header() {
+        use crate::recording::header::FileHeader;
+        use crate::recording::reader::RecordingReader;
+
+        let temp_dir = tempdir().unwrap();
+        let samples: Vec<_> = small_messages(500)
+            .iter()
+            .map(record::encode_envelope)
+            .collect();
+        let dictionary = zstd::dict::from_samples(&samples, 2048).unwrap();
+        let path = temp_dir.path().join("dictionary.rec");
+        let mut sink = codec_sink(&path, CompressionCodec::Zstd, 3, dictionary.clone()).unwrap();
+        let expected = small_messages(3);
+        for message in &expected {
+            sink.write(message).unwrap();
+        }
+        sink.flush().unwrap();
+
+        let (header, _) = FileHeader::read_from(&mut fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(header.dictionary(), &dictionary);
+        let read: Vec<_> = RecordingReader::open(&path)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn test_levels_are_validated_per_codec() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("levels.rec");
+        assert_eq!(
+            codec_sink(&path, CompressionCodec::Zstd, 23, vec![])
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert!(codec_sink(&path, CompressionCodec::Lz4, 13, vec![]).is_err());
+        assert!(codec_sink(&path, CompressionCodec::Lz4, -1, vec![]).is_err());
+        assert!(codec_sink(&path, CompressionCodec::Gzip, 0, vec![]).is_err());
+        assert!(!path.exists());
+        assert!(codec_sink(&path, CompressionCodec::Zstd, 22, vec![]).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_unsupported_combinations() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("combinations.rec");
+        assert!(codec_sink(&path, CompressionCodec::Lz4, 0, b"dictionary".to_vec()).is_err());
+        let oversized = vec![0; MAX_DICTIONARY_SIZE + 1];
+        assert!(codec_sink(&path, CompressionCodec::Zstd, 3, oversized).is_err());
+        assert!(!path.exists());
+        let stream_zstd = CompressedFileSink::new_with_options(
+            path.to_str().unwrap().to_string(),
+            FileSinkOptions::default(),
+            CompressionOptions {
+                codec: CompressionCodec::Zstd,
+                level: 3,
+                mode: CompressionMode::Stream,
+                ..Default::default()
+            },
+        );
+        assert!(stream_zstd.is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_compression_codec_from_str() {
+        assert_eq!(
+            CompressionCodec::try_from("zstd"),
+            Ok(CompressionCodec::Zstd)
+        );
+        assert_eq!(CompressionCodec::try_from("lz4"), Ok(CompressionCodec::Lz4));
+        assert!(CompressionCodec::try_from("brotli").is_err());
+    }
+
+    #[test]
+    fn test_compression_mode_from_str() {
+        assert_eq!(
+            CompressionMode::try_from("stream"),
+            Ok(CompressionMode::Stream)
+        );
+        assert_eq!(
+            CompressionMode::try_from("per-message"),
+            Ok(CompressionMode::PerMessage)
+        );
+        assert!(CompressionMode::try_from("zstd").is_err());
+    }
+
     #[test]
     fn test_compressed_file_sink_creation_invalid_compression_low() {
         let temp_dir = tempdir().unwrap();
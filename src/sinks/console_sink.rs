@@ -1,14 +1,64 @@
+use std::fmt::Write;
+use std::sync::Arc;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use prost_reflect::ReflectMessage;
+
+use crate::message_decoding::dynamic::MessageDecoder;
+use crate::message_envelope::MessageEnvelope;
 use crate::sink::{Sink, SinkError};
 
-#[derive(Debug)]
-pub struct ConsoleSink;
+/// How the console sink shows a message.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DisplayMode {
+    /// Frames as text, invalid UTF-8 replaced by U+FFFD
+    #[default]
+    Utf8Lossy,
+    /// Offset, hex bytes and printable characters, 16 bytes per line
+    Hexdump,
+    /// Payload as protobuf text format if the connection has a type for the
+    /// topic, as lossy text otherwise
+    Decoded,
+    /// Only the number of frames and bytes
+    Summary,
+}
+
+impl TryFrom<&str> for DisplayMode {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "utf8-lossy" => Ok(DisplayMode::Utf8Lossy),
+            "hexdump" => Ok(DisplayMode::Hexdump),
+            "decoded" => Ok(DisplayMode::Decoded),
+            "summary" => Ok(DisplayMode::Summary),
+            _ => Err(format!(
+                "Unknown display mode '{}', expected utf8-lossy, hexdump, decoded or summary",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConsoleOptions {
+    pub mode: DisplayMode,
+    /// Shows at most this many bytes of a message, or characters of decoded text
+    pub max_bytes: Option<usize>,
+    pub show_topic: bool,
+    pub show_timestamp: bool,
+}
+
+/// Prints every message to stdout. Never fails on binary data.
+#[derive(Debug, Default)]
+pub struct ConsoleSink {
+    options: ConsoleOptions,
+    decoder: Option<Arc<MessageDecoder>>,
+}
 
 impl Sink for ConsoleSink {
-    fn write(&mut self, data: &Vec<u8>) -> Result<(), SinkError> {
-        println!(
-            "Writing to console: {}",
-            String::from_utf8(data.clone()).expect("Failed to write to the console")
-        );
+    fn write(&mut self, message: &MessageEnvelope) -> Result<(), SinkError> {
+        println!("{}", self.format_message(message));
         Ok(())
     }
 
@@ -17,22 +67,212 @@ impl Sink for ConsoleSink {
     }
 }
 
-// stdout is not being captured correctly. Testing this also seems not necassary.
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use gag::BufferRedirect;
-//     use std::io::Read;
-
-//     #[test]
-//     fn test_console_sink_write() {
-//         let mut console_sink = ConsoleSink;
-//         let data = b"Hello, Console!".to_vec();
-//         let mut buffer = BufferRedirect::stdout().unwrap();
-//         console_sink.write(&data).expect("Failed to write to console");
-//         let mut output = String::new();
-//         buffer.read_to_string(&mut output).unwrap();
-//         println!("data recieved: {:?} data input: {}",output, String::from_utf8(data).expect("Our bytes should be valid utf8"));
-//         assert!(output.contains("Writing to console: Hello, Console!"));
-//     }
-// }
+impl ConsoleSink {
+    pub fn new() -> Self {
+        ConsoleSink::default()
+    }
+
+    pub fn new_with_options(options: ConsoleOptions, decoder: Option<Arc<MessageDecoder>>) -> Self {
+        ConsoleSink { options, decoder }
+    }
+
+    /// The text printed for a message, prefixes included.
+    pub fn format_message(&self, message: &MessageEnvelope) -> String {
+        let mut prefix = String::new();
+        if self.options.show_timestamp {
+            let timestamp = DateTime::<Utc>::from(message.wall_time())
+                .to_rfc3339_opts(SecondsFormat::Nanos, true);
+            prefix.push_str(&format!("[{}] ", timestamp));
+        }
+        if self.options.show_topic {
+            prefix.push_str(&format!("{}: ", message.topic().as_deref().unwrap_or("-")));
+        }
+        match self.options.mode {
+            DisplayMode::Utf8Lossy => format!("{}{}", prefix, self.lossy_text(message)),
+            DisplayMode::Hexdump => {
+                let data = message.frames().concat();
+                let (shown, omitted) = self.truncate(&data);
+                let mut text = format!("{}{} bytes\n{}", prefix, data.len(), hexdump(shown));
+                if omitted > 0 {
+                    text.push_str(&format!("\n... {} more bytes", omitted));
+                }
+                text
+            }
+            DisplayMode::Decoded => format!("{}{}", prefix, self.decoded_text(message)),
+            DisplayMode::Summary => format!(
+                "{}{} frames, {} bytes",
+                prefix,
+                message.frames().len(),
+                message.payload_len()
+            ),
+        }
+    }
+
+    fn lossy_text(&self, message: &MessageEnvelope) -> String {
+        let data = message.frames().concat();
+        let (shown, omitted) = self.truncate(&data);
+        let mut text = String::from_utf8_lossy(shown).into_owned();
+        if omitted > 0 {
+            text.push_str(&format!("... ({} more bytes)", omitted));
+        }
+        text
+    }
+
+    fn decoded_text(&self, message: &MessageEnvelope) -> String {
+        let decoded = match &self.decoder {
+            Some(decoder) => decoder.decode(message),
+            None => return self.lossy_text(message),
+        };
+        match decoded {
+            Ok(Some(decoded)) => {
+                let text = decoded.to_string();
+                match self.options.max_bytes {
+                    Some(max) if text.chars().count() > max => {
+                        let shown: String = text.chars().take(max).collect();
+                        format!("{} {}...", decoded.descriptor().full_name(), shown)
+                    }
+                    _ => format!("{} {}", decoded.descriptor().full_name(), text),
+                }
+            }
+            Ok(None) => self.lossy_text(message),
+            Err(e) => format!("({}) {}", e, self.lossy_text(message)),
+        }
+    }
+
+    /// Splits off the bytes beyond `max_bytes`, returns the rest and how many were cut.
+    fn truncate<'a>(&self, data: &'a [u8]) -> (&'a [u8], usize) {
+        match self.options.max_bytes {
+            Some(max) if data.len() > max => (&data[..max], data.len() - max),
+            _ => (data, 0),
+        }
+    }
+}
+
+/// Formats bytes like `hexdump -C`, without the trailing offset line.
+fn hexdump(data: &[u8]) -> String {
+    let mut text = String::new();
+    for (line, chunk) in data.chunks(16).enumerate() {
+        if line > 0 {
+            text.push('\n');
+        }
+        let _ = write!(text, "{:08x} ", line * 16);
+        for column in 0..16 {
+            if column == 8 {
+                text.push(' ');
+            }
+            match chunk.get(column) {
+                Some(byte) => {
+                    let _ = write!(text, " {:02x}", byte);
+                }
+                None => text.push_str("   "),
+            }
+        }
+        let printable: String = chunk
+            .iter()
+            .map(|byte| match byte {
+                0x20..=0x7e => *byte as char,
+                _ => '.',
+            })
+            .collect();
+        let _ = write!(text, "  |{}|", printable);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_decoding::dynamic::tests::{market_pool, quote};
+    use std::collections::HashMap;
+
+    fn message(frames: Vec<Vec<u8>>) -> MessageEnvelope {
+        MessageEnvelope::with_timestamps(
+            0,
+            1_704_164_645_000_000_000,
+            0,
+            "conn".to_string(),
+            Some("quotes".to_string()),
+            frames,
+        )
+    }
+
+    fn sink(mode: DisplayMode, max_bytes: Option<usize>) -> ConsoleSink {
+        ConsoleSink::new_with_options(
+            ConsoleOptions {
+                mode,
+                max_bytes,
+                ..Default::default()
+            },
+            None,
+        )
+    }
+
+    #[test]
+    fn test_binary_data_does_not_panic() {
+        let binary = message(vec![vec![0xFF, 0xFE, b'o', b'k']]);
+        let mut console_sink = ConsoleSink::new();
+        assert_eq!(console_sink.format_message(&binary), "\u{FFFD}\u{FFFD}ok");
+        assert!(console_sink.write(&binary).is_ok());
+    }
+
+    #[test]
+    fn test_prefixes_and_truncation() {
+        let console_sink = ConsoleSink::new_with_options(
+            ConsoleOptions {
+                max_bytes: Some(5),
+                show_topic: true,
+                show_timestamp: true,
+                ..Default::default()
+            },
+            None,
+        );
+        assert_eq!(
+            console_sink.format_message(&message(vec![b"Hello, Console!".to_vec()])),
+            "[2024-01-02T03:04:05.000000000Z] quotes: Hello... (10 more bytes)"
+        );
+    }
+
+    #[test]
+    fn test_hexdump_and_summary() {
+        let data = message(vec![b"0123456789abcdef\x00\x01".to_vec()]);
+        assert_eq!(
+            sink(DisplayMode::Hexdump, None).format_message(&data),
+            "18 bytes\n\
+             00000000  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|\n\
+             00000010  00 01                                             |..|"
+        );
+        assert!(sink(DisplayMode::Hexdump, Some(16))
+            .format_message(&data)
+            .ends_with("|0123456789abcdef|\n... 2 more bytes"));
+        assert_eq!(
+            sink(DisplayMode::Summary, None).format_message(&data),
+            "1 frames, 18 bytes"
+        );
+    }
+
+    #[test]
+    fn test_decoded_mode() {
+        let pool = market_pool();
+        let by_topic = HashMap::from([("quotes".to_string(), "market.Quote".to_string())]);
+        let decoder = MessageDecoder::new(&pool, None, &by_topic).unwrap();
+        let console_sink = ConsoleSink::new_with_options(
+            ConsoleOptions {
+                mode: DisplayMode::Decoded,
+                ..Default::default()
+            },
+            Some(Arc::new(decoder)),
+        );
+        assert_eq!(
+            console_sink.format_message(&message(vec![quote(&pool, "EURUSD", 1.5)])),
+            r#"market.Quote symbol:"EURUSD",bid:1.5"#
+        );
+        assert!(console_sink
+            .format_message(&message(vec![vec![0xFF]]))
+            .starts_with("(Payload is not a valid market.Quote"));
+        // Without a decoder the payload is shown as text
+        assert_eq!(
+            sink(DisplayMode::Decoded, None).format_message(&message(vec![b"text".to_vec()])),
+            "text"
+        );
+    }
+}
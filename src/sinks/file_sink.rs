@@ -1,77 +1,600 @@
-use byteorder::{BigEndian, WriteBytesExt};
+use std::fs::File;
+use std::path::Path;
+use std::time::SystemTime;
+
 use getset::Getters;
-use log::info;
+use log::{info, warn};
+use prometheus::Histogram;
 
+use crate::message_envelope::MessageEnvelope;
+use crate::recording::framing::{self, RECORD_PREFIX_SIZE};
+use crate::recording::header::{Codec, FileHeader};
+use crate::recording::index::{IndexKey, RecordingIndex};
+use crate::recording::reader::RecordingReader;
+use crate::recording::record;
 use crate::sink::{Sink, SinkError};
-use crate::sinks::raw_file_sink::RawFileSink;
+use crate::sinks::raw_file_sink::{OpenMode, RawFileSink};
+use crate::sinks::rotation::{RotatedNames, RotationPolicy};
+
+#[derive(Debug, Clone, Default)]
+pub struct FileSinkOptions {
+    pub flush_time_s: i32,
+    pub rotation: RotationPolicy,
+    pub open_mode: OpenMode,
+    /// Encoding of the record bodies, set by the sink that writes them
+    pub codec: Codec,
+    /// Dictionary the codec needs to decode the records, stored in the header
+    pub dictionary: Vec<u8>,
+    /// Connection and topics described in the file header
+    pub connection: String,
+    pub topics: Vec<String>,
+    /// Observes the flush latency of every file the sink writes
+    pub flush_latency: Option<Histogram>,
+}
 
 #[derive(Debug, Getters)]
 pub struct FileSink {
+    /// Name the connection asked for, rotated files are derived from it
+    #[get = "pub"]
+    base_filename: String,
+    #[get = "pub"]
+    options: FileSinkOptions,
     file_handle: RawFileSink,
+    opened_at: SystemTime,
+    names: RotatedNames,
+    bytes_written: u64,
+    messages_written: u64,
+    /// Sparse index of the current file, written next to it on rotation and close
+    index: RecordingIndex,
 }
 
 impl Sink for FileSink {
-    fn write(&mut self, data: &Vec<u8>) -> Result<(), SinkError> {
+    fn write(&mut self, message: &MessageEnvelope) -> Result<(), SinkError> {
         info!(
-            "Writing to file {}: {:?}",
-            self.file_handle.filename(),
-            data.clone()
+            "Writing message {} with {} frames to file {}",
+            message.sequence(),
+            message.frames().len(),
+            self.file_handle.filename()
         );
-        let mut data_size_vec = vec![];
-        WriteBytesExt::write_u64::<BigEndian>(&mut data_size_vec, data.len() as u64)?;
-        self.file_handle.write(&data_size_vec)?;
-        self.file_handle.write(data)?;
-        Ok(())
+        self.write_record(&record::encode_envelope(message), IndexKey::of(message))
     }
 
     fn flush(&mut self) -> Result<(), SinkError> {
         self.file_handle.flush()?;
         Ok(())
     }
+
+    fn tick(&mut self) -> Result<(), SinkError> {
+        self.file_handle.flush_if_due()
+    }
 }
 
 impl FileSink {
     pub fn new(filename: String, flush_time_s: i32) -> std::io::Result<Self> {
-        let file_handle = RawFileSink::new(filename, flush_time_s)?;
+        FileSink::new_with_options(
+            filename,
+            FileSinkOptions {
+                flush_time_s,
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn new_with_options(
+        base_filename: String,
+        options: FileSinkOptions,
+    ) -> std::io::Result<Self> {
+        let opened_at = SystemTime::now();
+        let mut names = RotatedNames::default();
+        let (file_handle, existing) =
+            FileSink::open_file(&base_filename, &options, opened_at, &mut names)?;
         Ok(FileSink {
-            file_handle: file_handle,
+            base_filename,
+            options,
+            file_handle,
+            opened_at,
+            names,
+            bytes_written: existing.bytes,
+            messages_written: existing.messages,
+            index: existing.index,
         })
     }
 
+    /// Rotating sinks always write to timestamped files, others use the name as given.
+    /// New files start with a header. Also returns what an appended-to file
+    /// already contains, so rotation limits count the earlier records too.
+    fn open_file(
+        base_filename: &str,
+        options: &FileSinkOptions,
+        opened_at: SystemTime,
+        names: &mut RotatedNames,
+    ) -> std::io::Result<(RawFileSink, ExistingContent)> {
+        let filename = if options.rotation.is_enabled() {
+            names.next(base_filename, opened_at)
+        } else {
+            base_filename.to_string()
+        };
+        let existing = if options.open_mode == OpenMode::Append {
+            ExistingContent::validate(Path::new(&filename), options)?
+        } else {
+            None
+        };
+        let mut file_handle =
+            RawFileSink::new_with_mode(filename, options.flush_time_s, options.open_mode)?;
+        if let Some(histogram) = &options.flush_latency {
+            file_handle.set_flush_latency(histogram.clone());
+        }
+        let existing = match existing {
+            Some(existing) => existing,
+            None => {
+                let header = FileHeader::new_with_dictionary(
+                    options.codec,
+                    options.connection.clone(),
+                    options.topics.clone(),
+                    options.dictionary.clone(),
+                )
+                .encode();
+                file_handle.write(&header)?;
+                ExistingContent {
+                    bytes: header.len() as u64,
+                    messages: 0,
+                    index: RecordingIndex::new(header.len() as u64),
+                }
+            }
+        };
+        Ok((file_handle, existing))
+    }
+
+    /// Name of the file currently being written.
     pub fn filename(&self) -> &String {
         self.file_handle.filename()
     }
+
+    /// Closes the current file and continues in a freshly named one.
+    pub fn rotate(&mut self) -> Result<(), SinkError> {
+        self.file_handle.flush()?;
+        self.write_index();
+        let opened_at = SystemTime::now();
+        let (file_handle, existing) = FileSink::open_file(
+            &self.base_filename,
+            &self.options,
+            opened_at,
+            &mut self.names,
+        )?;
+        info!(
+            "Rotating {} to {} after {} messages and {} bytes",
+            self.file_handle.filename(),
+            file_handle.filename(),
+            self.messages_written,
+            self.bytes_written
+        );
+        // Dropping the old handle closes the previous file
+        self.file_handle = file_handle;
+        self.opened_at = opened_at;
+        self.bytes_written = existing.bytes;
+        self.messages_written = existing.messages;
+        self.index = existing.index;
+        Ok(())
+    }
+
+    /// Writes an already encoded record body behind its big-endian u64 length
+    /// and u32 CRC-32C.
+    pub fn write_record(&mut self, body: &[u8], key: Option<IndexKey>) -> Result<(), SinkError> {
+        self.write_block(body, 1, key)
+    }
+
+    /// Writes a record holding `messages` messages, a compressed block or a
+    /// single message body. `key` describes its first message for the index,
+    /// `None` if it holds only monitor events.
+    pub fn write_block(
+        &mut self,
+        body: &[u8],
+        messages: u64,
+        key: Option<IndexKey>,
+    ) -> Result<(), SinkError> {
+        let record_len = RECORD_PREFIX_SIZE + body.len() as u64;
+        if self.options.rotation.should_rotate(
+            self.bytes_written,
+            self.messages_written,
+            record_len,
+            self.opened_at,
+            SystemTime::now(),
+        ) {
+            self.rotate()?;
+        }
+
+        self.file_handle.write(&framing::encode_prefix(body))?;
+        self.file_handle.write(body)?;
+        match self.options.codec.is_block_codec() {
+            true => self
+                .index
+                .add_block(self.bytes_written, record_len, messages, key),
+            false => self
+                .index
+                .add_record(self.bytes_written, record_len, messages, key),
+        }
+        self.bytes_written += record_len;
+        self.messages_written += messages;
+        Ok(())
+    }
+
+    /// Writes the index sidecar of the current file. A missing index only
+    /// makes seeking slower, so failures are logged.
+    fn write_index(&self) {
+        if let Err(e) = self.index.write(Path::new(self.filename())) {
+            warn!("Failed to write the index of {}: {}", self.filename(), e);
+        }
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        // The index must not cover records still sitting in the buffer
+        if self.file_handle.flush().is_ok() {
+            self.write_index();
+        }
+    }
+}
+
+/// Header and records already present in a file when it is opened.
+#[derive(Debug)]
+struct ExistingContent {
+    bytes: u64,
+    messages: u64,
+    index: RecordingIndex,
+}
+
+impl ExistingContent {
+    /// Checks a file before appending to it. Returns `None` for a missing or
+    /// empty file, which still needs a header. Refuses files from another
+    /// format version, codec or dictionary, and files not ending in a valid record, which
+    /// would make every following record unreadable. `framing::recover` can
+    /// repair those.
+    fn validate(path: &Path, options: &FileSinkOptions) -> std::io::Result<Option<Self>> {
+        if !path.exists() || path.metadata()?.len() == 0 {
+            return Ok(None);
+        }
+        let (header, records_start) = FileHeader::read_from(&mut File::open(path)?)?;
+        if *header.codec() != options.codec {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Cannot append {} records to {}, it holds {} records",
+                    options.codec,
+                    path.display(),
+                    header.codec()
+                ),
+            ));
+        }
+        if *header.dictionary() != options.dictionary {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Cannot append to {}, it was compressed with another dictionary",
+                    path.display()
+                ),
+            ));
+        }
+        let report = framing::scan(path, records_start)?;
+        if !report.tail_is_valid() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Cannot append to {}, its last valid record ends at offset {} of {}",
+                    path.display(),
+                    report.valid_end,
+                    report.file_len
+                ),
+            ));
+        }
+        for problem in &report.problems {
+            warn!("{}: {}", path.display(), problem);
+        }
+        let index = match RecordingIndex::load(path)? {
+            Some(index) => index,
+            None => {
+                info!("Indexing {} before appending to it", path.display());
+                RecordingReader::open(path)
+                    .and_then(|mut reader| reader.index().cloned())
+                    .map_err(|e| std::io::Error::other(e.to_string()))?
+            }
+        };
+        let messages = *index.messages();
+        let bytes = report.file_len;
+        if messages > 0 {
+            info!(
+                "Appending to {} after {} existing messages",
+                path.display(),
+                messages
+            );
+        }
+        Ok(Some(ExistingContent {
+            bytes,
+            messages,
+            index,
+        }))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use byteorder::{BigEndian, ReadBytesExt};
-    use std::io::Read;
-    use tempfile::NamedTempFile;
+    use tempfile::tempdir;
+
+    /// Reads the header and every record of a file sink output.
+    fn read_recording(path: &Path) -> (FileHeader, Vec<MessageEnvelope>) {
+        let reader = RecordingReader::open(path).expect("Failed to open recording");
+        let header = reader.header().clone();
+        let records = reader
+            .collect::<Result<_, _>>()
+            .expect("Failed to read records");
+        (header, records)
+    }
 
     #[test]
     fn test_file_sink_write() {
-        let temp_file = NamedTempFile::new().expect("Failed to create temp file");
-        let temp_path = temp_file.path().to_str().unwrap().to_string();
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let temp_path = temp_dir
+            .path()
+            .join("sink.rec")
+            .to_str()
+            .unwrap()
+            .to_string();
 
         let mut file_sink = FileSink::new(temp_path.clone(), 0).expect("Failed to create FileSink");
 
         let data = b"Hello, FileSink!".to_vec();
+        let message = MessageEnvelope::new(
+            0,
+            "tcp://localhost:5555".to_string(),
+            Some("test".to_string()),
+            vec![data.clone()],
+        );
+
+        file_sink.write(&message).expect("Failed to write data");
+        file_sink.flush().expect("Failed to flush");
+
+        let (_, records) = read_recording(Path::new(&temp_path));
+        assert_eq!(records, vec![message]);
+        assert_eq!(records[0].frames(), &vec![data]);
+    }
+
+    #[test]
+    fn test_file_sink_writes_header() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("header.rec");
+        let mut file_sink = FileSink::new_with_options(
+            path.to_str().unwrap().to_string(),
+            FileSinkOptions {
+                connection: "tcp://localhost:5555".to_string(),
+                topics: vec!["md.".to_string()],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        file_sink.flush().unwrap();
+
+        let (header, records) = read_recording(&path);
+        assert_eq!(*header.codec(), Codec::Uncompressed);
+        assert_eq!(header.connection(), "tcp://localhost:5555");
+        assert_eq!(header.topics(), &vec!["md.".to_string()]);
+        assert!(records.is_empty());
+    }
+
+    #[test]
+    fn test_file_sink_write_multiple_records() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let temp_path = temp_dir
+            .path()
+            .join("sink.rec")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let mut file_sink = FileSink::new(temp_path.clone(), 0).expect("Failed to create FileSink");
+
+        let multipart = MessageEnvelope::new(
+            0,
+            "conn".to_string(),
+            Some("topic".to_string()),
+            vec![b"header".to_vec(), b"payload".to_vec()],
+        );
+        let single = MessageEnvelope::new(1, "conn".to_string(), None, vec![b"single".to_vec()]);
+        file_sink.write(&multipart).expect("Failed to write data");
+        file_sink.write(&single).expect("Failed to write data");
+        file_sink.flush().expect("Failed to flush");
+
+        let (_, records) = read_recording(Path::new(&temp_path));
+        assert_eq!(records, vec![multipart, single]);
+    }
+
+    #[test]
+    fn test_file_sink_rotates_on_message_count() {
+        let temp_dir = tempdir().unwrap();
+        let base = temp_dir.path().join("rotate.rec");
+        let mut file_sink = FileSink::new_with_options(
+            base.to_str().unwrap().to_string(),
+            FileSinkOptions {
+                flush_time_s: 0,
+                rotation: RotationPolicy {
+                    max_messages: Some(2),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .expect("Failed to create FileSink");
+        let first_file = file_sink.filename().clone();
+        assert_ne!(first_file, base.to_str().unwrap());
 
-        file_sink.write(&data).expect("Failed to write data");
+        for sequence in 0..5 {
+            let message =
+                MessageEnvelope::new(sequence, "conn".to_string(), None, vec![b"data".to_vec()]);
+            file_sink.write(&message).expect("Failed to write data");
+        }
+        file_sink.flush().expect("Failed to flush");
+
+        let (indexes, mut files): (Vec<_>, Vec<_>) = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .partition(|path| path.extension().is_some_and(|ext| ext == "idx"));
+        files.sort();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0].to_str().unwrap(), first_file);
+        // Rotated files are indexed, the current one once the sink is closed
+        assert_eq!(indexes.len(), 2);
+
+        let mut sequences = vec![];
+        for path in files {
+            let (_, records) = read_recording(&path);
+            assert!(records.len() <= 2);
+            sequences.extend(records.iter().map(|record| *record.sequence()));
+        }
+        assert_eq!(sequences, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_file_sink_rotates_twice_within_one_second() {
+        for open_mode in [OpenMode::FailIfExists, OpenMode::Append] {
+            let temp_dir = tempdir().unwrap();
+            let base = temp_dir.path().join("fast.rec");
+            let mut file_sink = FileSink::new_with_options(
+                base.to_str().unwrap().to_string(),
+                FileSinkOptions {
+                    rotation: RotationPolicy {
+                        max_messages: Some(1),
+                        ..Default::default()
+                    },
+                    open_mode,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            for sequence in 0..3 {
+                let message = MessageEnvelope::new(
+                    sequence,
+                    "conn".to_string(),
+                    None,
+                    vec![b"data".to_vec()],
+                );
+                file_sink.write(&message).unwrap();
+            }
+            file_sink.flush().unwrap();
+
+            let mut files: Vec<_> = std::fs::read_dir(temp_dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "rec"))
+                .collect();
+            files.sort();
+            assert_eq!(files.len(), 3, "{:?}", open_mode);
+            for path in files {
+                assert_eq!(read_recording(&path).1.len(), 1, "{:?}", open_mode);
+            }
+        }
+    }
+
+    fn append_options() -> FileSinkOptions {
+        FileSinkOptions {
+            open_mode: OpenMode::Append,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_file_sink_append_continues_existing_recording() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("append.rec");
+        let path_str = path.to_str().unwrap().to_string();
+        let first = MessageEnvelope::new(0, "conn".to_string(), None, vec![b"one".to_vec()]);
+        let second = MessageEnvelope::new(1, "conn".to_string(), None, vec![b"two".to_vec()]);
 
-        let mut file = std::fs::File::open(temp_path).expect("Failed to open temp file");
+        let mut file_sink = FileSink::new(path_str.clone(), 0).unwrap();
+        file_sink.write(&first).unwrap();
+        file_sink.flush().unwrap();
+        drop(file_sink);
 
-        // Read the first 8 bytes to get the data size
-        let data_size = file
-            .read_u64::<BigEndian>()
-            .expect("Failed to read data size");
+        let mut file_sink = FileSink::new_with_options(path_str.clone(), append_options()).unwrap();
+        assert_eq!(file_sink.filename(), &path_str);
+        assert_eq!(file_sink.messages_written, 1);
+        file_sink.write(&second).unwrap();
+        file_sink.flush().unwrap();
+
+        let (_, records) = read_recording(&path);
+        assert_eq!(records, vec![first, second]);
+    }
+
+    #[test]
+    fn test_file_sink_index_survives_append() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("indexed.rec");
+        let path_str = path.to_str().unwrap().to_string();
+        let message = |sequence| {
+            MessageEnvelope::new(sequence, "conn".to_string(), None, vec![b"data".to_vec()])
+        };
+
+        let mut file_sink = FileSink::new(path_str.clone(), 0).unwrap();
+        file_sink.write(&message(0)).unwrap();
+        file_sink.write(&message(1)).unwrap();
+        drop(file_sink);
+        let index = RecordingIndex::load(&path).unwrap().unwrap();
+        assert_eq!(*index.messages(), 2);
+        assert_eq!(index.entries()[0].sequence, 0);
+
+        // Without the sidecar the sink indexes the file before appending
+        std::fs::remove_file(RecordingIndex::sidecar_path(&path)).unwrap();
+        let mut file_sink = FileSink::new_with_options(path_str.clone(), append_options()).unwrap();
+        assert_eq!(file_sink.index, index);
+        file_sink.write(&message(2)).unwrap();
+        drop(file_sink);
+
+        let index = RecordingIndex::load(&path).unwrap().unwrap();
+        assert_eq!(*index.messages(), 3);
+        assert_eq!(
+            *index.covered_len(),
+            std::fs::metadata(&path).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_file_sink_append_refuses_truncated_tail() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("truncated.rec");
+        let mut data = FileHeader::new(Codec::Uncompressed, "conn".to_string(), vec![]).encode();
+        data.extend_from_slice(&framing::encode_prefix(b"longer than what was written"));
+        data.extend_from_slice(b"short");
+        std::fs::write(&path, &data).unwrap();
+
+        let result =
+            FileSink::new_with_options(path.to_str().unwrap().to_string(), append_options());
+
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn test_file_sink_append_refuses_other_codec() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("gzip.rec");
+        let data = FileHeader::new(Codec::Gzip, "conn".to_string(), vec![]).encode();
+        std::fs::write(&path, &data).unwrap();
+
+        let result =
+            FileSink::new_with_options(path.to_str().unwrap().to_string(), append_options());
+
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn test_file_sink_never_truncates_by_default() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("existing.rec");
+        std::fs::write(&path, b"previous recording").unwrap();
 
-        // Read the data
-        let mut data_buf = vec![0u8; data_size as usize];
-        file.read_exact(&mut data_buf).expect("Failed to read data");
+        let file_sink = FileSink::new(path.to_str().unwrap().to_string(), 0).unwrap();
 
-        assert_eq!(data_buf, data);
+        assert_ne!(file_sink.filename(), path.to_str().unwrap());
+        assert_eq!(std::fs::read(&path).unwrap(), b"previous recording");
     }
 }
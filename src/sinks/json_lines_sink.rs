@@ -0,0 +1,451 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use base64::Engine;
+use chrono::{DateTime, SecondsFormat, Utc};
+use getset::Getters;
+use log::{debug, info, warn};
+use serde_json::{json, Map, Value};
+
+use crate::message_decoding::dynamic::MessageDecoder;
+use crate::message_envelope::MessageEnvelope;
+use crate::sink::{Sink, SinkError};
+use crate::sinks::file_sink::FileSinkOptions;
+use crate::sinks::raw_file_sink::{OpenMode, RawFileSink};
+use crate::sinks::rotation::RotatedNames;
+
+/// Text encoding of payloads that are not decoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PayloadEncoding {
+    #[default]
+    Base64,
+    Hex,
+}
+
+impl PayloadEncoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PayloadEncoding::Base64 => "base64",
+            PayloadEncoding::Hex => "hex",
+        }
+    }
+
+    pub fn encode(&self, data: &[u8]) -> String {
+        match self {
+            PayloadEncoding::Base64 => base64::engine::general_purpose::STANDARD.encode(data),
+            PayloadEncoding::Hex => data.iter().map(|byte| format!("{:02x}", byte)).collect(),
+        }
+    }
+}
+
+impl TryFrom<&str> for PayloadEncoding {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "base64" => Ok(PayloadEncoding::Base64),
+            "hex" => Ok(PayloadEncoding::Hex),
+            _ => Err(format!(
+                "Unknown payload encoding '{}', expected base64 or hex",
+                value
+            )),
+        }
+    }
+}
+
+/// Writes one JSON object per message and line. The payload, the last frame,
+/// is decoded protobuf JSON if the connection has a type for its topic, and
+/// `encoding` text otherwise. Rotation and flushing follow `FileSinkOptions`
+/// like the binary file sinks, the codec and header fields are not used.
+#[derive(Debug, Getters)]
+pub struct JsonLinesSink {
+    #[get = "pub"]
+    base_filename: String,
+    #[get = "pub"]
+    options: FileSinkOptions,
+    #[get = "pub"]
+    encoding: PayloadEncoding,
+    decoder: Option<Arc<MessageDecoder>>,
+    file_handle: RawFileSink,
+    opened_at: SystemTime,
+    names: RotatedNames,
+    bytes_written: u64,
+    messages_written: u64,
+}
+
+impl Sink for JsonLinesSink {
+    fn write(&mut self, message: &MessageEnvelope) -> Result<(), SinkError> {
+        let mut line = self.to_json(message).to_string();
+        line.push('\n');
+        if self.options.rotation.should_rotate(
+            self.bytes_written,
+            self.messages_written,
+            line.len() as u64,
+            self.opened_at,
+            SystemTime::now(),
+        ) {
+            self.rotate()?;
+        }
+        self.file_handle.write(line.as_bytes())?;
+        self.bytes_written += line.len() as u64;
+        self.messages_written += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        self.file_handle.flush()
+    }
+
+    fn tick(&mut self) -> Result<(), SinkError> {
+        self.file_handle.flush_if_due()
+    }
+}
+
+impl JsonLinesSink {
+    pub fn new(filename: String, flush_time_s: i32) -> std::io::Result<Self> {
+        JsonLinesSink::new_with_options(
+            filename,
+            FileSinkOptions {
+                flush_time_s,
+                ..Default::default()
+            },
+            PayloadEncoding::default(),
+            None,
+        )
+    }
+
+    pub fn new_with_options(
+        base_filename: String,
+        options: FileSinkOptions,
+        encoding: PayloadEncoding,
+        decoder: Option<Arc<MessageDecoder>>,
+    ) -> std::io::Result<Self> {
+        let opened_at = SystemTime::now();
+        let mut names = RotatedNames::default();
+        let (file_handle, bytes, messages) =
+            JsonLinesSink::open_file(&base_filename, &options, opened_at, &mut names)?;
+        Ok(JsonLinesSink {
+            base_filename,
+            options,
+            encoding,
+            decoder,
+            file_handle,
+            opened_at,
+            names,
+            bytes_written: bytes,
+            messages_written: messages,
+        })
+    }
+
+    /// Opens the file like `FileSink` does. Returns the bytes and lines an
+    /// appended-to file already holds, so rotation limits count them too.
+    fn open_file(
+        base_filename: &str,
+        options: &FileSinkOptions,
+        opened_at: SystemTime,
+        names: &mut RotatedNames,
+    ) -> std::io::Result<(RawFileSink, u64, u64)> {
+        let filename = if options.rotation.is_enabled() {
+            names.next(base_filename, opened_at)
+        } else {
+            base_filename.to_string()
+        };
+        let (bytes, messages) = match options.open_mode {
+            OpenMode::Append if Path::new(&filename).exists() => {
+                JsonLinesSink::complete_lines(Path::new(&filename))?
+            }
+            _ => (0, 0),
+        };
+        let mut file_handle =
+            RawFileSink::new_with_mode(filename, options.flush_time_s, options.open_mode)?;
+        if let Some(histogram) = &options.flush_latency {
+            file_handle.set_flush_latency(histogram.clone());
+        }
+        Ok((file_handle, bytes, messages))
+    }
+
+    /// Counts the complete lines of `path` without reading it into memory, and
+    /// cuts off a last line torn by a crash, so appended lines start on their
+    /// own. Returns the bytes and lines left.
+    fn complete_lines(path: &Path) -> std::io::Result<(u64, u64)> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let (mut complete, mut torn, mut lines) = (0, 0, 0);
+        loop {
+            let buffer = reader.fill_buf()?;
+            if buffer.is_empty() {
+                break;
+            }
+            let len = buffer.len();
+            match buffer.iter().rposition(|byte| *byte == b'\n') {
+                Some(last) => {
+                    lines += buffer.iter().filter(|byte| **byte == b'\n').count() as u64;
+                    complete += torn + last as u64 + 1;
+                    torn = (len - last - 1) as u64;
+                }
+                None => torn += len as u64,
+            }
+            reader.consume(len);
+        }
+        if torn > 0 {
+            warn!(
+                "Cutting a torn last line of {} bytes off {} before appending",
+                torn,
+                path.display()
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(complete)?;
+        }
+        Ok((complete, lines))
+    }
+
+    /// Name of the file currently being written.
+    pub fn filename(&self) -> &String {
+        self.file_handle.filename()
+    }
+
+    /// Closes the current file and continues in a freshly named one.
+    pub fn rotate(&mut self) -> Result<(), SinkError> {
+        self.file_handle.flush()?;
+        let opened_at = SystemTime::now();
+        let (file_handle, bytes, messages) = JsonLinesSink::open_file(
+            &self.base_filename,
+            &self.options,
+            opened_at,
+            &mut self.names,
+        )?;
+        info!(
+            "Rotating {} to {} after {} messages and {} bytes",
+            self.file_handle.filename(),
+            file_handle.filename(),
+            self.messages_written,
+            self.bytes_written
+        );
+        self.file_handle = file_handle;
+        self.opened_at = opened_at;
+        self.bytes_written = bytes;
+        self.messages_written = messages;
+        Ok(())
+    }
+
+    /// Renders a message as `{"sequence", "timestamp", "wall_time_ns",
+    /// "connection", "topic", "encoding", "payload"}`. Frames before the
+    /// payload are listed under `"frames"`, and a payload that failed to
+    /// decode keeps the reason under `"decode_error"`.
+    pub fn to_json(&self, message: &MessageEnvelope) -> Value {
+        let timestamp =
+            DateTime::<Utc>::from(message.wall_time()).to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let mut object = Map::new();
+        object.insert("sequence".to_string(), json!(message.sequence()));
+        object.insert("timestamp".to_string(), json!(timestamp));
+        object.insert("wall_time_ns".to_string(), json!(message.wall_time_ns()));
+        object.insert("connection".to_string(), json!(message.connection()));
+        object.insert("topic".to_string(), json!(message.topic()));
+
+        let Some((payload, leading)) = message.frames().split_last() else {
+            object.insert("encoding".to_string(), json!(self.encoding.name()));
+            object.insert("payload".to_string(), Value::Null);
+            return Value::Object(object);
+        };
+        if !leading.is_empty() {
+            let frames: Vec<String> = leading
+                .iter()
+                .map(|frame| self.encoding.encode(frame))
+                .collect();
+            object.insert("frames".to_string(), json!(frames));
+        }
+        let decoded = match &self.decoder {
+            Some(decoder) => decoder.decode_payload(message.topic().as_deref(), payload),
+            None => Ok(None),
+        };
+        match decoded.map(|decoded| decoded.map(|decoded| serde_json::to_value(&decoded))) {
+            Ok(Some(Ok(value))) => {
+                object.insert("encoding".to_string(), json!("protobuf"));
+                object.insert("payload".to_string(), value);
+            }
+            result => {
+                let error = match result {
+                    Err(e) => Some(e.to_string()),
+                    Ok(Some(Err(e))) => Some(e.to_string()),
+                    _ => None,
+                };
+                if let Some(error) = error {
+                    debug!(
+                        "Writing message {} undecoded: {}",
+                        message.sequence(),
+                        error
+                    );
+                    object.insert("decode_error".to_string(), json!(error));
+                }
+                object.insert("encoding".to_string(), json!(self.encoding.name()));
+                object.insert("payload".to_string(), json!(self.encoding.encode(payload)));
+            }
+        }
+        Value::Object(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_decoding::dynamic::tests::{market_pool, quote};
+    use crate::sinks::rotation::RotationPolicy;
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+
+    fn read_lines(path: &Path) -> Vec<Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_payload_encoding() {
+        assert_eq!(PayloadEncoding::Base64.encode(b"hi!"), "aGkh");
+        assert_eq!(PayloadEncoding::Hex.encode(&[0x00, 0xAB]), "00ab");
+        assert_eq!(PayloadEncoding::try_from("hex"), Ok(PayloadEncoding::Hex));
+        assert!(PayloadEncoding::try_from("ascii").is_err());
+    }
+
+    #[test]
+    fn test_writes_raw_payloads() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("raw.jsonl");
+        let mut sink = JsonLinesSink::new(path.to_str().unwrap().to_string(), 0).unwrap();
+        let message = MessageEnvelope::with_timestamps(
+            7,
+            1_704_164_645_000_000_001,
+            0,
+            "tcp://localhost:5555".to_string(),
+            Some("md".to_string()),
+            vec![b"key".to_vec(), b"hi!".to_vec()],
+        );
+        sink.write(&message).unwrap();
+        sink.flush().unwrap();
+
+        let lines = read_lines(&path);
+        assert_eq!(
+            lines,
+            vec![json!({
+                "sequence": 7,
+                "timestamp": "2024-01-02T03:04:05.000000001Z",
+                "wall_time_ns": 1_704_164_645_000_000_001u64,
+                "connection": "tcp://localhost:5555",
+                "topic": "md",
+                "frames": ["a2V5"],
+                "encoding": "base64",
+                "payload": "aGkh",
+            })]
+        );
+    }
+
+    #[test]
+    fn test_writes_decoded_payloads() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("decoded.jsonl");
+        let pool = market_pool();
+        let by_topic = HashMap::from([("quotes".to_string(), "market.Quote".to_string())]);
+        let decoder = MessageDecoder::new(&pool, None, &by_topic).unwrap();
+        let mut sink = JsonLinesSink::new_with_options(
+            path.to_str().unwrap().to_string(),
+            FileSinkOptions::default(),
+            PayloadEncoding::Hex,
+            Some(Arc::new(decoder)),
+        )
+        .unwrap();
+        let message = |topic: &str, payload: Vec<u8>| {
+            MessageEnvelope::new(
+                0,
+                "conn".to_string(),
+                Some(topic.to_string()),
+                vec![payload],
+            )
+        };
+        sink.write(&message("quotes", quote(&pool, "EURUSD", 1.5)))
+            .unwrap();
+        sink.write(&message("quotes", vec![0xFF])).unwrap();
+        sink.write(&message("trades", vec![0x01])).unwrap();
+        sink.flush().unwrap();
+
+        let lines = read_lines(&path);
+        assert_eq!(lines[0]["encoding"], "protobuf");
+        assert_eq!(lines[0]["payload"], json!({"symbol": "EURUSD", "bid": 1.5}));
+        assert_eq!(lines[1]["encoding"], "hex");
+        assert_eq!(lines[1]["payload"], "ff");
+        assert!(lines[1]["decode_error"].is_string());
+        assert_eq!(lines[2]["payload"], "01");
+        assert!(lines[2].get("decode_error").is_none());
+    }
+
+    #[test]
+    fn test_append_cuts_torn_last_line() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("torn.jsonl");
+        std::fs::write(&path, "{\"sequence\":0}\n{\"sequence\":1}\n{\"seq").unwrap();
+        let mut sink = JsonLinesSink::new_with_options(
+            path.to_str().unwrap().to_string(),
+            FileSinkOptions {
+                open_mode: OpenMode::Append,
+                ..Default::default()
+            },
+            PayloadEncoding::Base64,
+            None,
+        )
+        .unwrap();
+        assert_eq!(sink.messages_written, 2);
+        assert_eq!(sink.bytes_written, 30);
+
+        let message = MessageEnvelope::new(2, "conn".to_string(), None, vec![b"data".to_vec()]);
+        sink.write(&message).unwrap();
+        sink.flush().unwrap();
+        let sequences: Vec<_> = read_lines(&path)
+            .iter()
+            .map(|line| line["sequence"].as_u64().unwrap())
+            .collect();
+        assert_eq!(sequences, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_rotates_on_message_count() {
+        let temp_dir = tempdir().unwrap();
+        let base = temp_dir.path().join("rotate.jsonl");
+        let mut sink = JsonLinesSink::new_with_options(
+            base.to_str().unwrap().to_string(),
+            FileSinkOptions {
+                rotation: RotationPolicy {
+                    max_messages: Some(2),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            PayloadEncoding::Base64,
+            None,
+        )
+        .unwrap();
+        for sequence in 0..5 {
+            let message =
+                MessageEnvelope::new(sequence, "conn".to_string(), None, vec![b"data".to_vec()]);
+            sink.write(&message).unwrap();
+        }
+        sink.flush().unwrap();
+
+        let mut files: Vec<_> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        assert_eq!(files.len(), 3);
+        let sequences: Vec<_> = files
+            .iter()
+            .flat_map(|path| read_lines(path))
+            .map(|line| line["sequence"].as_u64().unwrap())
+            .collect();
+        assert_eq!(sequences, vec![0, 1, 2, 3, 4]);
+    }
+}
@@ -1,30 +1,371 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::message_envelope::MessageEnvelope;
 use crate::sink::{Sink, SinkError};
 
 use getset::Getters;
+use log::info;
+
+/// Windows the message rate is averaged over, in seconds.
+pub const RATE_WINDOWS_S: [u64; 3] = [1, 10, 60];
+/// Upper bounds of the payload size buckets in bytes. Larger payloads land in
+/// one more bucket at the end.
+pub const SIZE_BUCKETS: [usize; 8] = [64, 256, 1024, 4096, 16384, 65536, 262144, 1048576];
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Messages and payload bytes of one topic.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TopicCount {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+/// Smallest, mean and largest time between two messages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GapStats {
+    pub min: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+}
+
+/// Statistics of the messages counted since the last reset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CounterSummary {
+    pub messages: u64,
+    pub bytes: u64,
+    /// Messages per second over every window of `RATE_WINDOWS_S`
+    pub rates: Vec<(u64, f64)>,
+    /// Message count of every bucket of `SIZE_BUCKETS`, plus the overflow bucket
+    pub sizes: Vec<u64>,
+    /// `None` until two messages arrived
+    pub gaps: Option<GapStats>,
+    /// Counts by topic, sorted by topic. Messages without a topic are under `-`.
+    pub topics: Vec<(String, TopicCount)>,
+}
+
+impl std::fmt::Display for CounterSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} messages, {} bytes", self.messages, self.bytes)?;
+        let rates: Vec<String> = self
+            .rates
+            .iter()
+            .map(|(window, rate)| format!("{}s {:.1}", window, rate))
+            .collect();
+        write!(f, "; msg/s {}", rates.join(", "))?;
+        if let Some(gaps) = &self.gaps {
+            write!(
+                f,
+                "; gaps min {:?} mean {:?} max {:?}",
+                gaps.min, gaps.mean, gaps.max
+            )?;
+        }
+        let sizes: Vec<String> = self
+            .sizes
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(bucket, count)| match SIZE_BUCKETS.get(bucket) {
+                Some(bound) => format!("<{} {}", bound, count),
+                None => format!(">={} {}", SIZE_BUCKETS[SIZE_BUCKETS.len() - 1], count),
+            })
+            .collect();
+        write!(f, "; sizes {}", sizes.join(", "))?;
+        for (topic, count) in &self.topics {
+            write!(f, "; {} {}/{}B", topic, count.messages, count.bytes)?;
+        }
+        Ok(())
+    }
+}
 
+/// Counts messages and keeps statistics about them: bytes, rates, payload
+/// sizes, gaps between messages and counts by topic. With a report interval
+/// it logs a summary and starts over once the interval has passed, checked
+/// on every message, flush and tick, so reports keep coming while the
+/// connection is quiet.
 #[derive(Debug, Getters)]
 pub struct MessageCounter {
     #[get = "pub"]
     message_count: u64,
+    #[get = "pub"]
+    byte_count: u64,
+    #[get = "pub"]
+    report_interval: Option<Duration>,
+    /// Messages received in each of the last seconds, oldest first
+    per_second: VecDeque<(u64, u64)>,
+    sizes: [u64; SIZE_BUCKETS.len() + 1],
+    last_arrival_ns: Option<u64>,
+    min_gap_ns: u64,
+    max_gap_ns: u64,
+    total_gap_ns: u64,
+    gap_count: u64,
+    topics: HashMap<String, TopicCount>,
+    /// When the statistics were last reset
+    started_ns: u64,
 }
 
 impl Sink for MessageCounter {
-    fn write(&mut self, _: &Vec<u8>) -> Result<(), SinkError> {
-        self.message_count += 1;
+    fn write(&mut self, message: &MessageEnvelope) -> Result<(), SinkError> {
+        let arrival_ns = *message.wall_time_ns();
+        self.count(message);
+        self.report_if_due(arrival_ns);
         Ok(())
     }
 
     fn flush(&mut self) -> Result<(), SinkError> {
+        self.report_if_due(now_ns());
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<(), SinkError> {
+        self.report_if_due(now_ns());
         Ok(())
     }
 }
 
+impl Default for MessageCounter {
+    fn default() -> Self {
+        MessageCounter::new()
+    }
+}
+
 impl MessageCounter {
     pub fn new() -> MessageCounter {
-        MessageCounter { message_count: 0 }
+        MessageCounter::new_with_interval(None)
+    }
+
+    /// Counter that logs and resets its statistics every `report_interval`.
+    pub fn new_with_interval(report_interval: Option<Duration>) -> MessageCounter {
+        MessageCounter {
+            message_count: 0,
+            byte_count: 0,
+            report_interval,
+            per_second: VecDeque::new(),
+            sizes: [0; SIZE_BUCKETS.len() + 1],
+            last_arrival_ns: None,
+            min_gap_ns: u64::MAX,
+            max_gap_ns: 0,
+            total_gap_ns: 0,
+            gap_count: 0,
+            topics: HashMap::new(),
+            started_ns: now_ns(),
+        }
     }
 
     pub fn clear_message_count(&mut self) {
         self.message_count = 0;
     }
+
+    /// Resets every statistic, as after a report.
+    pub fn clear_statistics(&mut self) {
+        *self = MessageCounter::new_with_interval(self.report_interval);
+    }
+
+    fn count(&mut self, message: &MessageEnvelope) {
+        let arrival_ns = *message.wall_time_ns();
+        let len = message.payload_len();
+        self.message_count += 1;
+        self.byte_count += len as u64;
+
+        let second = arrival_ns / NANOS_PER_SECOND;
+        match self.per_second.back_mut() {
+            Some((last, count)) if *last == second => *count += 1,
+            _ => self.per_second.push_back((second, 1)),
+        }
+        let oldest = second.saturating_sub(RATE_WINDOWS_S[RATE_WINDOWS_S.len() - 1]);
+        while self.per_second.front().is_some_and(|(s, _)| *s <= oldest) {
+            self.per_second.pop_front();
+        }
+
+        let bucket = SIZE_BUCKETS.partition_point(|bound| *bound <= len);
+        self.sizes[bucket] += 1;
+
+        if let Some(last) = self.last_arrival_ns {
+            let gap = arrival_ns.saturating_sub(last);
+            self.min_gap_ns = self.min_gap_ns.min(gap);
+            self.max_gap_ns = self.max_gap_ns.max(gap);
+            self.total_gap_ns += gap;
+            self.gap_count += 1;
+        }
+        self.last_arrival_ns = Some(arrival_ns);
+
+        let topic = message.topic().clone().unwrap_or_else(|| "-".to_string());
+        let topic_count = self.topics.entry(topic).or_default();
+        topic_count.messages += 1;
+        topic_count.bytes += len as u64;
+    }
+
+    /// Statistics as of now.
+    pub fn summary(&self) -> CounterSummary {
+        self.summary_at(now_ns())
+    }
+
+    /// Statistics as of `now_ns`, which decides the rate windows.
+    pub fn summary_at(&self, now_ns: u64) -> CounterSummary {
+        let now_s = now_ns / NANOS_PER_SECOND;
+        let rates = RATE_WINDOWS_S
+            .iter()
+            .map(|window| {
+                let in_window: u64 = self
+                    .per_second
+                    .iter()
+                    .filter(|(second, _)| *second + window > now_s && *second <= now_s)
+                    .map(|(_, count)| count)
+                    .sum();
+                (*window, in_window as f64 / *window as f64)
+            })
+            .collect();
+        let gaps = match self.gap_count {
+            0 => None,
+            count => Some(GapStats {
+                min: Duration::from_nanos(self.min_gap_ns),
+                mean: Duration::from_nanos(self.total_gap_ns / count),
+                max: Duration::from_nanos(self.max_gap_ns),
+            }),
+        };
+        let mut topics: Vec<(String, TopicCount)> = self
+            .topics
+            .iter()
+            .map(|(topic, count)| (topic.clone(), *count))
+            .collect();
+        topics.sort_by(|(a, _), (b, _)| a.cmp(b));
+        CounterSummary {
+            messages: self.message_count,
+            bytes: self.byte_count,
+            rates,
+            sizes: self.sizes.to_vec(),
+            gaps,
+            topics,
+        }
+    }
+
+    fn report_if_due(&mut self, now_ns: u64) {
+        let Some(interval) = self.report_interval else {
+            return;
+        };
+        if now_ns.saturating_sub(self.started_ns) < interval.as_nanos() as u64 {
+            return;
+        }
+        info!(
+            "Message counter over the last {:?}: {}",
+            interval,
+            self.summary_at(now_ns)
+        );
+        self.clear_statistics();
+        self.started_ns = now_ns;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START_NS: u64 = 1_704_164_645 * NANOS_PER_SECOND;
+
+    fn message(offset_ms: u64, topic: Option<&str>, len: usize) -> MessageEnvelope {
+        MessageEnvelope::with_timestamps(
+            0,
+            START_NS + offset_ms * 1_000_000,
+            0,
+            "conn".to_string(),
+            topic.map(str::to_string),
+            vec![vec![0u8; len]],
+        )
+    }
+
+    #[test]
+    fn test_counts_bytes_sizes_and_topics() {
+        let mut counter = MessageCounter::new();
+        counter.write(&message(0, Some("a"), 10)).unwrap();
+        counter.write(&message(100, Some("b"), 300)).unwrap();
+        counter.write(&message(400, None, 2_000_000)).unwrap();
+
+        let summary = counter.summary_at(START_NS + 500_000_000);
+        assert_eq!(summary.messages, 3);
+        assert_eq!(summary.bytes, 2_000_310);
+        assert_eq!(summary.sizes, vec![1, 0, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(
+            summary.topics,
+            vec![
+                (
+                    "-".to_string(),
+                    TopicCount {
+                        messages: 1,
+                        bytes: 2_000_000
+                    }
+                ),
+                (
+                    "a".to_string(),
+                    TopicCount {
+                        messages: 1,
+                        bytes: 10
+                    }
+                ),
+                (
+                    "b".to_string(),
+                    TopicCount {
+                        messages: 1,
+                        bytes: 300
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            summary.gaps,
+            Some(GapStats {
+                min: Duration::from_millis(100),
+                mean: Duration::from_millis(200),
+                max: Duration::from_millis(300),
+            })
+        );
+    }
+
+    #[test]
+    fn test_rates_over_sliding_windows() {
+        let mut counter = MessageCounter::new();
+        // 20 messages in each of the first 30 seconds
+        for offset_ms in (0..30_000).step_by(50) {
+            counter.write(&message(offset_ms, None, 1)).unwrap();
+        }
+        let summary = counter.summary_at(START_NS + 29_999_000_000);
+        assert_eq!(summary.rates, vec![(1, 20.0), (10, 20.0), (60, 10.0)]);
+        // Ten seconds later the short windows are empty
+        let summary = counter.summary_at(START_NS + 40_000_000_000);
+        assert_eq!(summary.rates[0], (1, 0.0));
+        assert_eq!(summary.rates[1], (10, 0.0));
+    }
+
+    #[test]
+    fn test_reports_and_resets_after_interval() {
+        let mut counter = MessageCounter::new_with_interval(Some(Duration::from_secs(10)));
+        counter.started_ns = START_NS;
+        counter.write(&message(0, None, 5)).unwrap();
+        counter.write(&message(9_000, None, 5)).unwrap();
+        assert_eq!(*counter.message_count(), 2);
+        counter.write(&message(10_000, None, 5)).unwrap();
+        assert_eq!(*counter.message_count(), 0);
+        assert_eq!(counter.summary().gaps, None);
+        counter.write(&message(11_000, None, 5)).unwrap();
+        assert_eq!(*counter.message_count(), 1);
+
+        // A quiet connection is still reported once the interval passed
+        counter.started_ns = now_ns() - 10 * NANOS_PER_SECOND;
+        counter.tick().unwrap();
+        assert_eq!(*counter.message_count(), 0);
+        counter.write(&message(12_000, None, 5)).unwrap();
+
+        counter.clear_message_count();
+        assert_eq!(*counter.message_count(), 0);
+        assert_eq!(*counter.byte_count(), 5);
+        counter.clear_statistics();
+        assert_eq!(*counter.byte_count(), 0);
+    }
 }
@@ -1,5 +1,7 @@
 pub mod compressed_file_sink;
 pub mod console_sink;
 pub mod file_sink;
+pub mod json_lines_sink;
 pub mod message_counter;
 pub mod raw_file_sink;
+pub mod rotation;
@@ -1,10 +1,42 @@
+use std::fs::OpenOptions;
 use std::io::Write;
 use std::time::{Duration, Instant};
 
 use getset::Getters;
 use log::{error, info};
+use prometheus::Histogram;
 
-use crate::sink::{Sink, SinkError};
+use crate::sink::SinkError;
+use crate::utils::filename::insert_before_extension;
+
+/// How a file sink treats a file that already exists. None of the modes
+/// truncate, so a restart can never wipe an earlier recording.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OpenMode {
+    /// Continue writing at the end of the existing file
+    Append,
+    /// Refuse to start if the file exists
+    FailIfExists,
+    /// Write to `name_1.ext`, `name_2.ext`, ... instead of an existing file
+    #[default]
+    CreateUnique,
+}
+
+impl TryFrom<&str> for OpenMode {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "append" => Ok(OpenMode::Append),
+            "fail-if-exists" => Ok(OpenMode::FailIfExists),
+            "create-unique" => Ok(OpenMode::CreateUnique),
+            _ => Err(format!(
+                "Unknown open mode '{}', expected append, fail-if-exists or create-unique",
+                value
+            )),
+        }
+    }
+}
 
 #[derive(Debug, Getters)]
 pub struct RawFileSink {
@@ -15,23 +47,31 @@ pub struct RawFileSink {
     flush_time: Duration,
     #[get = "pub"]
     last_flush: Instant,
+    /// Observes how long every flush takes
+    flush_latency: Option<Histogram>,
 }
 
-impl Sink for RawFileSink {
-    fn write(&mut self, data: &Vec<u8>) -> Result<(), SinkError> {
+impl RawFileSink {
+    pub fn write(&mut self, data: &[u8]) -> Result<(), SinkError> {
         info!(
             "Writing to file {} with {} bytes",
             self.filename,
             data.len()
         );
-        self.writer.write_all(data.as_slice())?;
-        if self.last_flush.elapsed() >= self.flush_time {
+        self.writer.write_all(data)?;
+        self.flush_if_due()
+    }
+
+    /// Flushes buffered data once `flush_time` passed since the last flush.
+    pub fn flush_if_due(&mut self) -> Result<(), SinkError> {
+        if !self.writer.buffer().is_empty() && self.last_flush.elapsed() >= self.flush_time {
             self.flush()?
         }
         Ok(())
     }
 
-    fn flush(&mut self) -> Result<(), SinkError> {
+    pub fn flush(&mut self) -> Result<(), SinkError> {
+        let _timer = self.flush_latency.as_ref().map(Histogram::start_timer);
         match self.writer.flush() {
             Ok(_) => info!("Flushing writer"),
             Err(e) => {
@@ -42,19 +82,161 @@ impl Sink for RawFileSink {
         self.last_flush = Instant::now();
         Ok(())
     }
-}
 
-impl RawFileSink {
     pub fn new(filename: String, flush_time_s: i32) -> std::io::Result<Self> {
-        let file = std::fs::File::create(&filename)?; // Opens or creates the file
+        RawFileSink::new_with_mode(filename, flush_time_s, OpenMode::default())
+    }
+
+    /// Opens the file according to `open_mode`. The resulting filename can
+    /// differ from the requested one when `CreateUnique` had to pick a new name.
+    pub fn new_with_mode(
+        filename: String,
+        flush_time_s: i32,
+        open_mode: OpenMode,
+    ) -> std::io::Result<Self> {
+        let flush_time = u64::try_from(flush_time_s)
+            .map(Duration::from_secs)
+            .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Flush time {}s is negative", flush_time_s),
+                )
+            })?;
+        let (filename, file) = match open_mode {
+            OpenMode::Append => {
+                let file = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(&filename)?;
+                (filename, file)
+            }
+            OpenMode::FailIfExists => {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&filename)?;
+                (filename, file)
+            }
+            OpenMode::CreateUnique => open_unique(&filename)?,
+        };
         let writer = std::io::BufWriter::new(file); // Wraps the file in BufWriter
-        let flush_time = Duration::new(flush_time_s.try_into().unwrap(), 0);
         let last_flush = Instant::now();
         Ok(RawFileSink {
             filename,
             writer,
             flush_time,
             last_flush,
+            flush_latency: None,
         })
     }
+
+    pub fn set_flush_latency(&mut self, histogram: Histogram) {
+        self.flush_latency = Some(histogram);
+    }
+}
+
+/// Creates `filename`, or the first free `_N` variant of it. `create_new`
+/// makes the check and the creation atomic, so two sinks can never share a file.
+fn open_unique(filename: &str) -> std::io::Result<(String, std::fs::File)> {
+    let mut candidate = filename.to_string();
+    let mut counter = 1;
+    loop {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+        {
+            Ok(file) => {
+                if candidate != filename {
+                    info!("{} already exists, writing to {}", filename, candidate);
+                }
+                return Ok((candidate, file));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                candidate = insert_before_extension(filename, &format!("_{}", counter));
+                counter += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_open_mode_from_str() {
+        assert_eq!(OpenMode::try_from("append"), Ok(OpenMode::Append));
+        assert_eq!(
+            OpenMode::try_from("fail-if-exists"),
+            Ok(OpenMode::FailIfExists)
+        );
+        assert_eq!(
+            OpenMode::try_from("create-unique"),
+            Ok(OpenMode::CreateUnique)
+        );
+        assert!(OpenMode::try_from("truncate").is_err());
+    }
+
+    #[test]
+    fn test_append_keeps_existing_data() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("append.rec");
+        std::fs::write(&path, b"existing").unwrap();
+
+        let mut sink =
+            RawFileSink::new_with_mode(path.to_str().unwrap().to_string(), 0, OpenMode::Append)
+                .unwrap();
+        sink.write(b" more").unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"existing more");
+    }
+
+    #[test]
+    fn test_fail_if_exists() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("exists.rec");
+        std::fs::write(&path, b"existing").unwrap();
+
+        let result = RawFileSink::new_with_mode(
+            path.to_str().unwrap().to_string(),
+            0,
+            OpenMode::FailIfExists,
+        );
+
+        assert_eq!(
+            result.unwrap_err().kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"existing");
+    }
+
+    #[test]
+    fn test_refuses_negative_flush_time() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("negative.rec");
+
+        let result = RawFileSink::new(path.to_str().unwrap().to_string(), -1);
+
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_create_unique_picks_free_name() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("unique.rec");
+        std::fs::write(&path, b"existing").unwrap();
+        std::fs::write(temp_dir.path().join("unique_1.rec"), b"existing").unwrap();
+
+        let sink = RawFileSink::new(path.to_str().unwrap().to_string(), 0).unwrap();
+
+        assert_eq!(
+            sink.filename(),
+            temp_dir.path().join("unique_2.rec").to_str().unwrap()
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"existing");
+    }
 }
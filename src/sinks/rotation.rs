@@ -0,0 +1,215 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+
+use crate::utils::filename::insert_before_extension;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RotationInterval {
+    Hourly,
+    Daily,
+}
+
+impl RotationInterval {
+    fn seconds(&self) -> u64 {
+        match self {
+            RotationInterval::Hourly => 60 * 60,
+            RotationInterval::Daily => 24 * 60 * 60,
+        }
+    }
+
+    /// Index of the UTC hour or day that `time` falls in.
+    pub fn period(&self, time: SystemTime) -> u64 {
+        let seconds = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        seconds / self.seconds()
+    }
+}
+
+impl TryFrom<&str> for RotationInterval {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "hourly" => Ok(RotationInterval::Hourly),
+            "daily" => Ok(RotationInterval::Daily),
+            _ => Err(format!(
+                "Unknown rotation interval '{}', expected hourly or daily",
+                value
+            )),
+        }
+    }
+}
+
+/// When a file sink should close its current file and start a new one.
+/// Every limit is optional, the first one reached triggers the rotation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RotationPolicy {
+    pub max_bytes: Option<u64>,
+    pub max_messages: Option<u64>,
+    pub interval: Option<RotationInterval>,
+}
+
+impl RotationPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.max_bytes.is_some() || self.max_messages.is_some() || self.interval.is_some()
+    }
+
+    /// Decides whether the next record of `next_record_len` bytes belongs in a new file.
+    /// A file always takes at least one record, so oversized records are not split.
+    pub fn should_rotate(
+        &self,
+        bytes_written: u64,
+        messages_written: u64,
+        next_record_len: u64,
+        opened_at: SystemTime,
+        now: SystemTime,
+    ) -> bool {
+        if messages_written == 0 {
+            return false;
+        }
+        let over_bytes = self
+            .max_bytes
+            .is_some_and(|max| bytes_written + next_record_len > max);
+        let over_messages = self.max_messages.is_some_and(|max| messages_written >= max);
+        let new_period = self
+            .interval
+            .is_some_and(|interval| interval.period(now) != interval.period(opened_at));
+        over_bytes || over_messages || new_period
+    }
+}
+
+/// Inserts the UTC time the file was opened before its extension, for example
+/// `tcp___localhost_5555_test.rec` becomes `tcp___localhost_5555_test_20240102T030405Z.rec`.
+pub fn rotated_filename(base_filename: &str, opened_at: SystemTime) -> String {
+    let timestamp = DateTime::<Utc>::from(opened_at).format("%Y%m%dT%H%M%SZ");
+    insert_before_extension(base_filename, &format!("_{}", timestamp))
+}
+
+/// Names the files of one rotating sink. Files opened within the same second
+/// get a counter, `..._20240102T030405Z_1.rec`, so small size or message
+/// limits never reuse the name of the file just closed.
+#[derive(Debug, Default)]
+pub struct RotatedNames {
+    /// Last timestamped name and how many files got it before
+    last: Option<(String, u32)>,
+}
+
+impl RotatedNames {
+    pub fn next(&mut self, base_filename: &str, opened_at: SystemTime) -> String {
+        let name = rotated_filename(base_filename, opened_at);
+        let counter = match &self.last {
+            Some((last, counter)) if *last == name => counter + 1,
+            _ => 0,
+        };
+        let filename = match counter {
+            0 => name.clone(),
+            counter => insert_before_extension(&name, &format!("_{}", counter)),
+        };
+        self.last = Some((name, counter));
+        filename
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // 2024-01-02T03:04:05Z
+    const OPENED_AT_S: u64 = 1_704_164_645;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn test_disabled_policy_never_rotates() {
+        let policy = RotationPolicy::default();
+        assert!(!policy.is_enabled());
+        assert!(!policy.should_rotate(u64::MAX / 2, 1_000_000, 10, at(0), at(OPENED_AT_S)));
+    }
+
+    #[test]
+    fn test_rotate_on_bytes() {
+        let policy = RotationPolicy {
+            max_bytes: Some(100),
+            ..Default::default()
+        };
+        let now = at(OPENED_AT_S);
+        assert!(!policy.should_rotate(90, 3, 10, now, now));
+        assert!(policy.should_rotate(91, 3, 10, now, now));
+        // An empty file takes the record even when it is too large
+        assert!(!policy.should_rotate(0, 0, 1000, now, now));
+    }
+
+    #[test]
+    fn test_rotate_on_messages() {
+        let policy = RotationPolicy {
+            max_messages: Some(2),
+            ..Default::default()
+        };
+        let now = at(OPENED_AT_S);
+        assert!(!policy.should_rotate(10, 1, 10, now, now));
+        assert!(policy.should_rotate(10, 2, 10, now, now));
+    }
+
+    #[test]
+    fn test_rotate_on_hour_boundary() {
+        let policy = RotationPolicy {
+            interval: Some(RotationInterval::Hourly),
+            ..Default::default()
+        };
+        let opened_at = at(OPENED_AT_S);
+        // 03:59:59 is still in the same hour
+        assert!(!policy.should_rotate(10, 1, 10, opened_at, at(OPENED_AT_S + 3354)));
+        assert!(policy.should_rotate(10, 1, 10, opened_at, at(OPENED_AT_S + 3355)));
+    }
+
+    #[test]
+    fn test_rotate_on_day_boundary() {
+        let policy = RotationPolicy {
+            interval: Some(RotationInterval::Daily),
+            ..Default::default()
+        };
+        let opened_at = at(OPENED_AT_S);
+        assert!(!policy.should_rotate(10, 1, 10, opened_at, at(OPENED_AT_S + 3600)));
+        assert!(policy.should_rotate(10, 1, 10, opened_at, at(OPENED_AT_S + 86400)));
+    }
+
+    #[test]
+    fn test_rotated_filename() {
+        let name = rotated_filename("/data/tcp___localhost_5555_test.rec", at(OPENED_AT_S));
+        assert_eq!(name, "/data/tcp___localhost_5555_test_20240102T030405Z.rec");
+    }
+
+    #[test]
+    fn test_rotated_names_count_within_one_second() {
+        let mut names = RotatedNames::default();
+        let base = "/data/test.rec";
+        assert_eq!(
+            names.next(base, at(OPENED_AT_S)),
+            "/data/test_20240102T030405Z.rec"
+        );
+        assert_eq!(
+            names.next(base, at(OPENED_AT_S) + Duration::from_millis(300)),
+            "/data/test_20240102T030405Z_1.rec"
+        );
+        assert_eq!(
+            names.next(base, at(OPENED_AT_S) + Duration::from_millis(600)),
+            "/data/test_20240102T030405Z_2.rec"
+        );
+        assert_eq!(
+            names.next(base, at(OPENED_AT_S + 1)),
+            "/data/test_20240102T030406Z.rec"
+        );
+    }
+
+    #[test]
+    fn test_rotated_filename_without_extension() {
+        let name = rotated_filename("/nonexistent_dir/recording", at(OPENED_AT_S));
+        assert_eq!(name, "/nonexistent_dir/recording_20240102T030405Z");
+    }
+}
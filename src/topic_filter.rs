@@ -0,0 +1,163 @@
+use regex::bytes::Regex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TopicMatchMode {
+    /// Records every topic starting with one of the configured topics, like ZMQ does
+    Prefix,
+    /// Records only topics equal to one of the configured topics
+    Exact,
+    /// Records topics matching one of the configured regular expressions
+    Regex,
+}
+
+impl TryFrom<&str> for TopicMatchMode {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "prefix" => Ok(TopicMatchMode::Prefix),
+            "exact" => Ok(TopicMatchMode::Exact),
+            "regex" => Ok(TopicMatchMode::Regex),
+            _ => Err(format!(
+                "Unknown topic match mode '{}', expected prefix, exact or regex",
+                value
+            )),
+        }
+    }
+}
+
+/// The set of topics a connection records and how received topics are matched against it.
+///
+/// An empty filter subscribes to everything and treats every frame as payload.
+#[derive(Debug, Clone)]
+pub struct TopicFilter {
+    topics: Vec<String>,
+    mode: TopicMatchMode,
+    patterns: Vec<Regex>,
+}
+
+impl TopicFilter {
+    pub fn new(topics: Vec<String>, mode: TopicMatchMode) -> Result<Self, regex::Error> {
+        let patterns = match mode {
+            TopicMatchMode::Regex => topics
+                .iter()
+                .map(|topic| Regex::new(topic))
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => vec![],
+        };
+        Ok(Self {
+            topics,
+            mode,
+            patterns,
+        })
+    }
+
+    /// A filter that records everything published on the connection.
+    pub fn all() -> Self {
+        Self {
+            topics: vec![],
+            mode: TopicMatchMode::Prefix,
+            patterns: vec![],
+        }
+    }
+
+    pub fn topics(&self) -> &Vec<String> {
+        &self.topics
+    }
+
+    pub fn mode(&self) -> TopicMatchMode {
+        self.mode
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.topics.is_empty()
+    }
+
+    /// The prefixes to hand to the ZMQ socket. Regular expressions cannot be
+    /// expressed as ZMQ subscriptions, so they subscribe to everything and
+    /// are filtered on receipt.
+    pub fn subscriptions(&self) -> Vec<&[u8]> {
+        match self.mode {
+            TopicMatchMode::Regex => vec![b"".as_slice()],
+            _ if self.topics.is_empty() => vec![b"".as_slice()],
+            _ => self.topics.iter().map(|topic| topic.as_bytes()).collect(),
+        }
+    }
+
+    pub fn matches(&self, topic: &[u8]) -> bool {
+        if self.topics.is_empty() {
+            return true;
+        }
+        match self.mode {
+            TopicMatchMode::Prefix => self
+                .topics
+                .iter()
+                .any(|expected| topic.starts_with(expected.as_bytes())),
+            TopicMatchMode::Exact => self
+                .topics
+                .iter()
+                .any(|expected| topic == expected.as_bytes()),
+            TopicMatchMode::Regex => self.patterns.iter().any(|pattern| pattern.is_match(topic)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(topics: &[&str], mode: TopicMatchMode) -> TopicFilter {
+        TopicFilter::new(topics.iter().map(|t| t.to_string()).collect(), mode).unwrap()
+    }
+
+    #[test]
+    fn test_match_mode_from_str() {
+        assert_eq!(
+            TopicMatchMode::try_from("prefix"),
+            Ok(TopicMatchMode::Prefix)
+        );
+        assert_eq!(TopicMatchMode::try_from("exact"), Ok(TopicMatchMode::Exact));
+        assert_eq!(TopicMatchMode::try_from("regex"), Ok(TopicMatchMode::Regex));
+        assert!(TopicMatchMode::try_from("glob").is_err());
+    }
+
+    #[test]
+    fn test_prefix_matches_multiple_topics() {
+        let filter = filter(&["md.", "orders."], TopicMatchMode::Prefix);
+        assert!(filter.matches(b"md.AAPL"));
+        assert!(filter.matches(b"orders.new"));
+        assert!(!filter.matches(b"fills.new"));
+        assert_eq!(
+            filter.subscriptions(),
+            vec![b"md.".as_slice(), b"orders.".as_slice()]
+        );
+    }
+
+    #[test]
+    fn test_exact_rejects_longer_topics() {
+        let filter = filter(&["test"], TopicMatchMode::Exact);
+        assert!(filter.matches(b"test"));
+        assert!(!filter.matches(b"testing"));
+    }
+
+    #[test]
+    fn test_regex_subscribes_to_everything() {
+        let filter = filter(&["^md\\.(AAPL|MSFT)$"], TopicMatchMode::Regex);
+        assert!(filter.matches(b"md.AAPL"));
+        assert!(!filter.matches(b"md.GOOG"));
+        assert_eq!(filter.subscriptions(), vec![b"".as_slice()]);
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        assert!(TopicFilter::new(vec!["(".to_string()], TopicMatchMode::Regex).is_err());
+    }
+
+    #[test]
+    fn test_empty_filter_matches_everything() {
+        let filter = TopicFilter::all();
+        assert!(filter.is_empty());
+        assert!(filter.matches(b"anything"));
+        assert_eq!(filter.subscriptions(), vec![b"".as_slice()]);
+    }
+}
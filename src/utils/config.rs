@@ -1,88 +1,1146 @@
+use crate::capture_socket::{SocketConfig, SocketMode, SocketOptions, SocketType, TcpKeepalive};
+use crate::connection_registry::FileClaims;
+use crate::endpoint::Endpoint;
+use crate::message_decoding::dynamic::{load_descriptor_sets, MessageDecoder};
+use crate::metrics;
+use crate::security;
 use crate::sink::SinksEnum;
-use crate::sinks::compressed_file_sink::CompressedFileSink;
-use crate::sinks::console_sink::ConsoleSink;
+use crate::sink_worker::{OverflowPolicy, DEFAULT_QUEUE_SIZE};
+use crate::sinks::compressed_file_sink::{
+    CompressedFileSink, CompressionCodec, CompressionMode, CompressionOptions,
+};
+use crate::sinks::console_sink::{ConsoleOptions, ConsoleSink, DisplayMode};
+use crate::sinks::file_sink::FileSinkOptions;
+use crate::sinks::json_lines_sink::{JsonLinesSink, PayloadEncoding};
 use crate::sinks::message_counter::MessageCounter;
-use crate::{sinks::file_sink::FileSink, zmq_connection::ZmqConnection};
+use crate::sinks::raw_file_sink::OpenMode;
+use crate::sinks::rotation::{RotationInterval, RotationPolicy};
+use crate::topic_filter::{TopicFilter, TopicMatchMode};
+use crate::{
+    sinks::file_sink::FileSink,
+    zmq_connection::{MessageRecorderError, ZmqConnection},
+};
 
-use log::{error, warn};
+use log::{error, info, warn};
+use prost_reflect::DescriptorPool;
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
 
 use figment::{
     providers::{Format, Yaml},
     Figment,
 };
 
+#[derive(Debug, Deserialize)]
+struct Rotation {
+    max_bytes: Option<u64>,
+    max_messages: Option<u64>,
+    interval: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 struct Sink {
     sink_type: String,
     name: Option<String>,
     flush_time: Option<i32>,
+    codec: Option<String>,
     compression_level: Option<i32>,
+    compression_mode: Option<String>,
+    dictionary: Option<String>,
+    queue_size: Option<usize>,
+    overflow_policy: Option<String>,
+    rotation: Option<Rotation>,
+    open_mode: Option<String>,
+    payload_encoding: Option<String>,
+    display: Option<String>,
+    max_bytes: Option<usize>,
+    show_topic: Option<bool>,
+    show_timestamp: Option<bool>,
+    report_interval: Option<String>,
+    /// Also record the connection's socket monitor events
+    record_events: Option<bool>,
+}
+
+/// Which sources keys and credentials may be read from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SecretSources {
+    /// `file`, `env` and `value`, for the recorder's own config
+    All,
+    /// Only `value`, for configs sent by control clients, who must not get
+    /// to read the recorder's files and environment
+    InlineOnly,
+}
+
+/// Where a key or credential is read from, exactly one of them.
+#[derive(Debug, Deserialize)]
+struct SecretSource {
+    file: Option<String>,
+    env: Option<String>,
+    value: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Curve {
+    /// Needed when connecting
+    server_public_key: Option<SecretSource>,
+    public_key: SecretSource,
+    secret_key: SecretSource,
+}
+
+#[derive(Debug, Deserialize)]
+struct Plain {
+    username: SecretSource,
+    password: SecretSource,
+}
+
+#[derive(Debug, Deserialize)]
+struct Security {
+    curve: Option<Curve>,
+    plain: Option<Plain>,
+}
+
+/// How soon ZMQ reconnects after losing the peer, e.g. `100ms`. With
+/// `max_interval` the interval doubles after every failed attempt.
+#[derive(Debug, Deserialize)]
+struct Reconnect {
+    interval: Option<String>,
+    max_interval: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Keepalive {
+    /// Defaults to true when the block is present
+    enabled: Option<bool>,
+    idle: Option<String>,
+    interval: Option<String>,
+    count: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SocketOptionsConfig {
+    receive_hwm: Option<u32>,
+    receive_buffer: Option<u32>,
+    tcp_keepalive: Option<Keepalive>,
+    receive_timeout: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct Connections {
-    addr: String,
-    port: i32,
+    /// Full ZMQ endpoint such as `ipc:///tmp/feed.sock`, instead of `addr` and `port`
+    endpoint: Option<String>,
+    /// TCP host: IPv4 or IPv6 address, or host name
+    addr: Option<String>,
+    port: Option<i64>,
+    /// `sub` (default), `xsub`, `pull` or `router`
+    socket_type: Option<String>,
+    /// `connect` (default) or `bind`
+    mode: Option<String>,
+    security: Option<Security>,
+    reconnect: Option<Reconnect>,
+    socket_options: Option<SocketOptionsConfig>,
     topic: Option<String>,
+    topics: Option<Vec<String>>,
+    topic_match: Option<String>,
     file_extension: String,
+    /// Protobuf type of every payload, unless `message_types` has its topic
+    message_type: Option<String>,
+    /// Protobuf type by topic prefix
+    message_types: Option<HashMap<String, String>>,
     sinks: Option<Vec<Sink>>,
 }
 
 #[derive(Debug, Deserialize)]
-struct Config {
+struct Metrics {
+    /// Address of the Prometheus endpoint, e.g. `0.0.0.0:9100`
+    listen: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Control {
+    /// Address of the gRPC control service, e.g. `127.0.0.1:50051`
+    listen: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Zmq {
+    /// Threads of the context shared by all connections, ZMQ's default is 1
+    io_threads: Option<u32>,
+}
+
+/// The config file, read once and handed to every `read_*` function so they
+/// all see the same settings.
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    zmq: Option<Zmq>,
+    metrics: Option<Metrics>,
+    control: Option<Control>,
+    /// `FileDescriptorSet` files with the types named by `message_type(s)`
+    descriptor_sets: Option<Vec<String>>,
     connections: Vec<Connections>,
 }
 
-pub fn read_config(filename: &str) -> Vec<ZmqConnection> {
-    let config: Config = Figment::new()
+pub fn extract_config(filename: &str) -> Config {
+    Figment::new()
         .merge(Yaml::file(filename))
         .extract()
-        .unwrap();
+        .unwrap()
+}
+
+/// Builds the configured connections, decoding payloads with the types of `pool`.
+pub fn read_config(config: &Config, pool: &DescriptorPool) -> Vec<ZmqConnection> {
     let mut connections = Vec::new();
+    // Files of the connections built so far, they claim them for real once started
+    let files = FileClaims::default();
+
+    for conn_cfg in &config.connections {
+        let described = describe_connection(conn_cfg);
+        match build_connection(conn_cfg, pool, SecretSources::All, &files) {
+            Ok(zmq_conn) => {
+                // Cannot fail, the connection was checked against these
+                let _ = files.claim(&zmq_conn.sink_files());
+                connections.push(zmq_conn)
+            }
+            Err(e) => error!("Skipping connection to {}, {}", described, e),
+        }
+    }
+
+    connections
+}
+
+/// Builds a connection from one `connections` entry in YAML, the way the
+/// config file is read, reading secrets only from `secrets`. Sinks with a bad
+/// config are skipped with a warning, a connection whose sinks would write a
+/// file claimed in `files` is refused. Must be called from within a tokio runtime.
+pub fn parse_connection(
+    yaml: &str,
+    pool: &DescriptorPool,
+    secrets: SecretSources,
+    files: &FileClaims,
+) -> Result<ZmqConnection, String> {
+    let conn_cfg: Connections = Figment::new()
+        .merge(Yaml::string(yaml))
+        .extract()
+        .map_err(|e| format!("bad connection config: {}", e))?;
+    build_connection(&conn_cfg, pool, secrets, files)
+}
+
+/// Builds a sink from one `sinks` entry in YAML and registers it with the
+/// running `zmq_conn`, claiming its file in `files`. Must be called from
+/// within a tokio runtime.
+pub fn parse_sink(
+    yaml: &str,
+    zmq_conn: &ZmqConnection,
+    files: &FileClaims,
+) -> Result<String, String> {
+    let sink_cfg: Sink = Figment::new()
+        .merge(Yaml::string(yaml))
+        .extract()
+        .map_err(|e| format!("Bad sink config: {}", e))?;
+    add_sink(zmq_conn, &sink_cfg, Some(files))
+}
+
+/// Descriptor sets named in the config, for decoders of connections added later.
+pub fn read_descriptor_pool(config: &Config) -> DescriptorPool {
+    load_pool(config)
+}
+
+/// The connection's endpoint as configured, for error messages.
+fn describe_connection(conn_cfg: &Connections) -> String {
+    match (&conn_cfg.endpoint, &conn_cfg.addr, conn_cfg.port) {
+        (Some(endpoint), _, _) => endpoint.clone(),
+        (None, Some(addr), Some(port)) => format!("{}:{}", addr, port),
+        (None, Some(addr), None) => addr.clone(),
+        (None, None, _) => "an unnamed endpoint".to_string(),
+    }
+}
+
+fn build_endpoint(conn_cfg: &Connections) -> Result<Endpoint, String> {
+    match (&conn_cfg.endpoint, &conn_cfg.addr, conn_cfg.port) {
+        (Some(endpoint), None, None) => Endpoint::try_from(endpoint.as_str()),
+        (None, Some(addr), Some(port)) => {
+            let port = u16::try_from(port).map_err(|_| format!("bad port {}", port))?;
+            Endpoint::tcp(addr, port)
+        }
+        (Some(_), _, _) => Err("set either endpoint or addr and port, not both".to_string()),
+        (None, _, _) => Err("set either endpoint or addr and port".to_string()),
+    }
+}
+
+fn build_socket_config(
+    conn_cfg: &Connections,
+    secrets: SecretSources,
+) -> Result<SocketConfig, String> {
+    let mode = match conn_cfg.mode.as_deref() {
+        Some(mode) => SocketMode::try_from(mode)?,
+        None => SocketMode::default(),
+    };
+    let security = match &conn_cfg.security {
+        Some(security_cfg) => {
+            let security = build_security(security_cfg, secrets)
+                .map_err(|e| format!("bad security: {}", e))?;
+            security
+                .check_mode(mode)
+                .map_err(|e| format!("bad security: {}", e))?;
+            Some(security)
+        }
+        None => None,
+    };
+    let interval = |interval: &Option<String>| {
+        interval
+            .as_deref()
+            .map(|interval| {
+                humantime::parse_duration(interval)
+                    .map_err(|e| format!("bad reconnect interval '{}': {}", interval, e))
+            })
+            .transpose()
+    };
+    let (reconnect_interval, reconnect_interval_max) = match &conn_cfg.reconnect {
+        Some(reconnect) => (
+            interval(&reconnect.interval)?,
+            interval(&reconnect.max_interval)?,
+        ),
+        None => (None, None),
+    };
+    Ok(SocketConfig {
+        socket_type: match conn_cfg.socket_type.as_deref() {
+            Some(socket_type) => SocketType::try_from(socket_type)?,
+            None => SocketType::default(),
+        },
+        mode,
+        security,
+        reconnect_interval,
+        reconnect_interval_max,
+        options: match &conn_cfg.socket_options {
+            Some(options_cfg) => build_socket_options(options_cfg)
+                .map_err(|e| format!("bad socket options: {}", e))?,
+            None => SocketOptions::default(),
+        },
+    })
+}
+
+fn build_socket_options(options_cfg: &SocketOptionsConfig) -> Result<SocketOptions, String> {
+    let int = |name: &str, value: Option<u32>| {
+        value
+            .map(|value| {
+                i32::try_from(value).map_err(|_| format!("{} {} is too large", name, value))
+            })
+            .transpose()
+    };
+    let duration = |name: &str, value: &Option<String>| {
+        value
+            .as_deref()
+            .map(|value| {
+                humantime::parse_duration(value)
+                    .map_err(|e| format!("bad {} '{}': {}", name, value, e))
+            })
+            .transpose()
+    };
+    // The kernel counts keepalive times in whole seconds
+    let seconds = |name: &str, value: &Option<String>| match duration(name, value)? {
+        Some(value) if value.as_secs() == 0 || value.subsec_nanos() != 0 => {
+            Err(format!("{} must be a whole number of seconds", name))
+        }
+        value => Ok(value),
+    };
+    let tcp_keepalive = match &options_cfg.tcp_keepalive {
+        Some(keepalive) => Some(TcpKeepalive {
+            enabled: keepalive.enabled.unwrap_or(true),
+            idle: seconds("tcp_keepalive idle", &keepalive.idle)?,
+            interval: seconds("tcp_keepalive interval", &keepalive.interval)?,
+            count: int("tcp_keepalive count", keepalive.count)?,
+        }),
+        None => None,
+    };
+    Ok(SocketOptions {
+        receive_hwm: int("receive_hwm", options_cfg.receive_hwm)?,
+        receive_buffer: int("receive_buffer", options_cfg.receive_buffer)?,
+        tcp_keepalive,
+        receive_timeout: duration("receive_timeout", &options_cfg.receive_timeout)?,
+    })
+}
+
+fn build_security(
+    security_cfg: &Security,
+    secrets: SecretSources,
+) -> Result<security::Security, String> {
+    match (&security_cfg.curve, &security_cfg.plain) {
+        (Some(curve), None) => {
+            let key = |name: &str, source: &SecretSource| {
+                read_secret(name, source, secrets).and_then(|text| {
+                    security::parse_curve_key(&text).map_err(|e| format!("{}: {}", name, e))
+                })
+            };
+            let curve = security::Security::Curve {
+                server_key: match &curve.server_public_key {
+                    Some(source) => Some(key("server_public_key", source)?),
+                    None => None,
+                },
+                public_key: key("public_key", &curve.public_key)?,
+                secret_key: key("secret_key", &curve.secret_key)?,
+            };
+            security::check_curve_support()?;
+            Ok(curve)
+        }
+        (None, Some(plain)) => Ok(security::Security::Plain {
+            username: read_secret("username", &plain.username, secrets)?,
+            password: read_secret("password", &plain.password, secrets)?,
+        }),
+        (Some(_), Some(_)) => Err("set either curve or plain, not both".to_string()),
+        (None, None) => Err("set curve or plain".to_string()),
+    }
+}
+
+/// Reads a key or credential, trimming the line break a file usually ends with.
+fn read_secret(
+    name: &str,
+    source: &SecretSource,
+    secrets: SecretSources,
+) -> Result<String, String> {
+    if secrets == SecretSources::InlineOnly && (source.file.is_some() || source.env.is_some()) {
+        return Err(format!(
+            "{}: file and env cannot be used here, set the value inline",
+            name
+        ));
+    }
+    let text = match (&source.file, &source.env, &source.value) {
+        (Some(file), None, None) => std::fs::read_to_string(file)
+            .map_err(|e| format!("{}: cannot read file '{}': {}", name, file, e))?,
+        (None, Some(env), None) => std::env::var(env).map_err(|e| {
+            format!(
+                "{}: cannot read environment variable '{}': {}",
+                name, env, e
+            )
+        })?,
+        (None, None, Some(value)) => value.clone(),
+        _ => return Err(format!("{}: set exactly one of file, env or value", name)),
+    };
+    Ok(text.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Builds the connection and its sinks. Refuses a connection whose sinks
+/// would write one file twice, or a file claimed in `files`.
+fn build_connection(
+    conn_cfg: &Connections,
+    pool: &DescriptorPool,
+    secrets: SecretSources,
+    files: &FileClaims,
+) -> Result<ZmqConnection, String> {
+    let endpoint = build_endpoint(conn_cfg).map_err(|e| format!("bad endpoint: {}", e))?;
+    let decoder = build_decoder(pool, conn_cfg).map_err(|e| format!("bad message types: {}", e))?;
+    let socket_config = build_socket_config(conn_cfg, secrets)?;
+    let topic_filter = build_topic_filter(
+        conn_cfg.topic.clone(),
+        conn_cfg.topics.clone(),
+        conn_cfg.topic_match.clone(),
+    )
+    .map_err(|e| format!("bad topics: {}", e))?;
+    if !topic_filter.is_empty() && !socket_config.socket_type.has_topics() {
+        return Err(format!(
+            "{} sockets receive no topics, leave out topic and topics",
+            socket_config.socket_type.name()
+        ));
+    }
+    let mut zmq_conn =
+        ZmqConnection::new_with_owned(endpoint, topic_filter, conn_cfg.file_extension.clone());
+    zmq_conn.set_socket_config(socket_config);
+    if let Some(decoder) = decoder {
+        zmq_conn.set_decoder(Arc::new(decoder));
+    }
+    let sinks = conn_cfg.sinks.as_deref().unwrap_or_default();
+    // Different endpoints and topics can still map to the same file name,
+    // e.g. ipc:///tmp/feeds/md.sock and ipc:///tmp/feeds_md.sock
+    let mut own = HashSet::new();
+    for filename in sinks
+        .iter()
+        .filter_map(|sink_cfg| sink_filename(&zmq_conn, &sink_cfg.sink_type))
+    {
+        if files.is_claimed(&filename) {
+            return Err(format!(
+                "its sinks would write to {} like another connection, give it another file_extension",
+                filename
+            ));
+        }
+        if !own.insert(filename.clone()) {
+            return Err(format!("two of its sinks would write to {}", filename));
+        }
+    }
+    for sink_cfg in sinks {
+        if let Err(e) = add_sink(&zmq_conn, sink_cfg, None) {
+            warn!("Skipping sink '{}': {}", sink_cfg.sink_type, e);
+        }
+    }
+    Ok(zmq_conn)
+}
 
-    for conn_cfg in config.connections {
-        let zmq_conn = ZmqConnection::new_with_owned(
-            conn_cfg.addr,
-            conn_cfg.port.to_string(),
-            conn_cfg.topic,
-            conn_cfg.file_extension,
+/// File a sink of `sink_type` writes for `zmq_conn`, if it writes one.
+fn sink_filename(zmq_conn: &ZmqConnection, sink_type: &str) -> Option<String> {
+    match sink_type {
+        "File Sink" | "Compressed Sink" => Some(zmq_conn.get_filename()),
+        "JSON Lines Sink" => Some(jsonl_filename(zmq_conn)),
+        _ => None,
+    }
+}
+
+fn jsonl_filename(zmq_conn: &ZmqConnection) -> String {
+    Path::new(&zmq_conn.get_filename())
+        .with_extension("jsonl")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Builds the sink and registers it, returning its name. A sink added to a
+/// running connection claims its file in `files` before opening it, the
+/// sinks of a new connection claim theirs when it starts.
+fn add_sink(
+    zmq_conn: &ZmqConnection,
+    sink_cfg: &Sink,
+    files: Option<&FileClaims>,
+) -> Result<String, String> {
+    let sink_name = sink_cfg
+        .name
+        .clone()
+        .unwrap_or_else(|| sink_cfg.sink_type.clone());
+    // Saves opening a file for an obvious duplicate, registering checks again
+    if zmq_conn.has_sink(&sink_name) {
+        return Err(MessageRecorderError::DuplicateSink(sink_name).to_string());
+    }
+    let filename = sink_filename(zmq_conn, &sink_cfg.sink_type);
+    if let Some(filename) = &filename {
+        if zmq_conn.sink_files().contains(filename) {
+            return Err(MessageRecorderError::FileInUse(filename.clone()).to_string());
+        }
+        if let Some(files) = files {
+            files
+                .claim(std::slice::from_ref(filename))
+                .map_err(|taken| MessageRecorderError::FileInUse(taken).to_string())?;
+        }
+    }
+    match open_sink(zmq_conn, sink_cfg, &sink_name) {
+        Ok(()) => {
+            if let Some(filename) = filename {
+                zmq_conn.set_sink_file(&sink_name, filename);
+            }
+            Ok(sink_name)
+        }
+        Err(e) => {
+            if let (Some(files), Some(filename)) = (files, filename) {
+                files.release(&[filename]);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn open_sink(zmq_conn: &ZmqConnection, sink_cfg: &Sink, sink_name: &str) -> Result<(), String> {
+    let overflow_policy = match sink_cfg.overflow_policy.as_deref() {
+        Some(policy) => OverflowPolicy::try_from(policy)?,
+        None => OverflowPolicy::Block,
+    };
+    let file_options = FileSinkOptions {
+        connection: zmq_conn.get_host(),
+        topics: zmq_conn.get_topics().clone(),
+        flush_latency: Some(
+            metrics::global()
+                .sink(&zmq_conn.get_host(), sink_name)
+                .flush_seconds,
+        ),
+        ..build_file_sink_options(sink_cfg)?
+    };
+    let compression_options = build_compression_options(sink_cfg)?;
+    let open_failed = |e: std::io::Error| format!("Failed to open the file: {}", e);
+    let sink_enum = match sink_cfg.sink_type.as_str() {
+        "File Sink" => SinksEnum::FileSink(
+            FileSink::new_with_options(zmq_conn.get_filename(), file_options)
+                .map_err(open_failed)?,
+        ),
+        "Console Sink" => {
+            let mode = match sink_cfg.display.as_deref() {
+                Some(mode) => DisplayMode::try_from(mode)?,
+                None => DisplayMode::default(),
+            };
+            SinksEnum::ConsoleSink(ConsoleSink::new_with_options(
+                ConsoleOptions {
+                    mode,
+                    max_bytes: sink_cfg.max_bytes,
+                    show_topic: sink_cfg.show_topic.unwrap_or(false),
+                    show_timestamp: sink_cfg.show_timestamp.unwrap_or(false),
+                },
+                zmq_conn.get_decoder().cloned(),
+            ))
+        }
+        "Compressed Sink" => SinksEnum::CompressedFileSink(
+            CompressedFileSink::new_with_options(
+                zmq_conn.get_filename(),
+                file_options,
+                compression_options,
+            )
+            .map_err(open_failed)?,
+        ),
+        "JSON Lines Sink" => {
+            let encoding = match sink_cfg.payload_encoding.as_deref() {
+                Some(encoding) => PayloadEncoding::try_from(encoding)?,
+                None => PayloadEncoding::default(),
+            };
+            SinksEnum::JsonLinesSink(
+                JsonLinesSink::new_with_options(
+                    jsonl_filename(zmq_conn),
+                    file_options,
+                    encoding,
+                    zmq_conn.get_decoder().cloned(),
+                )
+                .map_err(open_failed)?,
+            )
+        }
+        "Message Counter" => {
+            let report_interval = match sink_cfg.report_interval.as_deref() {
+                Some(interval) => Some(
+                    humantime::parse_duration(interval)
+                        .map_err(|e| format!("Bad report interval '{}': {}", interval, e))?,
+                ),
+                None => None,
+            };
+            SinksEnum::MessageCounter(MessageCounter::new_with_interval(report_interval))
+        }
+        _ => return Err(format!("There is no sink for '{}'", sink_cfg.sink_type)),
+    };
+    let sink_repr = format!("{:?}", sink_enum);
+    zmq_conn
+        .register_new_sink(
+            sink_name.to_string(),
+            Box::new(sink_enum),
+            sink_cfg.queue_size.unwrap_or(DEFAULT_QUEUE_SIZE),
+            overflow_policy,
+        )
+        .map_err(|e| match e {
+            MessageRecorderError::DuplicateSink(_) => e.to_string(),
+            e => format!("Failed to register {}: {}", sink_repr, e),
+        })?;
+    if sink_cfg.record_events.unwrap_or(false) {
+        zmq_conn.record_events_in(sink_name);
+    }
+    Ok(())
+}
+
+/// Address to serve Prometheus metrics on, if configured.
+pub fn read_metrics_address(config: &Config) -> Option<String> {
+    config
+        .metrics
+        .as_ref()
+        .map(|metrics| metrics.listen.clone())
+}
+
+/// I/O threads of the ZMQ context, if configured. Refuses counts ZMQ cannot
+/// take, and 0 unless every connection is `inproc`, which needs no I/O thread.
+/// A control service could add other connections later, so it rules out 0 too.
+pub fn read_io_threads(config: &Config) -> Result<Option<i32>, String> {
+    let io_threads = match config.zmq.as_ref().and_then(|zmq| zmq.io_threads) {
+        Some(io_threads) => io_threads,
+        None => return Ok(None),
+    };
+    let io_threads = i32::try_from(io_threads)
+        .map_err(|_| format!("io_threads {} is more than {}", io_threads, i32::MAX))?;
+    let only_inproc = config.control.is_none()
+        && config.connections.iter().all(|conn_cfg| {
+            matches!(build_endpoint(conn_cfg), Ok(endpoint) if endpoint.transport() == "inproc")
+        });
+    if io_threads == 0 && !only_inproc {
+        return Err(
+            "io_threads 0 only works with inproc connections and no control service".to_string(),
         );
-        if let Some(sinks_cfg) = conn_cfg.sinks {
-            for sink_cfg in sinks_cfg {
-                let sink_enum = match sink_cfg.sink_type.as_str() {
-                    "File Sink" => SinksEnum::FileSink(
-                        FileSink::new(zmq_conn.get_filename(), sink_cfg.flush_time.unwrap_or(0))
-                            .unwrap(),
-                    ),
-                    "Console Sink" => SinksEnum::ConsoleSink(ConsoleSink {}),
-                    "Compressed Sink" => SinksEnum::CompressedFileSink(
-                        CompressedFileSink::new(
-                            zmq_conn.get_filename(),
-                            sink_cfg.flush_time.unwrap_or(0),
-                            sink_cfg.compression_level.unwrap_or(1),
-                        )
-                        .unwrap(),
-                    ),
-                    "Message Counter" => SinksEnum::MessageCounter(MessageCounter::new()),
-                    _ => {
-                        warn!("There is no sink for '{}'", sink_cfg.sink_type.as_str());
-                        continue;
-                    }
-                };
-                let sink_name = sink_cfg.name.unwrap_or(sink_cfg.sink_type);
-                let sink_repr = format!("{:?}", sink_enum);
-                if zmq_conn
-                    .register_new_sink(sink_name.clone(), Box::new(sink_enum))
-                    .is_err()
-                {
-                    error!("Failed to register {} with type {}", sink_name, sink_repr);
-                }
+    }
+    Ok(Some(io_threads))
+}
+
+/// Address to serve the control service on, if configured.
+pub fn read_control_address(config: &Config) -> Option<String> {
+    config
+        .control
+        .as_ref()
+        .map(|control| control.listen.clone())
+}
+
+/// Payload decoders of the configured connections by host, for tools that
+/// read recordings back and find their connection in the file header.
+pub fn read_decoders(config: &Config) -> HashMap<String, Arc<MessageDecoder>> {
+    let pool = load_pool(config);
+    let mut decoders = HashMap::new();
+    for conn_cfg in &config.connections {
+        let endpoint = match build_endpoint(conn_cfg) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                error!(
+                    "No decoder for {}, bad endpoint: {}",
+                    describe_connection(conn_cfg),
+                    e
+                );
+                continue;
+            }
+        };
+        match build_decoder(&pool, conn_cfg) {
+            Ok(Some(decoder)) => {
+                decoders.insert(endpoint.to_string(), Arc::new(decoder));
             }
+            Ok(None) => {}
+            Err(e) => error!("No decoder for {}, bad message types: {}", endpoint, e),
         }
+    }
+    decoders
+}
 
-        connections.push(zmq_conn);
+/// Loads the configured descriptor sets. On failure no types are known, so
+/// every connection that names one is reported and skipped.
+fn load_pool(config: &Config) -> DescriptorPool {
+    let paths = match &config.descriptor_sets {
+        Some(paths) if !paths.is_empty() => paths,
+        _ => return DescriptorPool::new(),
+    };
+    match load_descriptor_sets(paths) {
+        Ok(pool) => {
+            info!(
+                "Loaded {} message types from {} descriptor sets",
+                pool.all_messages().len(),
+                paths.len()
+            );
+            pool
+        }
+        Err(e) => {
+            error!("Failed to load the descriptor sets {:?}: {}", paths, e);
+            DescriptorPool::new()
+        }
     }
+}
 
-    connections
+fn build_decoder(
+    pool: &DescriptorPool,
+    conn_cfg: &Connections,
+) -> Result<Option<MessageDecoder>, String> {
+    if conn_cfg.message_type.is_none() && conn_cfg.message_types.is_none() {
+        return Ok(None);
+    }
+    MessageDecoder::new(
+        pool,
+        conn_cfg.message_type.as_deref(),
+        &conn_cfg.message_types.clone().unwrap_or_default(),
+    )
+    .map(Some)
+    .map_err(|e| e.to_string())
+}
+
+/// Merges the single `topic` and the `topics` list into one filter. The match
+/// mode defaults to prefix matching, which is what a ZMQ subscription does.
+fn build_topic_filter(
+    topic: Option<String>,
+    topics: Option<Vec<String>>,
+    topic_match: Option<String>,
+) -> Result<TopicFilter, String> {
+    let mode = match topic_match {
+        Some(mode) => TopicMatchMode::try_from(mode.as_str())?,
+        None => TopicMatchMode::Prefix,
+    };
+    let all_topics: Vec<String> = topic
+        .into_iter()
+        .chain(topics.unwrap_or_default())
+        .collect();
+    TopicFilter::new(all_topics, mode).map_err(|e| e.to_string())
+}
+
+fn build_file_sink_options(sink_cfg: &Sink) -> Result<FileSinkOptions, String> {
+    let rotation = match &sink_cfg.rotation {
+        Some(rotation) => RotationPolicy {
+            max_bytes: rotation.max_bytes,
+            max_messages: rotation.max_messages,
+            interval: rotation
+                .interval
+                .as_deref()
+                .map(RotationInterval::try_from)
+                .transpose()?,
+        },
+        None => RotationPolicy::default(),
+    };
+    let open_mode = match sink_cfg.open_mode.as_deref() {
+        Some(mode) => OpenMode::try_from(mode)?,
+        None => OpenMode::default(),
+    };
+    let flush_time_s = sink_cfg.flush_time.unwrap_or(0);
+    if flush_time_s < 0 {
+        return Err(format!("flush_time {} is negative", flush_time_s));
+    }
+    Ok(FileSinkOptions {
+        flush_time_s,
+        rotation,
+        open_mode,
+        ..Default::default()
+    })
+}
+
+fn build_compression_options(sink_cfg: &Sink) -> Result<CompressionOptions, String> {
+    let codec = match sink_cfg.codec.as_deref() {
+        Some(codec) => CompressionCodec::try_from(codec)?,
+        None => CompressionCodec::default(),
+    };
+    let mode = match sink_cfg.compression_mode.as_deref() {
+        Some(mode) => CompressionMode::try_from(mode)?,
+        None => CompressionMode::default(),
+    };
+    let dictionary = match &sink_cfg.dictionary {
+        Some(path) => std::fs::read(path)
+            .map_err(|e| format!("Failed to read the dictionary {}: {}", path, e))?,
+        None => vec![],
+    };
+    Ok(CompressionOptions {
+        codec,
+        level: sink_cfg
+            .compression_level
+            .unwrap_or_else(|| codec.default_level()),
+        mode,
+        dictionary,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_decoding::dynamic::tests::market_descriptor_set;
+    use crate::security::tests::{key, CLIENT_PUBLIC, CLIENT_SECRET, SERVER_PUBLIC};
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_read_decoders() {
+        let temp_dir = tempdir().unwrap();
+        let descriptors = temp_dir.path().join("market.pb");
+        std::fs::write(&descriptors, market_descriptor_set()).unwrap();
+        let config = temp_dir.path().join("config.yml");
+        std::fs::write(
+            &config,
+            format!(
+                r#"
+descriptor_sets: ["{}"]
+connections:
+- addr: "localhost"
+  port: 5555
+  file_extension: "rec"
+  message_types:
+    "quotes.": "market.Quote"
+- addr: "localhost"
+  port: 5556
+  file_extension: "rec"
+  message_type: "market.Missing"
+- addr: "localhost"
+  port: 5557
+  file_extension: "rec"
+"#,
+                descriptors.display()
+            ),
+        )
+        .unwrap();
+
+        let decoders = read_decoders(&extract_config(config.to_str().unwrap()));
+        assert_eq!(decoders.len(), 1);
+        let decoder = &decoders["tcp://localhost:5555"];
+        assert!(decoder.message_type(Some("quotes.EURUSD")).is_some());
+        assert!(decoder.message_type(Some("trades")).is_none());
+    }
+
+    #[test]
+    fn test_read_io_threads() {
+        let temp_dir = tempdir().unwrap();
+        let config = temp_dir.path().join("config.yml");
+        let read = |yaml: &str| {
+            std::fs::write(&config, yaml).unwrap();
+            read_io_threads(&extract_config(config.to_str().unwrap()))
+        };
+        let inproc = "connections:\n- endpoint: inproc://feed\n  file_extension: rec\n";
+        let tcp = "- addr: localhost\n  port: 5555\n  file_extension: rec\n";
+
+        assert_eq!(read(inproc), Ok(None));
+        assert_eq!(
+            read(&format!("zmq:\n  io_threads: 4\n{}", inproc)),
+            Ok(Some(4))
+        );
+        assert!(read(&format!("zmq:\n  io_threads: 3000000000\n{}", inproc)).is_err());
+        assert_eq!(
+            read(&format!("zmq:\n  io_threads: 0\n{}", inproc)),
+            Ok(Some(0))
+        );
+        assert!(read(&format!("zmq:\n  io_threads: 0\n{}{}", inproc, tcp)).is_err());
+        assert!(read(&format!(
+            "zmq:\n  io_threads: 0\ncontrol:\n  listen: 127.0.0.1:50051\n{}",
+            inproc
+        ))
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refuses_connections_writing_the_same_file() {
+        let pool = DescriptorPool::new();
+        // As if ipc:///tmp/feeds/md.sock was running with both sinks
+        let files = FileClaims::default();
+        files
+            .claim(&[
+                "ipc____tmp_feeds_md.sock_NO_TOPIC.rec".to_string(),
+                "ipc____tmp_feeds_md.sock_NO_TOPIC.jsonl".to_string(),
+            ])
+            .unwrap();
+        let build = |extension: &str, sinks: &[&str]| {
+            let sinks: String = sinks
+                .iter()
+                .enumerate()
+                .map(|(i, sink_type)| format!("- sink_type: {}\n  name: sink{}\n", sink_type, i))
+                .collect();
+            let yaml = format!(
+                "endpoint: ipc:///tmp/feeds_md.sock\nfile_extension: {}\nsinks:\n{}",
+                extension, sinks
+            );
+            let conn_cfg: Connections =
+                Figment::new().merge(Yaml::string(&yaml)).extract().unwrap();
+            build_connection(&conn_cfg, &pool, SecretSources::All, &files)
+        };
+
+        let file = build("rec", &["File Sink"]).unwrap_err();
+        assert!(
+            file.contains("ipc____tmp_feeds_md.sock_NO_TOPIC.rec"),
+            "{}",
+            file
+        );
+        // Another extension does not help JSON lines, they always end in .jsonl
+        assert!(build("log", &["JSON Lines Sink"]).is_err());
+        // Nor do two sinks of one connection get to share a file
+        let twice = build("dat", &["File Sink", "Compressed Sink"]).unwrap_err();
+        assert!(twice.contains("two of its sinks"), "{}", twice);
+        assert!(build("dat", &["File Sink", "File Sink"]).is_err());
+        // Sinks without files do not collide
+        let counted = build("rec", &["Message Counter"]).unwrap();
+        assert!(counted.sink_files().is_empty());
+        // A sink added later is checked against the claims as well
+        let added = parse_sink("sink_type: File Sink", &counted, &files).unwrap_err();
+        assert!(added.contains("already writes"), "{}", added);
+        counted.close_sinks().await.unwrap();
+    }
+
+    #[test]
+    fn test_parse_connection_endpoints() {
+        let pool = DescriptorPool::new();
+        let host = |yaml: &str| {
+            parse_connection(yaml, &pool, SecretSources::All, &FileClaims::default())
+                .map(|conn| conn.get_host())
+        };
+        assert_eq!(
+            host("endpoint: ipc:///tmp/md.sock\nfile_extension: rec").unwrap(),
+            "ipc:///tmp/md.sock"
+        );
+        assert_eq!(
+            host("addr: \"::1\"\nport: 5555\nfile_extension: rec").unwrap(),
+            "tcp://[::1]:5555"
+        );
+        assert!(host("endpoint: inproc://feed\naddr: localhost\nfile_extension: rec").is_err());
+        assert!(host("addr: localhost\nport: 70000\nfile_extension: rec").is_err());
+        assert!(host("file_extension: rec").is_err());
+    }
+
+    #[test]
+    fn test_parse_connection_socket_config() {
+        let pool = DescriptorPool::new();
+        let connection = parse_connection(
+            "endpoint: tcp://*:5560\nsocket_type: router\nmode: bind\nfile_extension: rec",
+            &pool,
+            SecretSources::All,
+            &FileClaims::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            connection.get_socket_config(),
+            &SocketConfig {
+                socket_type: SocketType::Router,
+                mode: SocketMode::Bind,
+                ..Default::default()
+            }
+        );
+        let connection = parse_connection(
+            "endpoint: tcp://localhost:5560
+file_extension: rec
+reconnect:
+  interval: 50ms
+  max_interval: 5s",
+            &pool,
+            SecretSources::All,
+            &FileClaims::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            connection.get_socket_config().reconnect_interval,
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(
+            connection.get_socket_config().reconnect_interval_max,
+            Some(Duration::from_secs(5))
+        );
+        assert!(parse_connection(
+            "endpoint: tcp://localhost:5560
+file_extension: rec
+reconnect:
+  interval: soon",
+            &pool,
+            SecretSources::All,
+            &FileClaims::default()
+        )
+        .is_err());
+        assert!(parse_connection(
+            "endpoint: tcp://*:5560\nsocket_type: pull\ntopic: md\nfile_extension: rec",
+            &pool,
+            SecretSources::All,
+            &FileClaims::default()
+        )
+        .is_err());
+        assert!(parse_connection(
+            "endpoint: tcp://*:5560\nsocket_type: pair\nfile_extension: rec",
+            &pool,
+            SecretSources::All,
+            &FileClaims::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_connection_socket_options() {
+        let pool = DescriptorPool::new();
+        let options = |yaml: &str| {
+            parse_connection(
+                &format!(
+                    "endpoint: tcp://localhost:5560\nfile_extension: rec\nsocket_options:\n{}",
+                    yaml
+                ),
+                &pool,
+                SecretSources::All,
+                &FileClaims::default(),
+            )
+            .map(|connection| connection.get_socket_config().options.clone())
+        };
+        assert_eq!(
+            options(
+                "  receive_hwm: 0\n  receive_buffer: 4194304\n  receive_timeout: 30s\n  \
+                 tcp_keepalive:\n    idle: 1m\n    count: 3"
+            )
+            .unwrap(),
+            SocketOptions {
+                receive_hwm: Some(0),
+                receive_buffer: Some(4_194_304),
+                tcp_keepalive: Some(TcpKeepalive {
+                    enabled: true,
+                    idle: Some(Duration::from_secs(60)),
+                    interval: None,
+                    count: Some(3),
+                }),
+                receive_timeout: Some(Duration::from_secs(30)),
+            }
+        );
+        assert_eq!(
+            options("  tcp_keepalive:\n    enabled: false")
+                .unwrap()
+                .tcp_keepalive
+                .map(|keepalive| keepalive.enabled),
+            Some(false)
+        );
+        assert!(options("  receive_hwm: -1").is_err());
+        assert!(options("  receive_buffer: 3000000000")
+            .unwrap_err()
+            .contains("receive_buffer 3000000000 is too large"));
+        assert!(options("  tcp_keepalive:\n    idle: 1500ms")
+            .unwrap_err()
+            .contains("whole number of seconds"));
+        assert!(options("  receive_timeout: later").is_err());
+    }
+
+    #[test]
+    fn test_parse_connection_security() {
+        let temp_dir = tempdir().unwrap();
+        let secret_file = temp_dir.path().join("client.key_secret");
+        std::fs::write(&secret_file, format!("{}\n", CLIENT_SECRET)).unwrap();
+        std::env::set_var("TEST_PARSE_CONNECTION_SECURITY_PUBLIC", CLIENT_PUBLIC);
+        let curve = |secret: &str| {
+            format!(
+                "addr: localhost\nport: 5555\nfile_extension: rec\nsecurity:\n  curve:\n    \
+                 server_public_key: {{ value: \"{}\" }}\n    \
+                 public_key: {{ env: TEST_PARSE_CONNECTION_SECURITY_PUBLIC }}\n    \
+                 secret_key: {}\n",
+                SERVER_PUBLIC, secret
+            )
+        };
+        let pool = DescriptorPool::new();
+        let parsed = parse_connection(
+            &curve(&format!("{{ file: \"{}\" }}", secret_file.display())),
+            &pool,
+            SecretSources::All,
+            &FileClaims::default(),
+        );
+        match security::check_curve_support() {
+            Ok(()) => assert_eq!(
+                parsed.unwrap().get_socket_config().security,
+                Some(security::Security::Curve {
+                    server_key: Some(key(SERVER_PUBLIC)),
+                    public_key: key(CLIENT_PUBLIC),
+                    secret_key: key(CLIENT_SECRET),
+                })
+            ),
+            Err(e) => assert!(parsed.unwrap_err().contains(&e)),
+        }
+
+        std::fs::write(&secret_file, "too short").unwrap();
+        let error = parse_connection(
+            &curve(&format!("{{ file: \"{}\" }}", secret_file.display())),
+            &pool,
+            SecretSources::All,
+            &FileClaims::default(),
+        )
+        .unwrap_err();
+        assert!(error.contains("secret_key: expected a 40 character Z85"));
+        let error = parse_connection(
+            &curve("{ file: /nonexistent/key }"),
+            &pool,
+            SecretSources::All,
+            &FileClaims::default(),
+        )
+        .unwrap_err();
+        assert!(error.contains("secret_key: cannot read file '/nonexistent/key'"));
+        let error = parse_connection(
+            &curve(&format!("{{ file: \"{}\" }}", secret_file.display())),
+            &pool,
+            SecretSources::InlineOnly,
+            &FileClaims::default(),
+        )
+        .unwrap_err();
+        assert!(error.contains("public_key: file and env cannot be used here"));
+        let error = parse_connection(
+            &curve("{ value: a, env: B }"),
+            &pool,
+            SecretSources::All,
+            &FileClaims::default(),
+        )
+        .unwrap_err();
+        assert!(error.contains("secret_key: set exactly one of file, env or value"));
+
+        let plain = "addr: localhost\nport: 5555\nfile_extension: rec\nsecurity:\n  plain:\n    \
+                     username: { value: rec }\n    password: { value: pw }\n";
+        assert_eq!(
+            parse_connection(plain, &pool, SecretSources::All, &FileClaims::default())
+                .unwrap()
+                .get_socket_config()
+                .security,
+            Some(security::Security::Plain {
+                username: "rec".to_string(),
+                password: "pw".to_string(),
+            })
+        );
+        let plain_bind = plain.replace("port: 5555", "port: 5555\nmode: bind");
+        assert!(parse_connection(
+            &plain_bind,
+            &pool,
+            SecretSources::All,
+            &FileClaims::default()
+        )
+        .unwrap_err()
+        .contains("PLAIN is only supported when connecting"));
+    }
 }
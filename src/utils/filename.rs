@@ -0,0 +1,33 @@
+use std::path::Path;
+
+/// Inserts `suffix` between a filename and its extension, so `data.rec` with
+/// `_1` becomes `data_1.rec`. Filenames without an extension get it appended.
+pub fn insert_before_extension(filename: &str, suffix: &str) -> String {
+    match Path::new(filename).extension() {
+        Some(extension) => {
+            let stem_len = filename.len() - extension.len() - 1;
+            format!(
+                "{}{}{}",
+                &filename[..stem_len],
+                suffix,
+                &filename[stem_len..]
+            )
+        }
+        None => format!("{}{}", filename, suffix),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_before_extension() {
+        assert_eq!(insert_before_extension("data.rec", "_1"), "data_1.rec");
+        assert_eq!(
+            insert_before_extension("/tmp/tcp___127.0.0.1_5555.test.rec", "_x"),
+            "/tmp/tcp___127.0.0.1_5555.test_x.rec"
+        );
+        assert_eq!(insert_before_extension("/tmp/data", "_1"), "/tmp/data_1");
+    }
+}
@@ -1,10 +1,18 @@
-use log::info;
+use log::{debug, error, info};
 
-use crate::sink::{Sink, SinkError};
-use std::borrow::BorrowMut;
+use crate::capture_socket::SocketConfig;
+use crate::connection_health::{ConnectionHealth, MonitorEvent, MONITOR_TOPIC};
+use crate::endpoint::Endpoint;
+use crate::message_decoding::dynamic::MessageDecoder;
+use crate::message_envelope::MessageEnvelope;
+use crate::metrics::{self, ConnectionMetrics};
+use crate::sink::SinkError;
 
 use crate::sink::SinksEnum;
-use std::collections::HashMap;
+use crate::sink_worker::{OverflowPolicy, SinkQueue, SinkWorker};
+use crate::topic_filter::{TopicFilter, TopicMatchMode};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
 #[derive(Debug)]
@@ -13,6 +21,10 @@ pub enum MessageRecorderError {
     IoError(std::io::Error),
     PoisonError(String),
     SinkError(SinkError),
+    /// A sink of that name is already registered
+    DuplicateSink(String),
+    /// Another sink already writes that file
+    FileInUse(String),
 }
 
 impl From<tmq::TmqError> for MessageRecorderError {
@@ -46,80 +58,224 @@ impl std::fmt::Display for MessageRecorderError {
             MessageRecorderError::TmqError(err) => write!(f, "Zmq error: {:?}", err),
             MessageRecorderError::PoisonError(err) => write!(f, "Poison error: {:?}", err),
             MessageRecorderError::SinkError(err) => write!(f, "Sink error: {:?}", err),
+            MessageRecorderError::DuplicateSink(name) => {
+                write!(f, "There already is a sink named '{}'", name)
+            }
+            MessageRecorderError::FileInUse(filename) => {
+                write!(f, "Another sink already writes {}", filename)
+            }
         }
     }
 }
 
 #[derive(Debug)]
 pub struct ZmqConnection {
-    addr: String,
-    port: String,
-    topic: Option<String>,
+    endpoint: Endpoint,
+    socket: SocketConfig,
+    topic_filter: TopicFilter,
     file_extension: String,
-    sinks: Arc<Mutex<HashMap<String, Box<SinksEnum>>>>,
+    decoder: Option<Arc<MessageDecoder>>,
+    metrics: ConnectionMetrics,
+    /// Set while received messages are discarded instead of recorded
+    paused: AtomicBool,
+    health: ConnectionHealth,
+    /// Sinks that also record the socket's monitor events
+    event_sinks: Mutex<HashSet<String>>,
+    /// Sequence number of the next recorded monitor event
+    event_sequence: AtomicU64,
+    /// File written by each sink that writes one, by sink name
+    sink_files: Mutex<HashMap<String, String>>,
+    sinks: Arc<Mutex<HashMap<String, SinkWorker>>>,
 }
 
 impl ZmqConnection {
-    pub fn new(addr: &str, port: &str, topic: Option<&str>, file_extension: &str) -> Self {
-        Self {
-            addr: addr.to_string(),
-            port: port.to_string(),
-            topic: topic.map(|t| t.to_string()),
-            file_extension: file_extension.to_string(),
-            sinks: Arc::new(Mutex::new(HashMap::new())),
-        }
+    pub fn new(endpoint: Endpoint, topic: Option<&str>, file_extension: &str) -> Self {
+        let topic_filter = match topic {
+            // A plain topic has no regex to reject
+            Some(t) => TopicFilter::new(vec![t.to_string()], TopicMatchMode::Prefix).unwrap(),
+            None => TopicFilter::all(),
+        };
+        ZmqConnection::new_with_owned(endpoint, topic_filter, file_extension.to_string())
     }
 
     pub fn new_with_owned(
-        addr: String,
-        port: String,
-        topic: Option<String>,
+        endpoint: Endpoint,
+        topic_filter: TopicFilter,
         file_extension: String,
     ) -> Self {
+        let metrics = metrics::global().connection(&endpoint.to_string());
         Self {
-            addr,
-            port,
-            topic,
+            endpoint,
+            socket: SocketConfig::default(),
+            topic_filter,
             file_extension,
+            decoder: None,
+            metrics,
+            paused: AtomicBool::new(false),
+            health: ConnectionHealth::new(),
+            event_sinks: Mutex::new(HashSet::new()),
+            event_sequence: AtomicU64::new(0),
+            sink_files: Mutex::new(HashMap::new()),
             sinks: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    /// The ZMQ endpoint string, which also names the connection in
+    /// recordings and metrics.
     pub fn get_host(&self) -> String {
-        format!("tcp://{}:{}", &self.addr, &self.port)
+        self.endpoint.to_string()
+    }
+
+    pub fn get_endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Socket type and whether to connect or bind, a connecting SUB socket by default.
+    pub fn set_socket_config(&mut self, socket: SocketConfig) {
+        self.socket = socket;
+    }
+
+    pub fn get_socket_config(&self) -> &SocketConfig {
+        &self.socket
+    }
+
+    /// State of the socket as its monitor reported it.
+    pub fn get_health(&self) -> &ConnectionHealth {
+        &self.health
     }
 
-    pub fn get_topic(&self) -> &Option<String> {
-        &self.topic
+    pub fn get_metrics(&self) -> &ConnectionMetrics {
+        &self.metrics
+    }
+
+    /// Notes that the sink writes `filename`, which no other sink may write.
+    pub fn set_sink_file(&self, sink_name: &str, filename: String) {
+        if let Ok(mut sink_files) = self.sink_files.lock() {
+            sink_files.insert(sink_name.to_string(), filename);
+        }
+    }
+
+    /// File the sink writes, if it writes one.
+    pub fn sink_file(&self, sink_name: &str) -> Option<String> {
+        match self.sink_files.lock() {
+            Ok(sink_files) => sink_files.get(sink_name).cloned(),
+            Err(_) => None,
+        }
+    }
+
+    /// Files written by the sinks.
+    pub fn sink_files(&self) -> Vec<String> {
+        match self.sink_files.lock() {
+            Ok(sink_files) => sink_files.values().cloned().collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    /// Also hands the socket's monitor events to the sink, so gaps in its
+    /// recording can be explained.
+    pub fn record_events_in(&self, sink_name: &str) {
+        if let Ok(mut event_sinks) = self.event_sinks.lock() {
+            event_sinks.insert(sink_name.to_string());
+        }
+    }
+
+    /// Hands a monitor event to the sinks recording events, as a JSON message
+    /// on `MONITOR_TOPIC`. Events are numbered apart from received messages,
+    /// so they leave no gaps in the messages' sequence numbers.
+    pub async fn record_event(&self, event: &MonitorEvent) -> Result<(), MessageRecorderError> {
+        let event_sinks = match self.event_sinks.lock() {
+            Ok(event_sinks) if event_sinks.is_empty() => return Ok(()),
+            Ok(event_sinks) => event_sinks.clone(),
+            Err(e) => {
+                return Err(MessageRecorderError::PoisonError(format!(
+                    "Failed to lock {}",
+                    e
+                )))
+            }
+        };
+        let message = Arc::new(MessageEnvelope::new(
+            self.event_sequence.fetch_add(1, Ordering::Relaxed),
+            self.get_host(),
+            Some(MONITOR_TOPIC.to_string()),
+            vec![event.to_json().to_string().into_bytes()],
+        ));
+        for queue in self.sink_queues()? {
+            if event_sinks.contains(queue.sink_name()) {
+                queue.push(message.clone()).await;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_topic_filter(&self) -> &TopicFilter {
+        &self.topic_filter
+    }
+
+    pub fn get_topics(&self) -> &Vec<String> {
+        self.topic_filter.topics()
     }
 
     pub fn get_file_extension(&self) -> &String {
         &self.file_extension
     }
 
+    /// Decoder of the payloads received on this connection. Set it before
+    /// registering sinks that present decoded messages.
+    pub fn set_decoder(&mut self, decoder: Arc<MessageDecoder>) {
+        self.decoder = Some(decoder);
+    }
+
+    pub fn get_decoder(&self) -> Option<&Arc<MessageDecoder>> {
+        self.decoder.as_ref()
+    }
+
     pub fn get_filename(&self) -> String {
-        match self.get_topic() {
-            None => format!("{}_NO_TOPIC.{}", self.get_host(), self.get_file_extension()),
-            Some(topic_str) => format!(
+        if self.topic_filter.is_empty() {
+            format!(
+                "{}_NO_TOPIC.{}",
+                self.endpoint.file_stem(),
+                self.get_file_extension()
+            )
+        } else {
+            format!(
                 "{}_{}.{}",
-                self.get_host(),
-                topic_str,
+                self.endpoint.file_stem(),
+                self.get_topics().join("+"),
                 self.get_file_extension()
-            ),
+            )
         }
-        .replace(":", "_")
-        .replace("/", "_")
-        .replace("\\", "_")
+        .chars()
+        .map(|c| match c {
+            // Path separators and characters that regex topics commonly bring along
+            ':' | '/' | '\\' | '*' | '?' | '<' | '>' | '|' | '"' => '_',
+            c => c,
+        })
+        .collect()
     }
 
+    /// Starts a worker task for the sink, fed by a queue of `queue_size` messages.
+    /// Fails if a sink of that name is already registered.
+    /// Must be called from within a tokio runtime.
     pub fn register_new_sink(
         &self,
         sink_name: String,
         new_sink: Box<SinksEnum>,
+        queue_size: usize,
+        overflow_policy: OverflowPolicy,
     ) -> Result<(), MessageRecorderError> {
         match self.sinks.lock() {
             Ok(mut res) => {
-                res.insert(sink_name, new_sink);
+                if res.contains_key(&sink_name) {
+                    return Err(MessageRecorderError::DuplicateSink(sink_name));
+                }
+                let worker = SinkWorker::spawn_with_metrics(
+                    sink_name.clone(),
+                    new_sink,
+                    queue_size,
+                    overflow_policy,
+                    metrics::global().sink(&self.get_host(), &sink_name),
+                );
+                res.insert(sink_name, worker);
                 Ok(())
             }
             Err(e) => Err(MessageRecorderError::PoisonError(format!(
@@ -129,26 +285,163 @@ impl ZmqConnection {
         }
     }
 
-    pub fn use_sinks(&self, data: &Vec<u8>) -> Result<(), MessageRecorderError> {
+    pub fn has_sink(&self, sink_name: &str) -> bool {
         match self.sinks.lock() {
-            Ok(mut res) => {
-                for (sink_name, sink) in res.iter_mut() {
-                    info!("Logging to {} with size {}", sink_name, data.len());
-                    match sink.borrow_mut() {
-                        SinksEnum::ConsoleSink(s) => s.write(&data)?,
-                        SinksEnum::FileSink(s) => s.write(&data)?,
-                        SinksEnum::CompressedFileSink(s) => s.write(&data)?,
-                        SinksEnum::MessageCounter(s) => s.write(&data)?,
-                    };
-                }
-                Ok(())
+            Ok(res) => res.contains_key(sink_name),
+            Err(_) => false,
+        }
+    }
+
+    /// Drains the sink's queue, flushes it and drops it. Returns false if
+    /// there is no sink of that name.
+    pub async fn close_sink(&self, sink_name: &str) -> Result<bool, MessageRecorderError> {
+        let worker = match self.sinks.lock() {
+            Ok(mut res) => res.remove(sink_name),
+            Err(e) => {
+                return Err(MessageRecorderError::PoisonError(format!(
+                    "Failed to lock {}",
+                    e
+                )))
             }
+        };
+        if let Ok(mut event_sinks) = self.event_sinks.lock() {
+            event_sinks.remove(sink_name);
+        }
+        if let Ok(mut sink_files) = self.sink_files.lock() {
+            sink_files.remove(sink_name);
+        }
+        match worker {
+            Some(worker) => {
+                info!("Closing sink {} on {}", sink_name, self.get_host());
+                let closed = worker.close().await;
+                metrics::global().remove_sink(&self.get_host(), sink_name);
+                closed?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Discards received messages until `resume`. Sinks stay open.
+    pub fn pause(&self) {
+        info!("Pausing recording on {}", self.get_host());
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        info!("Resuming recording on {}", self.get_host());
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Name, queued and dropped messages of every sink, sorted by name.
+    pub fn sink_status(&self) -> Result<Vec<(String, usize, u64)>, MessageRecorderError> {
+        let mut status: Vec<_> = self
+            .sink_queues()?
+            .iter()
+            .map(|queue| {
+                (
+                    queue.sink_name().clone(),
+                    queue.queued_messages(),
+                    queue.dropped_messages(),
+                )
+            })
+            .collect();
+        status.sort();
+        Ok(status)
+    }
+
+    fn sink_queues(&self) -> Result<Vec<Arc<SinkQueue>>, MessageRecorderError> {
+        match self.sinks.lock() {
+            Ok(res) => Ok(res.values().map(|worker| worker.queue().clone()).collect()),
             Err(e) => Err(MessageRecorderError::PoisonError(format!(
                 "Failed to lock {}",
                 e
             ))),
         }
     }
+
+    /// Hands the message to every sink's queue. Only waits if a queue is full
+    /// and its overflow policy is to block.
+    pub async fn use_sinks(&self, message: MessageEnvelope) -> Result<(), MessageRecorderError> {
+        self.metrics.record(&message);
+        let message = Arc::new(message);
+        for queue in self.sink_queues()? {
+            debug!(
+                "Queueing message {} for {} with size {}",
+                message.sequence(),
+                queue.sink_name(),
+                message.payload_len()
+            );
+            queue.push(message.clone()).await;
+        }
+        Ok(())
+    }
+
+    /// Number of messages each sink has dropped because its queue was full.
+    pub fn dropped_messages(&self) -> Result<HashMap<String, u64>, MessageRecorderError> {
+        Ok(self
+            .sink_queues()?
+            .iter()
+            .map(|queue| (queue.sink_name().clone(), queue.dropped_messages()))
+            .collect())
+    }
+
+    /// Flushes every registered sink, even if an earlier one fails, and
+    /// returns the first failure.
+    pub async fn flush_sinks(&self) -> Result<(), MessageRecorderError> {
+        let mut result = Ok(());
+        for queue in self.sink_queues()? {
+            if let Err(e) = queue.flush().await {
+                error!(
+                    "Failed to flush {} on {}: {}",
+                    queue.sink_name(),
+                    self.get_host(),
+                    e
+                );
+                if result.is_ok() {
+                    result = Err(MessageRecorderError::SinkError(e));
+                }
+            }
+        }
+        result
+    }
+
+    /// Drains every sink's queue, flushes it and drops it so its files are closed.
+    pub async fn close_sinks(&self) -> Result<(), MessageRecorderError> {
+        let workers: Vec<SinkWorker> = match self.sinks.lock() {
+            Ok(mut res) => res.drain().map(|(_, worker)| worker).collect(),
+            Err(e) => {
+                return Err(MessageRecorderError::PoisonError(format!(
+                    "Failed to lock {}",
+                    e
+                )))
+            }
+        };
+        if let Ok(mut sink_files) = self.sink_files.lock() {
+            sink_files.clear();
+        }
+        info!("Closing {} sinks on {}", workers.len(), self.get_host());
+        let mut result = Ok(());
+        for worker in workers {
+            let sink_name = worker.name().clone();
+            if let Err(e) = worker.close().await {
+                error!(
+                    "Failed to close {} on {}: {}",
+                    sink_name,
+                    self.get_host(),
+                    e
+                );
+                if result.is_ok() {
+                    result = Err(MessageRecorderError::SinkError(e));
+                }
+            }
+        }
+        result
+    }
 }
 
 impl std::fmt::Display for ZmqConnection {
@@ -159,8 +452,14 @@ impl std::fmt::Display for ZmqConnection {
         };
         write!(
             f,
-            "ZmqConnection: {{ addr:{}, port:{}, topic:{:?}, file_extension:{}, len(sinks):{} }}",
-            self.addr, self.port, self.topic, self.file_extension, sink_number
+            "ZmqConnection: {{ endpoint:{}, socket:{} {}, topics:{:?}, topic_match:{:?}, file_extension:{}, len(sinks):{} }}",
+            self.endpoint,
+            self.socket.mode.name(),
+            self.socket.socket_type.name(),
+            self.get_topics(),
+            self.topic_filter.mode(),
+            self.file_extension,
+            sink_number
         )
     }
 }
@@ -168,63 +467,207 @@ impl std::fmt::Display for ZmqConnection {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::recording::reader::RecordingReader;
+    use crate::sinks::file_sink::FileSink;
+    use crate::sinks::message_counter::MessageCounter;
+
+    fn tcp(host: &str) -> Endpoint {
+        Endpoint::tcp(host, 5555).unwrap()
+    }
 
     #[test]
     fn test_new() {
-        let connection = ZmqConnection::new("127.0.0.1", "5555", Some("test_topic"), "test");
-        assert_eq!(connection.addr, "127.0.0.1");
-        assert_eq!(connection.port, "5555");
-        assert_eq!(connection.topic, Some("test_topic".to_string()));
+        let connection = ZmqConnection::new(tcp("127.0.0.1"), Some("test_topic"), "test");
+        assert_eq!(connection.get_endpoint(), &tcp("127.0.0.1"));
+        assert_eq!(connection.get_topics(), &vec!["test_topic".to_string()]);
+        assert_eq!(connection.get_topic_filter().mode(), TopicMatchMode::Prefix);
     }
 
     #[test]
     fn test_new_with_owned() {
         let connection = ZmqConnection::new_with_owned(
-            "192.168.1.1".to_string(),
-            "6666".to_string(),
-            Some("another_topic".to_string()),
+            Endpoint::tcp("192.168.1.1", 6666).unwrap(),
+            TopicFilter::new(
+                vec!["another_topic".to_string(), "more".to_string()],
+                TopicMatchMode::Exact,
+            )
+            .unwrap(),
             "test".to_string(),
         );
-        assert_eq!(connection.addr, "192.168.1.1");
-        assert_eq!(connection.port, "6666");
-        assert_eq!(connection.topic, Some("another_topic".to_string()));
+        assert_eq!(connection.get_host(), "tcp://192.168.1.1:6666");
+        assert_eq!(
+            connection.get_topics(),
+            &vec!["another_topic".to_string(), "more".to_string()]
+        );
+        assert_eq!(connection.get_topic_filter().mode(), TopicMatchMode::Exact);
     }
 
     #[test]
     fn test_get_host() {
-        let connection = ZmqConnection::new("localhost", "5555", None, "test");
+        let connection = ZmqConnection::new(tcp("localhost"), None, "test");
         assert_eq!(connection.get_host(), "tcp://localhost:5555");
     }
 
     #[test]
     fn test_get_topic_some() {
-        let connection = ZmqConnection::new("127.0.0.1", "5555", Some("test_topic"), "test");
-        assert_eq!(connection.get_topic(), &Some("test_topic".to_string()));
+        let connection = ZmqConnection::new(tcp("127.0.0.1"), Some("test_topic"), "test");
+        assert_eq!(connection.get_topics(), &vec!["test_topic".to_string()]);
     }
 
     #[test]
     fn test_get_topic_none() {
-        let connection = ZmqConnection::new("127.0.0.1", "5555", None, "test");
-        assert_eq!(connection.get_topic(), &None);
+        let connection = ZmqConnection::new(tcp("127.0.0.1"), None, "test");
+        assert!(connection.get_topics().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_close_sinks_flushes_and_removes_sinks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let filename = temp_dir.path().join("close.rec");
+        let connection = ZmqConnection::new(tcp("127.0.0.1"), None, "rec");
+        let sink = FileSink::new(filename.to_str().unwrap().to_string(), 3600).unwrap();
+        connection
+            .register_new_sink(
+                "file".to_string(),
+                Box::new(SinksEnum::FileSink(sink)),
+                16,
+                OverflowPolicy::Block,
+            )
+            .unwrap();
+        connection
+            .use_sinks(MessageEnvelope::new(
+                0,
+                connection.get_host(),
+                None,
+                vec![b"payload".to_vec()],
+            ))
+            .await
+            .unwrap();
+        // The flush interval has not elapsed, so nothing reached the file yet
+        assert_eq!(std::fs::metadata(&filename).unwrap().len(), 0);
+        assert_eq!(connection.dropped_messages().unwrap()["file"], 0);
+
+        connection.close_sinks().await.unwrap();
+
+        assert!(std::fs::metadata(&filename).unwrap().len() > 0);
+        assert!(connection.to_string().contains("len(sinks):0"));
+    }
+
+    #[tokio::test]
+    async fn test_register_refuses_duplicate_name() {
+        let connection = ZmqConnection::new(tcp("127.0.0.1"), None, "rec");
+        let counter = || Box::new(SinksEnum::MessageCounter(MessageCounter::new()));
+        connection
+            .register_new_sink("counter".to_string(), counter(), 16, OverflowPolicy::Block)
+            .unwrap();
+
+        let duplicate = connection.register_new_sink(
+            "counter".to_string(),
+            counter(),
+            16,
+            OverflowPolicy::Block,
+        );
+        assert!(matches!(
+            duplicate,
+            Err(MessageRecorderError::DuplicateSink(name)) if name == "counter"
+        ));
+        assert_eq!(connection.dropped_messages().unwrap().len(), 1);
+        connection.close_sinks().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_record_event_reaches_only_opted_in_sinks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let connection = ZmqConnection::new(tcp("127.0.0.1"), None, "rec");
+        for name in ["events", "messages"] {
+            let filename = temp_dir.path().join(format!("{}.rec", name));
+            let sink = FileSink::new(filename.to_str().unwrap().to_string(), 3600).unwrap();
+            connection
+                .register_new_sink(
+                    name.to_string(),
+                    Box::new(SinksEnum::FileSink(sink)),
+                    16,
+                    OverflowPolicy::Block,
+                )
+                .unwrap();
+        }
+        connection.record_events_in("events");
+        let event =
+            MonitorEvent::parse(&[&[1, 0, 9, 0, 0, 0], b"tcp://127.0.0.1:5555"], 1).unwrap();
+        connection.record_event(&event).await.unwrap();
+        connection.record_event(&event).await.unwrap();
+        connection.close_sinks().await.unwrap();
+
+        let recorded = |name: &str| {
+            let mut reader =
+                RecordingReader::open(temp_dir.path().join(format!("{}.rec", name))).unwrap();
+            reader.set_include_events(true);
+            reader.collect::<Result<Vec<_>, _>>().unwrap()
+        };
+        assert!(recorded("messages").is_empty());
+        let events = recorded("events");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].topic().as_deref(), Some(MONITOR_TOPIC));
+        assert_eq!(events[0].connection(), &connection.get_host());
+        // Numbered on their own, stamped like received messages
+        assert_eq!((events[0].sequence(), events[1].sequence()), (&0, &1));
+        assert!(events[1].monotonic_ns() >= events[0].monotonic_ns());
+        let json: serde_json::Value = serde_json::from_slice(&events[0].frames()[0]).unwrap();
+        assert_eq!(json, event.to_json());
+        assert_eq!(json["event"], "connected");
+        assert_eq!(json["value"], 9);
+        assert_eq!(json["address"], "tcp://127.0.0.1:5555");
+
+        // Readers skip events unless asked for them
+        let reader = RecordingReader::open(temp_dir.path().join("events.rec")).unwrap();
+        assert_eq!(reader.count(), 0);
     }
 
     #[test]
     fn test_get_file_extension() {
-        let connection = ZmqConnection::new("127.0.0.1", "5555", None, "test_ext");
+        let connection = ZmqConnection::new(tcp("127.0.0.1"), None, "test_ext");
         assert_eq!(connection.get_file_extension(), "test_ext");
     }
 
     #[test]
     fn test_get_filename_with_topic() {
-        let connection = ZmqConnection::new("127.0.0.1", "5555", Some("test_topic"), "txt");
+        let connection = ZmqConnection::new(tcp("127.0.0.1"), Some("test_topic"), "txt");
         let expected_filename = "tcp___127.0.0.1_5555_test_topic.txt";
         assert_eq!(connection.get_filename(), expected_filename);
     }
 
     #[test]
     fn test_get_filename_without_topic() {
-        let connection = ZmqConnection::new("127.0.0.1", "5555", None, "log");
+        let connection = ZmqConnection::new(tcp("127.0.0.1"), None, "log");
         let expected_filename = "tcp___127.0.0.1_5555_NO_TOPIC.log";
         assert_eq!(connection.get_filename(), expected_filename);
     }
+
+    #[test]
+    fn test_get_filename_with_topic_list() {
+        let connection = ZmqConnection::new_with_owned(
+            tcp("127.0.0.1"),
+            TopicFilter::new(
+                vec!["md.".to_string(), "orders.*".to_string()],
+                TopicMatchMode::Regex,
+            )
+            .unwrap(),
+            "rec".to_string(),
+        );
+        let expected_filename = "tcp___127.0.0.1_5555_md.+orders._.rec";
+        assert_eq!(connection.get_filename(), expected_filename);
+    }
+
+    #[test]
+    fn test_get_filename_for_other_transports() {
+        let filename = |endpoint: &str| {
+            ZmqConnection::new(Endpoint::try_from(endpoint).unwrap(), None, "rec").get_filename()
+        };
+        assert_eq!(filename("tcp://[::1]:5555"), "tcp___--1_5555_NO_TOPIC.rec");
+        assert_eq!(
+            filename("ipc:///tmp/feeds/md.sock"),
+            "ipc____tmp_feeds_md.sock_NO_TOPIC.rec"
+        );
+        assert_eq!(filename("inproc://feed"), "inproc___feed_NO_TOPIC.rec");
+    }
 }
@@ -1,23 +1,180 @@
 import struct
+import sys
 import gzip
+import zlib
+
+MAGIC = b"ZMQ-REC\0"
+FORMAT_VERSION = 3
+CODECS = {0: "none", 1: "gzip", 2: "deflate-stream", 3: "zstd", 4: "lz4"}
+
+
+def _crc32c_table():
+    table = []
+    for index in range(256):
+        crc = index
+        for _ in range(8):
+            crc = (crc >> 1) ^ 0x82F63B78 if crc & 1 else crc >> 1
+        table.append(crc)
+    return table
+
+
+_CRC32C_TABLE = _crc32c_table()
+
+
+def crc32c(data: bytes) -> int:
+    crc = 0xFFFFFFFF
+    for byte in data:
+        crc = _CRC32C_TABLE[(crc ^ byte) & 0xFF] ^ (crc >> 8)
+    return crc ^ 0xFFFFFFFF
+
+
+class UnsupportedRecording(Exception):
+    pass
+
+
+def _read_string(fields: bytes, offset: int):
+    length = struct.unpack("!H", fields[offset : offset + 2])[0]
+    offset += 2
+    return fields[offset : offset + length].decode(), offset + length
+
+
+def read_header(f):
+    """Reads the file header and leaves f at the first record"""
+    magic = f.read(8)
+    if magic != MAGIC:
+        raise UnsupportedRecording(f"Not a message-recorder file, it starts with {magic!r}")
+    version, fields_len = struct.unpack("!HI", f.read(6))
+    if version != FORMAT_VERSION:
+        raise UnsupportedRecording(
+            f"Unsupported format version {version}, this script reads version {FORMAT_VERSION}"
+        )
+    fields = f.read(fields_len)
+    codec_id, created_at_ns = struct.unpack("!BQ", fields[:9])
+    if codec_id not in CODECS:
+        raise UnsupportedRecording(f"Unknown codec id {codec_id}")
+    connection, offset = _read_string(fields, 9)
+    topic_count = struct.unpack("!H", fields[offset : offset + 2])[0]
+    offset += 2
+    topics = []
+    for _ in range(topic_count):
+        topic, offset = _read_string(fields, offset)
+        topics.append(topic)
+    recorder_version, offset = _read_string(fields, offset)
+    dictionary = b""
+    if offset < len(fields):
+        dictionary_len = struct.unpack("!I", fields[offset : offset + 4])[0]
+        dictionary = fields[offset + 4 : offset + 4 + dictionary_len]
+    return {
+        "format_version": version,
+        "codec": CODECS[codec_id],
+        "created_at_ns": created_at_ns,
+        "connection": connection,
+        "topics": topics,
+        "recorder_version": recorder_version,
+        "dictionary": dictionary,
+    }
 
 
 def read_file(filename: str):
+    """Yields every record body of a recording, decompressed according to its header"""
     with open(filename, "rb") as f:
-        data = f.read(8)
-        if len(data) != 8:
+        header = read_header(f)
+        decode = _decoder(header)
+        for body in _read_records(f):
+            if decode is not None:
+                yield decode(body)
+            elif header["codec"] == "deflate-stream":
+                yield from split_block(zlib.decompressobj(-15).decompress(body))
+            elif header["codec"] == "gzip":
+                yield gzip.decompress(body)
+            else:
+                yield body
+
+
+def _decoder(header):
+    """zstd and lz4 need the zstandard and lz4 packages from PyPI"""
+    if header["codec"] == "zstd":
+        import zstandard
+
+        dictionary = None
+        if header["dictionary"]:
+            dictionary = zstandard.ZstdCompressionDict(header["dictionary"])
+        return zstandard.ZstdDecompressor(dict_data=dictionary).decompress
+    if header["codec"] == "lz4":
+        import lz4.block
+
+        return lz4.block.decompress
+    return None
+
+
+def split_block(block: bytes):
+    """Splits a decompressed block into the message bodies it holds"""
+    offset = 0
+    while offset < len(block):
+        size = struct.unpack("!Q", block[offset : offset + 8])[0]
+        offset += 8
+        yield block[offset : offset + size]
+        offset += size
+
+
+def _read_records(f):
+    while True:
+        offset = f.tell()
+        prefix = f.read(12)
+        if len(prefix) != 12:
             return
-        size = struct.unpack("!Q", data)[0]
-        while True:
-            data = f.read(size)
-            if len(data) != size:
-                return
-            yield data
-            data = f.read(8)
-            if len(data) != 8:
-                return
-            size = struct.unpack("!Q", data)[0]
-
-
-for data in read_file("../tcp___localhost_5557_test.rec"):
-    print(gzip.decompress(data).decode())
+        size, checksum = struct.unpack("!QI", prefix)
+        data = f.read(size)
+        if len(data) != size:
+            return
+        # The checksum covers the length too, so a damaged length is caught
+        if crc32c(prefix[:8] + data) != checksum:
+            print(
+                f"Stopping at corrupted record at offset {offset}, "
+                "`message-recorder recover` reports what follows",
+                file=sys.stderr,
+            )
+            return
+        yield data
+
+def parse_record(body: bytes):
+    """Splits a record body into its receive metadata and the original frames"""
+    sequence, wall_time_ns, monotonic_ns = struct.unpack("!QQQ", body[:24])
+    offset = 24
+    topic_len = struct.unpack("!H", body[offset : offset + 2])[0]
+    offset += 2
+    topic = None
+    if topic_len != 0xFFFF:
+        topic = body[offset : offset + topic_len].decode()
+        offset += topic_len
+    connection_len = struct.unpack("!H", body[offset : offset + 2])[0]
+    offset += 2
+    connection = body[offset : offset + connection_len].decode()
+    offset += connection_len
+    metadata = {
+        "sequence": sequence,
+        "wall_time_ns": wall_time_ns,
+        "monotonic_ns": monotonic_ns,
+        "topic": topic,
+        "connection": connection,
+    }
+    return metadata, split_frames(body[offset:])
+
+
+def split_frames(body: bytes):
+    """Splits the frame section of a record back into the original multipart message"""
+    frame_count = struct.unpack("!I", body[:4])[0]
+    lengths = struct.unpack(f"!{frame_count}Q", body[4 : 4 + 8 * frame_count])
+    offset = 4 + 8 * frame_count
+    frames = []
+    for length in lengths:
+        frames.append(body[offset : offset + length])
+        offset += length
+    return frames
+
+
+if __name__ == "__main__":
+    for data in read_file("../tcp___localhost_5557_test.rec"):
+        metadata, frames = parse_record(data)
+        for frame in frames:
+            print(metadata["sequence"], metadata["topic"], frame.decode())